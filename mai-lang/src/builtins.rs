@@ -0,0 +1,25 @@
+/// Functions built into the language, which each backend lowers itself
+/// rather than the program declaring them. A function the program declares
+/// with the same name takes precedence.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Builtin {
+    /// `len(s: str) -> i64` returns the length of a string in bytes, and
+    /// `len(a: [T]) -> i64` the number of elements in an array.
+    Len,
+    /// `concat(a: str, b: str) -> str` returns a new string joining both.
+    Concat,
+    /// `push(a: [T], value: T)` appends an element to an array, growing it
+    /// in place.
+    Push,
+}
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        match name {
+            "len" => Some(Builtin::Len),
+            "concat" => Some(Builtin::Concat),
+            "push" => Some(Builtin::Push),
+            _ => None,
+        }
+    }
+}
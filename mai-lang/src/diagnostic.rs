@@ -0,0 +1,136 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::lexer::LexingError;
+use crate::parser::ParseError;
+use crate::token::Span;
+
+/// An error raised while translating a parsed program, optionally pointing at
+/// the source that caused it.
+#[derive(Debug,Clone,Error)]
+#[error("{message}")]
+pub struct CompileError {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl CompileError {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Self { message: message.into(), span: Some(span) }
+    }
+    pub fn unspanned(message: impl Into<String>) -> Self {
+        Self { message: message.into(), span: None }
+    }
+}
+
+impl From<LexingError> for CompileError {
+    fn from(err: LexingError) -> Self {
+        Self::new(err.to_string(), err.span())
+    }
+}
+
+impl From<ParseError> for CompileError {
+    fn from(err: ParseError) -> Self {
+        Self::new(err.message, err.span)
+    }
+}
+
+/// A source file of the program being compiled. Spans into it are offset by
+/// `start`, so spans into different files never overlap.
+pub struct SourceFile {
+    pub path: String,
+    pub input: String,
+    pub start: usize,
+}
+
+/// Every file making up a program, so diagnostics can be rendered against
+/// the file they point into.
+#[derive(Default)]
+pub struct SourceMap {
+    pub files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    /// Adds a file, returning the offset its spans start at.
+    pub fn add(&mut self, path: String, input: String) -> usize {
+        // Leave a gap so the end of one file is never the start of the next.
+        let start = self.files.last().map_or(0, |file| file.start + file.input.len() + 1);
+        self.files.push(SourceFile { path, input, start });
+        start
+    }
+
+    /// Finds the file a span points into. Errors without a span are
+    /// reported against the first file, which imports all the others.
+    pub fn file(&self, span: Option<Span>) -> &SourceFile {
+        let offset = span.map_or(0, |span| span.offset);
+        self.files
+            .iter()
+            .rev()
+            .find(|file| file.start <= offset)
+            .unwrap_or(&self.files[0])
+    }
+}
+
+/// An error that can be reported against a location in the source, rendered
+/// in the style of rustc:
+///
+/// ```text
+/// error: expected `;` after return value, found `}`
+///  --> main.mai:4:5
+///   |
+/// 4 |     }
+///   |     ^
+/// ```
+pub trait Diagnostic: fmt::Display {
+    fn span(&self) -> Option<Span>;
+
+    fn render(&self, files: &SourceMap) -> String {
+        let file = files.file(self.span());
+        let (path, source) = (&file.path, &file.input);
+        let Some(span) = self.span() else {
+            return format!("error: {}\n --> {}\n", self, path);
+        };
+        let line = source.lines().nth(span.line - 1).unwrap_or("");
+        let line_no = span.line.to_string();
+        let gutter = " ".repeat(line_no.len());
+
+        // Keep tabs so the caret lines up with the offending text.
+        let indent: String = line
+            .chars()
+            .take(span.column - 1)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        let offset = span.offset - file.start;
+        let width = source
+            .get(offset..offset + span.len)
+            .map(|text| text.lines().next().unwrap_or("").chars().count())
+            .unwrap_or(0)
+            .max(1);
+
+        let mut out = format!("error: {}\n", self);
+        out.push_str(&format!("{}--> {}:{}:{}\n", gutter, path, span.line, span.column));
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line_no, line));
+        out.push_str(&format!("{} | {}{}\n", gutter, indent, "^".repeat(width)));
+        out
+    }
+}
+
+impl Diagnostic for LexingError {
+    fn span(&self) -> Option<Span> {
+        Some(LexingError::span(self))
+    }
+}
+
+impl Diagnostic for ParseError {
+    fn span(&self) -> Option<Span> {
+        Some(self.span)
+    }
+}
+
+impl Diagnostic for CompileError {
+    fn span(&self) -> Option<Span> {
+        self.span
+    }
+}
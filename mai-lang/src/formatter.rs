@@ -0,0 +1,612 @@
+use crate::diagnostic::CompileError;
+use crate::lexer::TokenLexer;
+use crate::parser::*;
+use crate::token::{SpannedToken,Token};
+use crate::types::Type;
+
+/// Formats a source file, keeping its comments, or returns the errors that
+/// stop it from parsing.
+///
+/// The source is printed back from its syntax tree, indenting blocks by four
+/// spaces and putting every statement on its own line. Line breaks are only
+/// kept where they separate statements by a blank line or lay out the fields
+/// of a struct. Comments aren't part of the tree, so the printer follows
+/// along in the tokens of the source as it goes, writing out each comment
+/// before the first token printed after it.
+pub fn format(input: &str) -> Result<String, Vec<CompileError>> {
+    let (tokens, lex_errors) = TokenLexer::new(input).with_trivia().tokenize();
+    let (comments, tokens): (Vec<SpannedToken>, Vec<SpannedToken>) = tokens
+        .into_iter()
+        .partition(|tok| matches!(tok.token, Token::Comment(_)));
+    let (statements, parse_errors) = Parser::new(tokens.clone()).parse();
+    let mut errors = lex_errors.into_iter().map(CompileError::from).collect::<Vec<CompileError>>();
+    errors.extend(parse_errors.into_iter().map(CompileError::from));
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut formatter = Formatter {
+        source: input,
+        tokens,
+        comments,
+        cursor: 0,
+        comment: 0,
+        out: String::new(),
+        indent: 0,
+        line_start: true,
+        break_line: false,
+        continued: false,
+        last_line: 1,
+    };
+    formatter.statements(&statements, true);
+    formatter.comments_before(usize::MAX);
+    if !formatter.line_start {
+        formatter.newline();
+    }
+    Ok(formatter.out)
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    // Tokens of the source without its comments, ending with `Token::EOF`.
+    tokens: Vec<SpannedToken>,
+    comments: Vec<SpannedToken>,
+    // The next source token and comment to be printed.
+    cursor: usize,
+    comment: usize,
+    out: String,
+    indent: usize,
+    // Whether nothing has been written on the current line yet, not even
+    // its indentation.
+    line_start: bool,
+    // Whether a comment ends the current line, so anything written after it
+    // has to go on the next one.
+    break_line: bool,
+    // Whether a comment broke a statement in two, so the next line carries
+    // on from the one before and is indented further.
+    continued: bool,
+    // Line in the source of the last token or comment printed.
+    last_line: usize,
+}
+
+impl<'a> Formatter<'a> {
+    fn write(&mut self, text: &str) {
+        if self.break_line {
+            self.newline();
+        }
+        if self.line_start {
+            self.out.push_str(&"    ".repeat(self.indent + self.continued as usize));
+            self.line_start = false;
+            self.continued = false;
+        }
+        self.out.push_str(text);
+    }
+
+    fn newline(&mut self) {
+        self.out.truncate(self.out.trim_end_matches(' ').len());
+        self.out.push('\n');
+        self.line_start = true;
+        self.break_line = false;
+    }
+
+    // Leaves an empty line, unless there is one already or it would be the
+    // first line of a block.
+    fn blank_line(&mut self) {
+        if self.out.is_empty() || self.out.ends_with("\n\n") || self.out.trim_end().ends_with('{') {
+            return;
+        }
+        self.out.push('\n');
+    }
+
+    fn space(&mut self) {
+        self.write(" ");
+    }
+
+    /// Prints a token, first writing out any comments before it in the
+    /// source.
+    ///
+    /// The printer leaves out trailing commas and the `-> void` of function
+    /// types, so those are skipped to find the token in the source. Tokens
+    /// the printer adds, such as trailing commas, aren't found at all.
+    fn token(&mut self, token: Token) {
+        let mut index = self.cursor;
+        let mut found = None;
+        while let Some(tok) = self.tokens.get(index) {
+            if tok.token == token {
+                let span = tok.span;
+                self.comments_before(span.offset);
+                self.cursor = index + 1;
+                self.last_line = span.line;
+                found = Some(span);
+                break;
+            }
+            match &tok.token {
+                Token::Comma | Token::Arrow => index += 1,
+                Token::Ident(name) if name == "void" => index += 1,
+                _ => break,
+            }
+        }
+        match (token, found) {
+            // Numbers are kept as written, rather than in the decimal the
+            // lexer turns them into.
+            (Token::Number(_), Some(span)) => {
+                let source = self.source;
+                self.write(&source[span.offset..span.offset + span.len]);
+            },
+            (Token::Str(value), _) => self.write(&escape(&value)),
+            (token, _) => self.write(&token.to_string()),
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.cursor.min(self.tokens.len() - 1)].token
+    }
+
+    // Where the next token of the source starts.
+    fn next_offset(&self) -> usize {
+        self.tokens.get(self.cursor).map_or(usize::MAX, |tok| tok.span.offset)
+    }
+
+    fn comments_before(&mut self, offset: usize) {
+        while let Some(comment) = self.comments.get(self.comment).cloned() {
+            if comment.span.offset >= offset {
+                break;
+            }
+            self.comment += 1;
+            let text = comment.token.to_string();
+            let end_line = comment.span.line + text.matches('\n').count();
+            // A block comment with code after it on the same line stays
+            // inline, separated by spaces from the tokens around it.
+            let next = self.tokens.iter().find(|tok| tok.span.offset > comment.span.offset).cloned();
+            if text.starts_with("/*") && next.as_ref().map_or(false, |tok| tok.span.line == end_line && tok.token != Token::EOF) {
+                let joined = !self.break_line && comment.span.line == self.last_line;
+                if !self.line_start && !joined {
+                    self.newline();
+                    self.continued = true;
+                } else if self.line_start && comment.span.line > self.last_line + 1 {
+                    self.blank_line();
+                }
+                if !self.line_start && !self.out.ends_with(['(', '[', ' ']) {
+                    self.write(" ");
+                }
+                self.write(&text);
+                if !matches!(next.as_ref().map(|tok| &tok.token), Some(Token::RParen | Token::RBracket | Token::Comma | Token::Semicolon)) {
+                    self.write(" ");
+                }
+                self.last_line = end_line;
+                continue;
+            }
+            // A comment following code on the same line stays there.
+            if !self.line_start && !self.break_line && comment.span.line == self.last_line {
+                self.out.truncate(self.out.trim_end_matches(' ').len());
+                self.write(" ");
+                self.write(&text);
+                self.break_line = true;
+                self.continued = true;
+            } else {
+                let continued = !self.line_start;
+                if continued {
+                    self.newline();
+                } else if comment.span.line > self.last_line + 1 {
+                    self.blank_line();
+                }
+                self.continued = continued;
+                self.write(&text);
+                self.newline();
+                self.continued = continued;
+            }
+            self.last_line = end_line;
+        }
+    }
+
+    // Ends the line of a statement along with any comment after it.
+    fn end_line(&mut self) {
+        while let Some(comment) = self.comments.get(self.comment) {
+            if comment.span.line != self.last_line || comment.span.offset >= self.next_offset() {
+                break;
+            }
+            self.comments_before(comment.span.offset + 1);
+        }
+        self.newline();
+        self.continued = false;
+    }
+
+    fn statements(&mut self, statements: &[Box<Stmt>], top_level: bool) {
+        for (i, stmt) in statements.iter().enumerate() {
+            if i > 0 {
+                let next_line = match self.comments.get(self.comment) {
+                    Some(comment) if comment.span.offset < self.next_offset() => comment.span.line,
+                    _ => self.tokens[self.cursor.min(self.tokens.len() - 1)].span.line,
+                };
+                // Functions and structs are always set apart at the top level.
+                let item = |stmt: &Stmt| matches!(stmt, Stmt::Function { .. } | Stmt::Struct { .. });
+                if next_line > self.last_line + 1 || (top_level && (item(statements[i - 1].as_ref()) || item(stmt.as_ref()))) {
+                    self.blank_line();
+                }
+            }
+            self.stmt(stmt);
+            self.end_line();
+        }
+    }
+
+    fn block(&mut self, statements: &[Box<Stmt>]) {
+        self.token(Token::LBrace);
+        let has_comments = self.comments.get(self.comment).is_some_and(|comment| comment.span.offset < self.next_offset());
+        if statements.is_empty() && !has_comments {
+            self.token(Token::RBrace);
+            return;
+        }
+        self.end_line();
+        self.indent += 1;
+        self.statements(statements, false);
+        // Comments at the end of the block, before its closing brace.
+        self.comments_before(self.next_offset());
+        self.indent -= 1;
+        self.token(Token::RBrace);
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        if *self.peek() == Token::For && self.for_loop(stmt) {
+            return;
+        }
+        match stmt {
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::Expr(expr) => {
+                self.expr(expr);
+                self.token(Token::Semicolon);
+            },
+            Stmt::Return { value, .. } => {
+                self.token(Token::Return);
+                if let Some(value) = value {
+                    self.space();
+                    self.expr(value);
+                }
+                self.token(Token::Semicolon);
+            },
+            Stmt::Function { name, params, ret, body, exported } => {
+                if *exported {
+                    self.token(Token::Export);
+                    self.space();
+                }
+                self.token(Token::Fun);
+                self.space();
+                self.token(name.token.clone());
+                self.signature(params, ret);
+                self.space();
+                self.block(body);
+            },
+            Stmt::Extern { name, params, ret } => {
+                self.token(Token::Extern);
+                self.space();
+                self.token(Token::Fun);
+                self.space();
+                self.token(name.token.clone());
+                self.signature(params, ret);
+                self.token(Token::Semicolon);
+            },
+            Stmt::Struct { name, fields } => {
+                self.token(Token::Struct);
+                self.space();
+                self.token(name.token.clone());
+                self.space();
+                let multiline = fields.first().is_some_and(|field| field.name.span.line != name.span.line);
+                self.fields(fields, multiline, |formatter, field| formatter.param(field));
+            },
+            Stmt::Import { path, .. } => {
+                self.token(Token::Import);
+                self.space();
+                self.token(path.token.clone());
+                self.token(Token::Semicolon);
+            },
+            Stmt::If { cond, then_branch, else_branch } => {
+                self.token(Token::If);
+                self.space();
+                self.token(Token::LParen);
+                self.expr(cond);
+                self.token(Token::RParen);
+                self.space();
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.space();
+                    self.token(Token::Else);
+                    self.space();
+                    self.stmt(else_branch);
+                }
+            },
+            Stmt::While { condition, body } => {
+                self.token(Token::While);
+                self.space();
+                self.token(Token::LParen);
+                self.expr(condition);
+                self.token(Token::RParen);
+                self.space();
+                self.stmt(body);
+            },
+            Stmt::Var { name, ty, initializer } => {
+                self.token(Token::Var);
+                self.space();
+                self.token(name.token.clone());
+                if let Some(ty) = ty {
+                    self.token(Token::Colon);
+                    self.space();
+                    self.ty(ty);
+                }
+                if let Some(initializer) = initializer {
+                    self.space();
+                    self.token(Token::Eq);
+                    self.space();
+                    self.expr(initializer);
+                }
+                self.token(Token::Semicolon);
+            },
+        }
+    }
+
+    /// Prints a `for` loop the parser turned into a `while` loop, which is
+    /// wrapped in a block along with its initializer if it has one, and runs
+    /// its increment at the end of the body. Returns `false` if the statement
+    /// doesn't have that shape.
+    fn for_loop(&mut self, stmt: &Stmt) -> bool {
+        let (initializer, inner) = match stmt {
+            Stmt::Block(statements) => match &statements[..] {
+                [initializer, inner] if matches!(inner.as_ref(), Stmt::While { .. }) => (Some(initializer), inner.as_ref()),
+                _ => return false,
+            },
+            Stmt::While { .. } => (None, stmt),
+            _ => return false,
+        };
+        let Stmt::While { condition, body } = inner else {
+            return false;
+        };
+        let keyword = self.tokens[self.cursor].span;
+        self.token(Token::For);
+        self.space();
+        self.token(Token::LParen);
+        match initializer {
+            Some(initializer) => self.stmt(initializer),
+            None => self.token(Token::Semicolon),
+        }
+        // A missing condition is filled in with a `true` spanning the keyword.
+        if !matches!(condition.as_ref(), Expr::Literal { span, .. } if *span == keyword) {
+            self.space();
+            self.expr(condition);
+        }
+        self.token(Token::Semicolon);
+        let mut body = body.as_ref();
+        // Without an increment the loop's clauses end right away.
+        if *self.peek() != Token::RParen {
+            if let Stmt::Block(statements) = body {
+                if let [inner, increment] = &statements[..] {
+                    if let Stmt::Expr(increment) = increment.as_ref() {
+                        self.space();
+                        self.expr(increment);
+                        body = inner.as_ref();
+                    }
+                }
+            }
+        }
+        self.token(Token::RParen);
+        self.space();
+        self.stmt(body);
+        true
+    }
+
+    fn signature(&mut self, params: &[Param], ret: &Option<Type>) {
+        self.token(Token::LParen);
+        for (i, param) in params.iter().enumerate() {
+            if i > 0 {
+                self.token(Token::Comma);
+                self.space();
+            }
+            self.param(param);
+        }
+        self.token(Token::RParen);
+        if let Some(ret) = ret {
+            self.space();
+            self.token(Token::Arrow);
+            self.space();
+            self.ty(ret);
+        }
+    }
+
+    fn param(&mut self, param: &Param) {
+        self.token(param.name.token.clone());
+        if let Some(ty) = &param.ty {
+            self.token(Token::Colon);
+            self.space();
+            self.ty(ty);
+        }
+    }
+
+    // Prints the fields of a struct declaration or literal in braces, one
+    // per line if the source did so too.
+    fn fields<T>(&mut self, fields: &[T], multiline: bool, mut field: impl FnMut(&mut Self, &T)) {
+        self.token(Token::LBrace);
+        if fields.is_empty() {
+            self.token(Token::RBrace);
+            return;
+        }
+        if multiline {
+            self.end_line();
+            self.indent += 1;
+            for item in fields.iter() {
+                field(self, item);
+                self.token(Token::Comma);
+                self.end_line();
+            }
+            self.comments_before(self.next_offset());
+            self.indent -= 1;
+        } else {
+            self.space();
+            for (i, item) in fields.iter().enumerate() {
+                if i > 0 {
+                    self.token(Token::Comma);
+                    self.space();
+                }
+                field(self, item);
+            }
+            self.space();
+        }
+        self.token(Token::RBrace);
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        match ty {
+            Type::Array(elem) => {
+                self.token(Token::LBracket);
+                self.ty(elem);
+                self.token(Token::RBracket);
+            },
+            Type::Function { params, ret } => {
+                self.token(Token::Fun);
+                self.token(Token::LParen);
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        self.token(Token::Comma);
+                        self.space();
+                    }
+                    self.ty(param);
+                }
+                self.token(Token::RParen);
+                if **ret != Type::Void {
+                    self.space();
+                    self.token(Token::Arrow);
+                    self.space();
+                    self.ty(ret);
+                }
+            },
+            ty => self.name(&ty.to_string()),
+        }
+    }
+
+    // Prints a name, which can be qualified by a module as in `math.Point`.
+    fn name(&mut self, name: &str) {
+        for (i, part) in name.split('.').enumerate() {
+            if i > 0 {
+                self.token(Token::Dot);
+            }
+            self.token(Token::Ident(part.to_string()));
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::BinaryExpr { left, op, right, .. } | Expr::Logical { left, op, right } => {
+                self.expr(left);
+                self.space();
+                self.token(op.token.clone());
+                self.space();
+                self.expr(right);
+            },
+            Expr::UnaryExpr { op, right } => {
+                self.token(op.token.clone());
+                self.expr(right);
+            },
+            Expr::Grouping { expr } => {
+                self.token(Token::LParen);
+                self.expr(expr);
+                self.token(Token::RParen);
+            },
+            Expr::Literal { value, .. } => match value.as_str() {
+                "true" => self.token(Token::True),
+                "false" => self.token(Token::False),
+                number => self.token(Token::Number(number.to_string())),
+            },
+            Expr::Str { value, .. } => self.token(Token::Str(value.clone())),
+            Expr::Array { elements, .. } => {
+                self.token(Token::LBracket);
+                self.exprs(elements);
+                self.token(Token::RBracket);
+            },
+            Expr::Index { target, index, .. } => {
+                self.expr(target);
+                self.token(Token::LBracket);
+                self.expr(index);
+                self.token(Token::RBracket);
+            },
+            Expr::Struct { name, fields } => {
+                self.name(&name.token.to_string());
+                self.space();
+                let multiline = fields.first().is_some_and(|(field, _)| field.span.line != name.span.line);
+                self.fields(fields, multiline, |formatter, (field, value)| {
+                    formatter.token(field.token.clone());
+                    formatter.token(Token::Colon);
+                    formatter.space();
+                    formatter.expr(value);
+                });
+            },
+            Expr::Get { object, name, .. } => {
+                self.expr(object);
+                self.token(Token::Dot);
+                self.token(name.token.clone());
+            },
+            Expr::Set { object, name, value, .. } => {
+                self.expr(object);
+                self.token(Token::Dot);
+                self.token(name.token.clone());
+                self.assigned(value);
+            },
+            Expr::Assign { name, value, .. } => {
+                self.token(name.token.clone());
+                self.assigned(value);
+            },
+            Expr::IndexAssign { target, index, value, .. } => {
+                self.expr(target);
+                self.token(Token::LBracket);
+                self.expr(index);
+                self.token(Token::RBracket);
+                self.assigned(value);
+            },
+            Expr::Variable { name, .. } => self.token(name.token.clone()),
+            Expr::Call { callee, args, .. } => {
+                self.expr(callee);
+                self.token(Token::LParen);
+                self.exprs(args);
+                self.token(Token::RParen);
+            },
+            Expr::Lambda { params, ret, body, .. } => {
+                self.token(Token::Fun);
+                self.space();
+                self.signature(params, ret);
+                self.space();
+                self.block(body);
+            },
+        }
+    }
+
+    fn assigned(&mut self, value: &Expr) {
+        self.space();
+        self.token(Token::Eq);
+        self.space();
+        self.expr(value);
+    }
+
+    fn exprs(&mut self, exprs: &[Box<Expr>]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.token(Token::Comma);
+                self.space();
+            }
+            self.expr(expr);
+        }
+    }
+}
+
+/// Writes a string literal back out, escaping the characters the lexer
+/// decodes escapes into.
+fn escape(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for ch in value.chars() {
+        match ch {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
@@ -0,0 +1,683 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::builtins::Builtin;
+use crate::diagnostic::CompileError;
+use crate::parser::*;
+use crate::runtime::{Host,HostFunction};
+use crate::token::{Span,SpannedToken,Token};
+use crate::types::{checked,float_literal,integer_literal,structs,StructDef,Type};
+
+/// Stack size of the thread `with_stack` runs on. Evaluating a call recurses
+/// through the statements and expressions of the function called, so this
+/// leaves room for recursion as deep as compiled code handles.
+pub const STACK_SIZE: usize = 1 << 30;
+
+// How much stack the interpreter uses unless told it has more, which fits in
+// the 8 MiB main threads usually get.
+const MAIN_STACK_SIZE: usize = 4 << 20;
+
+// Stack kept free below the limit, for the frames evaluated between two
+// checks of how much is used and for calls into the host.
+const STACK_SLACK: usize = 256 << 10;
+
+/// Runs `f` on a thread with a stack of `STACK_SIZE`, for interpreting
+/// programs with an interpreter given that much stack.
+pub fn with_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, f)
+            .expect("failed to spawn the interpreter thread")
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+// The address of a local variable, which tells how deep into the stack the
+// caller is.
+#[inline(never)]
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// A runtime value produced by the interpreter.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+    // Shared, so pushing through one reference is seen through every other,
+    // as with arrays in linear memory.
+    Array(Rc<RefCell<Vec<Value>>>),
+    // The name of the struct and its fields in declaration order, shared
+    // like arrays are.
+    Struct(String, Rc<RefCell<Vec<(String, Value)>>>),
+    Function(Rc<Closure>),
+    Void,
+}
+
+/// The code a function value runs when it is called.
+#[derive(Debug,Clone,PartialEq)]
+pub enum FunctionRef {
+    /// A function declared by the program.
+    Named(String),
+    /// An anonymous function, indexing the lambdas the interpreter has seen.
+    Lambda(usize),
+    /// The zero value of a function type, which returns the zero value of
+    /// its return type.
+    Zero(Type),
+}
+
+/// A function value along with copies of the variables it captured.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Closure {
+    pub function: FunctionRef,
+    pub captures: Vec<(String, Value)>,
+}
+
+impl Value {
+    /// Parses a value of the given type from a string, such as a command
+    /// line argument.
+    pub fn parse(ty: &Type, s: &str) -> Option<Value> {
+        match ty {
+            Type::I32 => s.parse().ok().map(Value::I32),
+            Type::I64 => s.parse().ok().map(Value::I64),
+            Type::F64 => s.parse().ok().map(Value::F64),
+            Type::Bool => s.parse().ok().map(Value::Bool),
+            Type::Str => Some(Value::Str(s.to_string())),
+            Type::Array(_) | Type::Struct(_) | Type::Function { .. } | Type::Void => None,
+        }
+    }
+
+    /// Parses the value of a type checked literal, whose integers can be
+    /// written in hexadecimal or binary.
+    pub fn literal(ty: &Type, s: &str) -> Option<Value> {
+        match ty {
+            Type::I32 => integer_literal(s, ty).map(|value| Value::I32(value as i32)),
+            Type::I64 => integer_literal(s, ty).map(Value::I64),
+            Type::F64 => float_literal(s).map(Value::F64),
+            ty => Value::parse(ty, s),
+        }
+    }
+
+    fn zero(ty: &Type, structs: &HashMap<String, StructDef>) -> Value {
+        match ty {
+            Type::I32 => Value::I32(0),
+            Type::I64 => Value::I64(0),
+            Type::F64 => Value::F64(0.0),
+            Type::Bool => Value::Bool(false),
+            Type::Str => Value::Str(String::new()),
+            Type::Array(_) => Value::array(vec![]),
+            Type::Struct(name) => {
+                let fields = structs[name]
+                    .fields
+                    .iter()
+                    .map(|(field, ty)| (field.clone(), Value::zero(ty, structs)))
+                    .collect();
+                Value::Struct(name.clone(), Rc::new(RefCell::new(fields)))
+            },
+            Type::Function { ret, .. } => Value::Function(Rc::new(Closure {
+                function: FunctionRef::Zero(ret.as_ref().clone()),
+                captures: vec![],
+            })),
+            Type::Void => Value::Void,
+        }
+    }
+
+    pub fn array(elements: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RefCell::new(elements)))
+    }
+
+    fn as_bool(&self) -> bool {
+        matches!(self, Value::Bool(true))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::I32(v) => write!(f, "{}", v),
+            Value::I64(v) => write!(f, "{}", v),
+            Value::F64(v) => write!(f, "{:?}", v),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Str(v) => write!(f, "{}", v),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            },
+            Value::Struct(name, fields) => {
+                write!(f, "{} {{", name)?;
+                for (i, (field, value)) in fields.borrow().iter().enumerate() {
+                    let sep = if i > 0 { "," } else { "" };
+                    write!(f, "{} {}: {}", sep, field, value)?;
+                }
+                write!(f, " }}")
+            },
+            Value::Function(_) => write!(f, "<fun>"),
+            Value::Void => write!(f, "()"),
+        }
+    }
+}
+
+/// How control leaves a statement, so `return` can unwind through any
+/// number of enclosing blocks and loops.
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+/// Lexical scopes of a single function call.
+struct Environment {
+    scopes: Vec<HashMap<String, Value>>,
+}
+
+impl Environment {
+    fn define(&mut self, name: String, value: Value) {
+        self.scopes.last_mut().unwrap().insert(name, value);
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    // Looks up a variable in the scope the resolver found it in.
+    fn get_at(&self, depth: usize, name: &str) -> Option<&Value> {
+        self.scopes.iter().rev().nth(depth)?.get(name)
+    }
+
+    fn get_at_mut(&mut self, depth: usize, name: &str) -> Option<&mut Value> {
+        self.scopes.iter_mut().rev().nth(depth)?.get_mut(name)
+    }
+}
+
+/// Evaluates a type checked program by walking its AST directly, without
+/// needing an LLVM or wasm toolchain.
+pub struct Interpreter<'a> {
+    functions: HashMap<String, &'a Stmt>,
+    structs: HashMap<String, StructDef>,
+    // Every lambda evaluated so far, so closures can refer to their code by
+    // index.
+    lambdas: Vec<&'a Expr>,
+    lambda_ids: HashMap<*const Expr, usize>,
+    frames: Vec<Environment>,
+    host: Host,
+    // Where the stack was when the program was called, and how far past it
+    // evaluation may go before reporting a stack overflow.
+    stack_start: usize,
+    stack_limit: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(statements: &'a [Box<Stmt>]) -> Self {
+        let mut functions = HashMap::new();
+        for stmt in statements.iter() {
+            if let Stmt::Function { name, .. } | Stmt::Extern { name, .. } = stmt.as_ref() {
+                functions.insert(name.token.to_string(), stmt.as_ref());
+            }
+        }
+        Self {
+            functions,
+            structs: structs(statements),
+            lambdas: vec![],
+            lambda_ids: HashMap::new(),
+            frames: vec![],
+            host: Host::new(),
+            stack_start: 0,
+            stack_limit: MAIN_STACK_SIZE - STACK_SLACK,
+        }
+    }
+
+    /// Lets evaluation use a stack of `size` bytes, for calling on a thread
+    /// with more stack than the main thread has.
+    pub fn with_stack_size(mut self, size: usize) -> Self {
+        self.stack_limit = size - STACK_SLACK;
+        self
+    }
+
+    /// Calls the function with the given name, returning its result.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, CompileError> {
+        self.stack_start = stack_address();
+        self.call_at(name, args, None)
+    }
+
+    // Whether evaluation has used up the stack it was allowed. The stack is
+    // measured rather than counting calls, as how much a call takes depends
+    // on how deeply its expressions nest.
+    fn out_of_stack(&self) -> bool {
+        self.stack_start.abs_diff(stack_address()) > self.stack_limit
+    }
+
+    fn call_at(&mut self, name: &str, args: Vec<Value>, span: Option<Span>) -> Result<Value, CompileError> {
+        let error = |message: String| CompileError { message, span };
+        let (params, ret, body) = match self.functions.get(name).copied() {
+            Some(Stmt::Function { params, ret, body, .. }) => (params, ret, body),
+            Some(Stmt::Extern { name: decl, params, ret }) => {
+                let params = params.iter().map(|param| checked(&param.ty)).collect::<Vec<Type>>();
+                let function = HostFunction::resolve(name, &params, &checked(ret))
+                    .map_err(|message| CompileError::new(message, decl.span))?;
+                return self.host.call(function, &args).map_err(error);
+            },
+            _ => return Err(error(format!("cannot find function `{}`", name))),
+        };
+        self.invoke(name, params, ret, body, args, vec![], span)
+    }
+
+    // Calls a closure, which may refer to a named function, a lambda or be
+    // the zero value of a function type.
+    fn call_closure(&mut self, closure: &Closure, args: Vec<Value>, span: Span) -> Result<Value, CompileError> {
+        match &closure.function {
+            FunctionRef::Named(name) => self.call_at(name, args, Some(span)),
+            FunctionRef::Lambda(id) => {
+                let Expr::Lambda { params, ret, body, .. } = self.lambdas[*id] else {
+                    return Err(CompileError::new("only functions can be called", span));
+                };
+                self.invoke("closure", params, ret, body, args, closure.captures.clone(), Some(span))
+            },
+            FunctionRef::Zero(ret) => Ok(Value::zero(ret, &self.structs)),
+        }
+    }
+
+    // Runs the body of a function in a new frame holding its captures and
+    // arguments.
+    #[allow(clippy::too_many_arguments)]
+    fn invoke(
+        &mut self,
+        name: &str,
+        params: &[Param],
+        ret: &Option<Type>,
+        body: &'a [Box<Stmt>],
+        args: Vec<Value>,
+        captures: Vec<(String, Value)>,
+        span: Option<Span>,
+    ) -> Result<Value, CompileError> {
+        let error = |message: String| CompileError { message, span };
+        if params.len() != args.len() {
+            return Err(error(format!(
+                "function `{}` takes {} arguments but {} were supplied",
+                name,
+                params.len(),
+                args.len(),
+            )));
+        }
+        if self.out_of_stack() {
+            return Err(error(format!("stack overflow while calling `{}`", name)));
+        }
+
+        let mut scope = captures.into_iter().collect::<HashMap<String, Value>>();
+        for (param, arg) in params.iter().zip(args.into_iter()) {
+            scope.insert(param.name.token.to_string(), arg);
+        }
+        self.frames.push(Environment { scopes: vec![scope] });
+        let flow = self.execute_block(body);
+        self.frames.pop();
+
+        match flow? {
+            Flow::Return(value) => Ok(value),
+            // Functions that fall off the end without a `return` yield zero.
+            Flow::Normal => Ok(Value::zero(&checked(ret), &self.structs)),
+        }
+    }
+
+    fn env(&mut self) -> &mut Environment {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn execute_block(&mut self, statements: &'a [Box<Stmt>]) -> Result<Flow, CompileError> {
+        self.env().scopes.push(HashMap::new());
+        let mut flow = Ok(Flow::Normal);
+        for stmt in statements.iter() {
+            flow = self.execute(stmt);
+            if !matches!(flow, Ok(Flow::Normal)) {
+                break;
+            }
+        }
+        self.env().scopes.pop();
+        flow
+    }
+
+    fn execute(&mut self, stmt: &'a Stmt) -> Result<Flow, CompileError> {
+        match stmt {
+            Stmt::Expr(expr) => {
+                self.evaluate(expr)?;
+                Ok(Flow::Normal)
+            },
+            Stmt::Block(statements) => self.execute_block(statements),
+            Stmt::If { cond, then_branch, else_branch } => {
+                if self.evaluate(cond)?.as_bool() {
+                    self.execute_block(std::slice::from_ref(then_branch))
+                } else if let Some(else_branch) = else_branch {
+                    self.execute_block(std::slice::from_ref(else_branch))
+                } else {
+                    Ok(Flow::Normal)
+                }
+            },
+            Stmt::While { condition, body } => {
+                while self.evaluate(condition)?.as_bool() {
+                    if let Flow::Return(value) = self.execute_block(std::slice::from_ref(body))? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+                Ok(Flow::Normal)
+            },
+            Stmt::Return { value, .. } => {
+                let value = match value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::Void,
+                };
+                Ok(Flow::Return(value))
+            },
+            Stmt::Var { name, ty, initializer } => {
+                let value = match initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Value::zero(&checked(ty), &self.structs),
+                };
+                self.env().define(name.token.to_string(), value);
+                Ok(Flow::Normal)
+            },
+            Stmt::Function { name, .. } | Stmt::Extern { name, .. } => Err(CompileError::new(
+                "nested function declarations are not supported",
+                name.span,
+            )),
+            Stmt::Struct { name, .. } => Err(CompileError::new(
+                "nested struct declarations are not supported",
+                name.span,
+            )),
+            Stmt::Import { keyword, .. } => Err(CompileError::new(
+                "imports are only allowed at the top level",
+                keyword.span,
+            )),
+        }
+    }
+
+    fn evaluate(&mut self, expr: &'a Expr) -> Result<Value, CompileError> {
+        if self.out_of_stack() {
+            return Err(CompileError::new("stack overflow while evaluating this expression", expr.span()));
+        }
+        match expr {
+            Expr::Literal { value, ty, span } => {
+                let literal = match value.as_str() {
+                    "true" => Some(Value::Bool(true)),
+                    "false" => Some(Value::Bool(false)),
+                    nb => Value::literal(&checked(ty), nb),
+                };
+                literal.ok_or_else(|| CompileError::new(format!("invalid literal `{}`", value), *span))
+            },
+            Expr::Str { value, .. } => Ok(Value::Str(value.clone())),
+            Expr::Array { elements, .. } => {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements.iter() {
+                    values.push(self.evaluate(element)?);
+                }
+                Ok(Value::array(values))
+            },
+            Expr::Struct { name, fields } => {
+                let struct_name = name.token.to_string();
+                let mut values = HashMap::new();
+                for (field, value) in fields.iter() {
+                    values.insert(field.token.to_string(), self.evaluate(value)?);
+                }
+                // Fields are evaluated in the order they are written, but
+                // stored in the order they are declared.
+                let Some(def) = self.structs.get(&struct_name) else {
+                    return Err(CompileError::new(format!("cannot find struct `{}` in this scope", struct_name), name.span));
+                };
+                let fields = def
+                    .fields
+                    .iter()
+                    .map(|(field, _)| (field.clone(), values.remove(field).unwrap_or(Value::Void)))
+                    .collect();
+                Ok(Value::Struct(struct_name, Rc::new(RefCell::new(fields))))
+            },
+            Expr::Get { object, name, .. } => {
+                let fields = self.evaluate_object(object, name)?;
+                let id = name.token.to_string();
+                let value = fields.borrow().iter().find(|(field, _)| *field == id).map(|(_, value)| value.clone());
+                value.ok_or_else(|| CompileError::new(format!("no field `{}`", id), name.span))
+            },
+            Expr::Set { object, name, value, .. } => {
+                let fields = self.evaluate_object(object, name)?;
+                let value = self.evaluate(value)?;
+                let id = name.token.to_string();
+                let mut fields = fields.borrow_mut();
+                let Some((_, field)) = fields.iter_mut().find(|(field, _)| *field == id) else {
+                    return Err(CompileError::new(format!("no field `{}`", id), name.span));
+                };
+                *field = value.clone();
+                Ok(value)
+            },
+            Expr::Index { target, bracket, index, .. } => {
+                let (elements, index) = self.evaluate_index(target, bracket.span, index)?;
+                let value = elements.borrow()[index].clone();
+                Ok(value)
+            },
+            Expr::IndexAssign { target, bracket, index, value, .. } => {
+                let (elements, index) = self.evaluate_index(target, bracket.span, index)?;
+                let value = self.evaluate(value)?;
+                elements.borrow_mut()[index] = value.clone();
+                Ok(value)
+            },
+            Expr::Variable { name, depth } => {
+                let id = name.token.to_string();
+                if let Some(value) = depth.and_then(|depth| self.env().get_at(depth, &id)) {
+                    return Ok(value.clone());
+                }
+                // Functions declared by the program can be used as values too.
+                if self.functions.contains_key(&id) {
+                    return Ok(Value::Function(Rc::new(Closure { function: FunctionRef::Named(id), captures: vec![] })));
+                }
+                Err(CompileError::new(format!("cannot find variable `{}` in this scope", id), name.span))
+            },
+            Expr::Assign { name, value, depth } => {
+                let value = self.evaluate(value)?;
+                let id = name.token.to_string();
+                match depth.and_then(|depth| self.env().get_at_mut(depth, &id)) {
+                    Some(var) => {
+                        *var = value.clone();
+                        Ok(value)
+                    },
+                    None => Err(CompileError::new(format!("cannot find variable `{}` in this scope", id), name.span)),
+                }
+            },
+            Expr::Grouping { expr } => self.evaluate(expr),
+            Expr::UnaryExpr { op, right } => {
+                let value = self.evaluate(right)?;
+                unary(&op.token, value).map_err(|message| CompileError::new(message, op.span))
+            },
+            Expr::Logical { op, left, right } => {
+                let lhs = self.evaluate(left)?.as_bool();
+                // Only evaluate the right hand side if the left doesn't decide the result.
+                let result = match op.token {
+                    Token::And => lhs && self.evaluate(right)?.as_bool(),
+                    Token::Or => lhs || self.evaluate(right)?.as_bool(),
+                    _ => return Err(CompileError::new("unsupported logical operation", op.span)),
+                };
+                Ok(Value::Bool(result))
+            },
+            Expr::BinaryExpr { op, left, right, .. } => {
+                let lhs = self.evaluate(left)?;
+                let rhs = self.evaluate(right)?;
+                binary(op.token.clone(), lhs, rhs)
+                    .map_err(|message| CompileError::new(message, op.span))
+            },
+            Expr::Call { callee, paren, args } => {
+                // Named functions are called directly, unless a variable
+                // shadows them.
+                if let Expr::Variable { name, depth: None } = callee.as_ref() {
+                    let fn_name = name.token.to_string();
+                    let values = self.evaluate_args(args)?;
+                    if !self.functions.contains_key(&fn_name) {
+                        if let Some(builtin) = Builtin::from_name(&fn_name) {
+                            return builtin_call(builtin, values)
+                                .ok_or_else(|| CompileError::new("invalid arguments", name.span));
+                        }
+                    }
+                    return self.call_at(&fn_name, values, Some(name.span));
+                }
+                let Value::Function(closure) = self.evaluate(callee)? else {
+                    return Err(CompileError::new("only functions can be called", paren.span));
+                };
+                let values = self.evaluate_args(args)?;
+                self.call_closure(&closure, values, paren.span)
+            },
+            Expr::Lambda { captures, .. } => {
+                let key = expr as *const Expr;
+                let id = match self.lambda_ids.get(&key) {
+                    Some(id) => *id,
+                    None => {
+                        self.lambdas.push(expr);
+                        self.lambda_ids.insert(key, self.lambdas.len() - 1);
+                        self.lambdas.len() - 1
+                    },
+                };
+                let mut values = Vec::with_capacity(captures.len());
+                for (name, _) in captures.iter() {
+                    match self.env().get(name) {
+                        Some(value) => values.push((name.clone(), value.clone())),
+                        None => return Err(CompileError::new(
+                            format!("cannot find variable `{}` in this scope", name),
+                            expr.span(),
+                        )),
+                    }
+                }
+                Ok(Value::Function(Rc::new(Closure { function: FunctionRef::Lambda(id), captures: values })))
+            },
+        }
+    }
+
+    fn evaluate_args(&mut self, args: &'a [Box<Expr>]) -> Result<Vec<Value>, CompileError> {
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args.iter() {
+            values.push(self.evaluate(arg)?);
+        }
+        Ok(values)
+    }
+
+    fn evaluate_object(
+        &mut self,
+        object: &'a Expr,
+        name: &SpannedToken,
+    ) -> Result<Rc<RefCell<Vec<(String, Value)>>>, CompileError> {
+        match self.evaluate(object)? {
+            Value::Struct(_, fields) => Ok(fields),
+            _ => Err(CompileError::new(format!("cannot access field `{}` on a value that is not a struct", name.token), name.span)),
+        }
+    }
+
+    // Evaluates the array and index of an indexing expression, checking the
+    // index is within bounds.
+    fn evaluate_index(
+        &mut self,
+        target: &'a Expr,
+        span: Span,
+        index: &'a Expr,
+    ) -> Result<(Rc<RefCell<Vec<Value>>>, usize), CompileError> {
+        let Value::Array(elements) = self.evaluate(target)? else {
+            return Err(CompileError::new("only arrays can be indexed", span));
+        };
+        let index = match self.evaluate(index)? {
+            Value::I32(i) => i as i64,
+            Value::I64(i) => i,
+            _ => return Err(CompileError::new("array indices must be integers", span)),
+        };
+        let len = elements.borrow().len();
+        if index < 0 || index as usize >= len {
+            return Err(CompileError::new(
+                format!("index out of bounds: the len is {} but the index is {}", len, index),
+                span,
+            ));
+        }
+        Ok((elements, index as usize))
+    }
+}
+
+fn builtin_call(builtin: Builtin, args: Vec<Value>) -> Option<Value> {
+    match (builtin, args.as_slice()) {
+        (Builtin::Len, [Value::Str(s)]) => Some(Value::I64(s.len() as i64)),
+        (Builtin::Len, [Value::Array(elements)]) => Some(Value::I64(elements.borrow().len() as i64)),
+        (Builtin::Concat, [Value::Str(a), Value::Str(b)]) => Some(Value::Str(format!("{}{}", a, b))),
+        (Builtin::Push, [Value::Array(elements), value]) => {
+            elements.borrow_mut().push(value.clone());
+            Some(Value::Void)
+        },
+        _ => None,
+    }
+}
+
+macro_rules! int_op {
+    ($op:expr, $lhs:expr, $rhs:expr, $variant:path) => {
+        match $op {
+            Token::Plus => Ok($variant($lhs.wrapping_add($rhs))),
+            Token::Minus => Ok($variant($lhs.wrapping_sub($rhs))),
+            Token::Times => Ok($variant($lhs.wrapping_mul($rhs))),
+            Token::Div if $rhs == 0 => Err("attempt to divide by zero".to_string()),
+            // Only dividing the smallest integer by -1 overflows.
+            Token::Div => match $lhs.checked_div($rhs) {
+                Some(v) => Ok($variant(v)),
+                None => Err("attempt to divide with overflow".to_string()),
+            },
+            op => compare(op, $lhs.partial_cmp(&$rhs)),
+        }
+    };
+}
+
+fn compare(op: Token, ordering: Option<std::cmp::Ordering>) -> Result<Value, String> {
+    use std::cmp::Ordering::*;
+    let result = match (op, ordering) {
+        (Token::BangEq, None) => true,
+        (_, None) => false,
+        (Token::Less, Some(ord)) => ord == Less,
+        (Token::Greater, Some(ord)) => ord == Greater,
+        (Token::Leq, Some(ord)) => ord != Greater,
+        (Token::Geq, Some(ord)) => ord != Less,
+        (Token::Eqq, Some(ord)) => ord == Equal,
+        (Token::BangEq, Some(ord)) => ord != Equal,
+        _ => return Err("unsupported binary operation".to_string()),
+    };
+    Ok(Value::Bool(result))
+}
+
+/// Applies a unary operator, as when evaluating `-x` or `!x`.
+pub fn unary(op: &Token, value: Value) -> Result<Value, String> {
+    match (op, value) {
+        (Token::Minus, Value::I32(v)) => Ok(Value::I32(v.wrapping_neg())),
+        (Token::Minus, Value::I64(v)) => Ok(Value::I64(v.wrapping_neg())),
+        (Token::Minus, Value::F64(v)) => Ok(Value::F64(-v)),
+        (Token::Bang, Value::Bool(v)) => Ok(Value::Bool(!v)),
+        _ => Err("unsupported unary operation".to_string()),
+    }
+}
+
+/// Applies a binary operator, as when evaluating `x + y` or `x < y`.
+/// Integers wrap on overflow like they do in wasm, and dividing an integer
+/// by zero or the smallest integer by -1 is an error.
+pub fn binary(op: Token, lhs: Value, rhs: Value) -> Result<Value, String> {
+    match (lhs, rhs) {
+        (Value::I32(lhs), Value::I32(rhs)) => int_op!(op, lhs, rhs, Value::I32),
+        (Value::I64(lhs), Value::I64(rhs)) => int_op!(op, lhs, rhs, Value::I64),
+        (Value::F64(lhs), Value::F64(rhs)) => match op {
+            Token::Plus => Ok(Value::F64(lhs + rhs)),
+            Token::Minus => Ok(Value::F64(lhs - rhs)),
+            Token::Times => Ok(Value::F64(lhs * rhs)),
+            Token::Div => Ok(Value::F64(lhs / rhs)),
+            op => compare(op, lhs.partial_cmp(&rhs)),
+        },
+        (Value::Bool(lhs), Value::Bool(rhs)) => match op {
+            Token::Eqq => Ok(Value::Bool(lhs == rhs)),
+            Token::BangEq => Ok(Value::Bool(lhs != rhs)),
+            _ => Err("unsupported binary operation".to_string()),
+        },
+        _ => Err("unsupported binary operation".to_string()),
+    }
+}
@@ -1,118 +1,224 @@
 use std::iter::Peekable;
-use std::ops::DerefMut;
 use std::str::Chars;
 
 use thiserror::Error;
 use eyre::Result;
 
-use crate::token::Token;
+use crate::token::{Span,SpannedToken,Token};
 
-#[derive(Debug,Error)]
+#[derive(Debug,Clone,Error)]
 pub enum LexingError {
     #[error("unknown token matched `{0}`")]
-    UnknownToken(String),
+    UnknownToken(String, Span),
+    #[error("unterminated string literal")]
+    UnterminatedString(Span),
+    #[error("unknown character escape `\\{0}`")]
+    UnknownEscape(char, Span),
+    #[error("unterminated block comment")]
+    UnterminatedComment(Span),
+    #[error("malformed number `{0}`: {1}")]
+    MalformedNumber(String, &'static str, Span),
 }
 
-pub type LexResult = Result<Token, LexingError>;
+impl LexingError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexingError::UnknownToken(_, span) => *span,
+            LexingError::UnterminatedString(span) => *span,
+            LexingError::UnknownEscape(_, span) => *span,
+            LexingError::UnterminatedComment(span) => *span,
+            LexingError::MalformedNumber(_, _, span) => *span,
+        }
+    }
+}
+
+pub type LexResult = Result<SpannedToken, LexingError>;
 
 pub struct TokenLexer<'a> {
     input: &'a str,
     chars: Box<Peekable<Chars<'a>>>,
+    // Byte offset of the next character.
     curr: usize,
+    // Offset added to every span, for inputs that are one of several files.
+    start: usize,
+    line: usize,
+    column: usize,
+    // Whether to produce comments as tokens rather than skipping them.
+    trivia: bool,
 }
 
 impl<'a> Iterator for TokenLexer<'a> {
-    type Item = Token;
+    type Item = LexResult;
     fn next(&mut self) -> Option<Self::Item> {
+        // Errors are yielded rather than ending the stream, as the offending
+        // character has already been skipped and lexing can carry on.
         match self.lex() {
-            Ok(Token::EOF) => None,
-            Err(_) => None,
-            Ok(token) => Some(token),
+            Ok(SpannedToken { token: Token::EOF, .. }) => None,
+            result => Some(result),
         }
     }
 }
 
 impl<'a> TokenLexer<'a> {
     pub fn new(input: &'a str) -> TokenLexer<'a> {
+        TokenLexer::starting_at(input, 0)
+    }
+
+    /// Creates a lexer whose spans start at the given offset rather than
+    /// zero, as given by `SourceMap::add`.
+    pub fn starting_at(input: &'a str, start: usize) -> TokenLexer<'a> {
         TokenLexer {
             input,
             chars: Box::new(input.chars().peekable()),
             curr: 0,
+            start,
+            line: 1,
+            column: 1,
+            trivia: false,
         }
     }
 
-    pub fn lex(&mut self) -> LexResult {
-        let chars = self.chars.deref_mut();
-        let src = self.input;
+    /// Makes the lexer produce comments as `Token::Comment` instead of
+    /// skipping them. The parser doesn't expect them, so they have to be
+    /// filtered out before parsing.
+    pub fn with_trivia(mut self) -> TokenLexer<'a> {
+        self.trivia = true;
+        self
+    }
 
-        let mut curr = self.curr;
+    /// Lexes the whole input, returning every token followed by a final
+    /// `Token::EOF` that marks the end of the input, along with any errors
+    /// encountered along the way.
+    pub fn tokenize(&mut self) -> (Vec<SpannedToken>, Vec<LexingError>) {
+        let mut tokens = vec![];
+        let mut errors = vec![];
         loop {
-            {
-                let ch = chars.peek();
-                if ch.is_none() {
-                    self.curr = curr;
-                    return Ok(Token::EOF);
-                }
-                if !ch.unwrap().is_whitespace() {
+            match self.lex() {
+                Ok(token) => {
+                    let done = token.token == Token::EOF;
+                    tokens.push(token);
+                    if done {
+                        return (tokens, errors);
+                    }
+                },
+                Err(err) => errors.push(err),
+            }
+        }
+    }
+
+    fn peek_char(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    // Consumes the next character, keeping track of where we are in the input.
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        self.curr += ch.len_utf8();
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(ch)
+    }
+
+    fn span_from(&self, start: usize, line: usize, column: usize) -> Span {
+        Span { offset: self.start + start, len: self.curr - start, line, column }
+    }
+
+    // Consumes the rest of a `//` or `/* */` comment after its first `/`.
+    fn comment(&mut self, start: usize, line: usize, column: usize) -> Result<(), LexingError> {
+        if self.bump() == Some('/') {
+            while let Some(ch) = self.peek_char() {
+                if ch == '\n' {
                     break;
                 }
+                self.bump();
             }
-            chars.next();
-            curr += 1;
+            return Ok(());
         }
+        loop {
+            match self.bump() {
+                Some('*') if self.peek_char() == Some('/') => break,
+                Some(_) => {},
+                None => return Err(LexingError::UnterminatedComment(self.span_from(start, line, column))),
+            }
+        }
+        self.bump();
+        Ok(())
+    }
 
-        let start = curr;
-        let next = chars.next();
-
-        if next.is_none() {
-            return Ok(Token::EOF);
+    pub fn lex(&mut self) -> LexResult {
+        // Comments are skipped along with whitespace, unless they are kept as
+        // trivia.
+        loop {
+            while let Some(ch) = self.peek_char() {
+                if !ch.is_whitespace() {
+                    break;
+                }
+                self.bump();
+            }
+            let rest = &self.input[self.curr..];
+            if self.trivia || !(rest.starts_with("//") || rest.starts_with("/*")) {
+                break;
+            }
+            let (start, line, column) = (self.curr, self.line, self.column);
+            self.bump();
+            self.comment(start, line, column)?;
         }
 
-        curr += 1;
+        let src = self.input;
+        let (start, line, column) = (self.curr, self.line, self.column);
+
+        let Some(next) = self.bump() else {
+            let span = self.span_from(start, line, column);
+            return Ok(SpannedToken { token: Token::EOF, span });
+        };
 
-        let result = match next.unwrap() {
+        let result = match next {
             '(' => Ok(Token::LParen),
             ')' => Ok(Token::RParen),
             ',' => Ok(Token::Comma),
             ';' => Ok(Token::Semicolon),
+            ':' => Ok(Token::Colon),
             '{' => Ok(Token::LBrace),
             '}' => Ok(Token::RBrace),
-            '0'..='9' | '.' => {
-                loop {
-                    let ch = match chars.peek() {
-                        Some(ch) => *ch,
-                        None => return Ok(Token::EOF),
-                    };
-                    if ch != '.' && !ch.is_ascii_hexdigit() {
-                        break;
-                    }
-                    chars.next();
-                    curr += 1;
-                }
-                Ok(Token::Number(src[start..curr].parse().unwrap()))
+            '[' => Ok(Token::LBracket),
+            ']' => Ok(Token::RBracket),
+            '"' => return self.string(start, line, column),
+            '/' if matches!(self.peek_char(), Some('/' | '*')) => {
+                self.comment(start, line, column)?;
+                Ok(Token::Comment(src[start..self.curr].trim_end().to_string()))
+            },
+            // A `.` only starts a number when a digit follows, as in `.5`,
+            // otherwise it accesses a field.
+            '0'..='9' | '.' if next != '.' || matches!(self.peek_char(), Some('0'..='9')) => {
+                return self.number(start, line, column);
             },
+            '.' => Ok(Token::Dot),
 
             'a'..='z' | 'A'..='Z' | '_' => {
-                loop {
-                    let ch = match chars.peek() {
-                        Some(ch) => *ch,
-                        None => return Ok(Token::EOF),
-                    };
+                while let Some(ch) = self.peek_char() {
                     if ch != '_' && !ch.is_alphanumeric() {
                         break;
                     }
-                    chars.next();
-                    curr += 1;
+                    self.bump();
                 }
 
-                match &src[start..curr] {
+                match &src[start..self.curr] {
                     "var" => Ok(Token::Var),
                     "if" => Ok(Token::If),
+                    "while" => Ok(Token::While),
                     "for" => Ok(Token::For),
                     "gm" => Ok(Token::Fun),
                     "mai" => Ok(Token::Fun),
                     "fun" => Ok(Token::Fun),
                     "return" => Ok(Token::Return),
+                    "export" => Ok(Token::Export),
+                    "extern" => Ok(Token::Extern),
+                    "struct" => Ok(Token::Struct),
+                    "import" => Ok(Token::Import),
                     "or" => Ok(Token::Or),
                     "and" => Ok(Token::And),
                     "else" => Ok(Token::Else),
@@ -130,10 +236,9 @@ impl<'a> TokenLexer<'a> {
                 // check if the next character is `=` to return Token::Eqq otherwise Token::Eq
                 macro_rules! peek_next_otherwise {
                     ($char:expr, $require:expr,$otherwise:expr) => {
-                        match chars.peek() {
+                        match self.peek_char() {
                             Some($char) => {
-                                chars.next();
-                                curr += 1;
+                                self.bump();
                                 Ok($require)
                             }
                             _ => Ok($otherwise),
@@ -142,18 +247,158 @@ impl<'a> TokenLexer<'a> {
                 }
                 match op {
                     '+' => Ok(Token::Plus),
-                    '-' => Ok(Token::Minus),
+                    '-' => peek_next_otherwise!('>', Token::Arrow, Token::Minus),
                     '*' => Ok(Token::Times),
                     '/' => Ok(Token::Div),
-                    '!' => peek_next_otherwise!('=', Token::Neq, Token::Bang),
+                    '!' => peek_next_otherwise!('=', Token::BangEq, Token::Bang),
                     '=' => peek_next_otherwise!('=', Token::Eqq, Token::Eq),
                     '<' => peek_next_otherwise!('=', Token::Leq, Token::Less),
                     '>' => peek_next_otherwise!('=', Token::Geq, Token::Greater),
-                    unknown => Err(LexingError::UnknownToken(unknown.to_string()))
+                    unknown => Err(unknown.to_string()),
+                }
+            },
+        };
+        let span = self.span_from(start, line, column);
+        match result {
+            Ok(token) => Ok(SpannedToken { token, span }),
+            Err(unknown) => Err(LexingError::UnknownToken(unknown, span)),
+        }
+    }
+
+    // Lexes a number literal from its first character on. The whole run of
+    // characters that could belong to it is consumed before it is checked,
+    // so `1abc` is reported as one malformed number rather than lexing as a
+    // number followed by a name.
+    //
+    // Numbers are given to the parser without underscores, as in `1000` for
+    // `1_000`. Hexadecimal and binary ones keep their prefix, as in `0xff`
+    // for `0xFF`, since they are bit patterns whose value depends on the
+    // width of the type they are given.
+    fn number(&mut self, start: usize, line: usize, column: usize) -> LexResult {
+        let radix = match &self.input[start..] {
+            rest if rest.starts_with("0x") || rest.starts_with("0X") => 16,
+            rest if rest.starts_with("0b") || rest.starts_with("0B") => 2,
+            _ => 10,
+        };
+        let mut prev = self.input[start..].chars().next().unwrap_or('0');
+        while let Some(ch) = self.peek_char() {
+            // Exponents can be signed, which hexadecimal digits can't.
+            let sign = radix == 10 && matches!(ch, '+' | '-') && matches!(prev, 'e' | 'E');
+            if !(ch.is_alphanumeric() || ch == '_' || ch == '.' || sign) {
+                break;
+            }
+            prev = ch;
+            self.bump();
+        }
+
+        let text = &self.input[start..self.curr];
+        let span = self.span_from(start, line, column);
+        let malformed = |reason| LexingError::MalformedNumber(text.to_string(), reason, span);
+        let number = match radix {
+            10 => decimal(text).map_err(malformed)?,
+            _ => {
+                let digits = &text[2..];
+                let (missing, invalid, large) = match radix {
+                    16 => ("expected digits after `0x`", "invalid digit in hexadecimal literal", "hexadecimal literal is too large"),
+                    _ => ("expected digits after `0b`", "invalid digit in binary literal", "binary literal is too large"),
+                };
+                if digits.is_empty() {
+                    return Err(malformed(missing));
+                }
+                if digits.chars().any(|ch| ch != '_' && !ch.is_digit(radix)) {
+                    return Err(malformed(invalid));
+                }
+                separated(digits).map_err(malformed)?;
+                let value = u64::from_str_radix(&digits.replace('_', ""), radix).map_err(|_| malformed(large))?;
+                match radix {
+                    16 => format!("0x{:x}", value),
+                    _ => format!("0b{:b}", value),
                 }
             },
         };
-        self.curr = curr;
-        result
+        Ok(SpannedToken { token: Token::Number(number), span })
+    }
+
+    // Lexes the rest of a string literal after its opening quote, decoding
+    // escapes. An unknown escape is reported once the whole literal has been
+    // consumed, so lexing carries on after it.
+    fn string(&mut self, start: usize, line: usize, column: usize) -> LexResult {
+        let mut value = String::new();
+        let mut error = None;
+        loop {
+            let (escape_start, escape_line, escape_column) = (self.curr, self.line, self.column);
+            match self.bump() {
+                Some('"') => break,
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        Some(unknown) => {
+                            let span = self.span_from(escape_start, escape_line, escape_column);
+                            error.get_or_insert(LexingError::UnknownEscape(unknown, span));
+                            unknown
+                        },
+                        None => {
+                            let span = self.span_from(start, line, column);
+                            return Err(LexingError::UnterminatedString(span));
+                        },
+                    };
+                    value.push(escaped);
+                },
+                Some(ch) => value.push(ch),
+                None => {
+                    let span = self.span_from(start, line, column);
+                    return Err(LexingError::UnterminatedString(span));
+                },
+            }
+        }
+        match error {
+            Some(err) => Err(err),
+            None => Ok(SpannedToken { token: Token::Str(value), span: self.span_from(start, line, column) }),
+        }
+    }
+}
+
+// Checks a decimal literal such as `1_000`, `2.5` or `6.02e23`, returning
+// it without its underscores.
+fn decimal(text: &str) -> Result<String, &'static str> {
+    let (mantissa, exponent) = match text.find(|ch| ch == 'e' || ch == 'E') {
+        Some(at) => (&text[..at], Some(&text[at + 1..])),
+        None => (text, None),
+    };
+    if mantissa.chars().any(|ch| !ch.is_ascii_digit() && ch != '_' && ch != '.') {
+        return Err("invalid digit in number");
+    }
+    if mantissa.matches('.').count() > 1 {
+        return Err("a number can only have one `.`");
+    }
+    separated(mantissa)?;
+    if let Some(exponent) = exponent {
+        let digits = exponent.strip_prefix(|ch| ch == '+' || ch == '-').unwrap_or(exponent);
+        if digits.is_empty() || digits.chars().any(|ch| !ch.is_ascii_digit() && ch != '_') {
+            return Err("expected digits in exponent");
+        }
+        separated(digits)?;
+    }
+    Ok(text.replace('_', ""))
+}
+
+// Checks that every `_` in a number sits between two digits.
+fn separated(digits: &str) -> Result<(), &'static str> {
+    let chars = digits.chars().collect::<Vec<char>>();
+    for (i, ch) in chars.iter().enumerate() {
+        if *ch != '_' {
+            continue;
+        }
+        let before = i > 0 && chars[i - 1].is_ascii_alphanumeric();
+        let after = chars.get(i + 1).map_or(false, |ch| ch.is_ascii_alphanumeric());
+        if !before || !after {
+            return Err("`_` can only separate digits");
+        }
     }
+    Ok(())
 }
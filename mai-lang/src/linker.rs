@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use thiserror::Error;
+use wasm_encoder::{
+    ConstExpr, EntityType, ExportKind, ExportSection, GlobalSection, GlobalType, ImportSection,
+    MemorySection, MemoryType, RawSection, RefType, TableSection, TableType, ValType,
+};
+use wasmparser::{
+    DataKind, ElementItems, ElementKind, KnownCustom, Linking, Operator, Payload, SymbolFlags, SymbolInfo, TypeRef,
+};
+
+const PAGE_SIZE: u32 = 65536;
+const STACK_SIZE: u32 = 64 * 1024;
+
+// Section ids from the wasm binary format.
+const TYPE_SECTION: u8 = 1;
+const FUNCTION_SECTION: u8 = 3;
+const ELEMENT_SECTION: u8 = 9;
+const CODE_SECTION: u8 = 10;
+const DATA_SECTION: u8 = 11;
+const DATA_COUNT_SECTION: u8 = 12;
+
+#[derive(Debug,Error)]
+pub enum LinkError {
+    #[error("malformed object file: {0}")]
+    Malformed(#[from] wasmparser::BinaryReaderError),
+    #[error("cannot export `{0}`: no such function")]
+    MissingExport(String),
+    #[error("unresolved import `{0}.{1}`")]
+    UnresolvedImport(String, String),
+    #[error("object file contains an unsupported {0}")]
+    Unsupported(&'static str),
+}
+
+/// Links a relocatable wasm32 object file, as emitted by LLVM, into a
+/// standalone module exporting only the given functions.
+///
+/// The object is self contained, so no relocations need to be applied: LLVM
+/// already writes each index and data address with its value within the object.
+/// What's left is defining the memory, globals and function table the
+/// object imports from its environment. Memory is laid out as data segments at
+/// address 0, followed by the stack growing downwards, followed by the heap
+/// starting at `__heap_base`. Function imports are kept, for the host to provide.
+pub fn link(object: &[u8], exports: &[String]) -> Result<Vec<u8>, LinkError> {
+    let mut sections: HashMap<u8, Range<usize>> = HashMap::new();
+    let mut imports = ImportSection::new();
+    let mut table = None;
+    let mut globals = vec![];
+    let mut data_end = 0;
+    let mut elements_end = 0;
+    let mut symbols = HashMap::new();
+
+    for payload in wasmparser::Parser::new(0).parse_all(object) {
+        match payload? {
+            Payload::TypeSection(reader) => { sections.insert(TYPE_SECTION, reader.range()); },
+            Payload::FunctionSection(reader) => { sections.insert(FUNCTION_SECTION, reader.range()); },
+            Payload::ElementSection(reader) => {
+                sections.insert(ELEMENT_SECTION, reader.range());
+                for element in reader {
+                    let element = element?;
+                    let ElementKind::Active { offset_expr, .. } = element.kind else {
+                        return Err(LinkError::Unsupported("passive element segment"));
+                    };
+                    let Operator::I32Const { value } = offset_expr.get_operators_reader().read()? else {
+                        return Err(LinkError::Unsupported("element segment offset"));
+                    };
+                    let count = match element.items {
+                        ElementItems::Functions(items) => items.count(),
+                        ElementItems::Expressions(_, items) => items.count(),
+                    };
+                    elements_end = elements_end.max(value as u64 + count as u64);
+                }
+            },
+            Payload::DataCountSection { range, .. } => { sections.insert(DATA_COUNT_SECTION, range); },
+            Payload::CodeSectionStart { range, .. } => { sections.insert(CODE_SECTION, range); },
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    match (import.module, import.name, import.ty) {
+                        (_, _, TypeRef::Func(ty)) => {
+                            imports.import(import.module, import.name, EntityType::Function(ty));
+                        },
+                        ("env", "__linear_memory", TypeRef::Memory(_)) => (),
+                        ("env", name @ ("__stack_pointer" | "__heap_base"), TypeRef::Global(global)) => {
+                            globals.push((name, global.mutable));
+                        },
+                        ("env", "__indirect_function_table", TypeRef::Table(imported)) => {
+                            table = Some(imported.initial);
+                        },
+                        (module, name, _) => {
+                            return Err(LinkError::UnresolvedImport(module.to_string(), name.to_string()));
+                        },
+                    }
+                }
+            },
+            Payload::DataSection(reader) => {
+                sections.insert(DATA_SECTION, reader.range());
+                for data in reader {
+                    let data = data?;
+                    let DataKind::Active { offset_expr, .. } = data.kind else {
+                        return Err(LinkError::Unsupported("passive data segment"));
+                    };
+                    let Operator::I32Const { value } = offset_expr.get_operators_reader().read()? else {
+                        return Err(LinkError::Unsupported("data segment offset"));
+                    };
+                    data_end = data_end.max(value as u32 + data.data.len() as u32);
+                }
+            },
+            Payload::CustomSection(reader) => {
+                if let KnownCustom::Linking(linking) = reader.as_known() {
+                    for subsection in linking.subsections() {
+                        let Linking::SymbolTable(symbol_table) = subsection? else {
+                            continue;
+                        };
+                        for symbol in symbol_table {
+                            if let SymbolInfo::Func { flags, index, name: Some(name) } = symbol? {
+                                if !flags.contains(SymbolFlags::UNDEFINED) {
+                                    symbols.insert(name.to_string(), index);
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            // Objects only ever import their memory, tables and globals.
+            Payload::TableSection(_) => return Err(LinkError::Unsupported("table definition")),
+            Payload::MemorySection(_) => return Err(LinkError::Unsupported("memory definition")),
+            Payload::GlobalSection(_) => return Err(LinkError::Unsupported("global definition")),
+            Payload::StartSection { .. } => return Err(LinkError::Unsupported("start function")),
+            _ => (),
+        }
+    }
+
+    let stack_base = align(data_end, 16);
+    let heap_base = stack_base + STACK_SIZE;
+
+    // The object imports its table without knowing how many functions end up
+    // in it, so it is sized to fit every element segment.
+    let mut tables = TableSection::new();
+    if let Some(initial) = table {
+        tables.table(TableType {
+            element_type: RefType::FUNCREF,
+            table64: false,
+            minimum: initial.max(elements_end),
+            maximum: None,
+            shared: false,
+        });
+    }
+
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: ((heap_base + PAGE_SIZE - 1) / PAGE_SIZE) as u64,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+
+    // Imported globals are defined in the order they were imported, so they
+    // keep the indices the object refers to them by. Both start out at the
+    // heap base, as the stack grows down from there.
+    let mut global_section = GlobalSection::new();
+    let global = |mutable| GlobalType { val_type: ValType::I32, mutable, shared: false };
+    for (_, mutable) in globals.iter() {
+        global_section.global(global(*mutable), &ConstExpr::i32_const(heap_base as i32));
+    }
+    let heap_base_index = match globals.iter().position(|(name, _)| *name == "__heap_base") {
+        Some(index) => index as u32,
+        None => {
+            global_section.global(global(false), &ConstExpr::i32_const(heap_base as i32));
+            global_section.len() - 1
+        },
+    };
+
+    let mut export_section = ExportSection::new();
+    export_section.export("memory", ExportKind::Memory, 0);
+    export_section.export("__heap_base", ExportKind::Global, heap_base_index);
+    for name in exports.iter() {
+        let index = symbols.get(name).ok_or_else(|| LinkError::MissingExport(name.clone()))?;
+        export_section.export(name, ExportKind::Func, *index);
+    }
+
+    let raw = |id: u8| sections.get(&id).map(|range| RawSection { id, data: &object[range.clone()] });
+    let mut module = wasm_encoder::Module::new();
+    // Sections have to be written in the order the binary format defines.
+    if let Some(section) = raw(TYPE_SECTION) { module.section(&section); }
+    if !imports.is_empty() { module.section(&imports); }
+    if let Some(section) = raw(FUNCTION_SECTION) { module.section(&section); }
+    if !tables.is_empty() { module.section(&tables); }
+    module.section(&memories);
+    module.section(&global_section);
+    module.section(&export_section);
+    if let Some(section) = raw(ELEMENT_SECTION) { module.section(&section); }
+    if let Some(section) = raw(DATA_COUNT_SECTION) { module.section(&section); }
+    if let Some(section) = raw(CODE_SECTION) { module.section(&section); }
+    if let Some(section) = raw(DATA_SECTION) { module.section(&section); }
+    Ok(module.finish())
+}
+
+fn align(value: u32, to: u32) -> u32 {
+    (value + to - 1) / to * to
+}
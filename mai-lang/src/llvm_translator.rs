@@ -1,27 +1,341 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use inkwell::module::Module;
+use inkwell::module::{Linkage,Module};
 use inkwell::builder::Builder;
 use inkwell::context::Context;
+use inkwell::memory_buffer::MemoryBuffer;
 use inkwell::passes::PassManager;
-use inkwell::types::BasicMetadataTypeEnum;
-use inkwell::basic_block::BasicBlock;
-use inkwell::values::{BasicValue,FloatValue,FunctionValue,PointerValue};
-use inkwell::FloatPredicate;
+use inkwell::targets::{CodeModel,FileType,InitializationConfig,RelocMode,Target,TargetMachine,TargetTriple};
+use inkwell::types::{BasicMetadataTypeEnum,BasicType,BasicTypeEnum,FunctionType,PointerType};
+use inkwell::values::{BasicMetadataValueEnum,BasicValueEnum,CallableValue,FunctionValue,IntValue,PointerValue};
+use inkwell::{AddressSpace,FloatPredicate,IntPredicate,OptimizationLevel};
 
+use crate::builtins::Builtin;
+use crate::diagnostic::CompileError;
 use crate::parser::*;
-use crate::token::Token;
+use crate::token::{Span,SpannedToken,Token};
+use crate::types::{checked,float_literal,integer_literal,structs,StructDef,Type};
+
+// Support functions for allocation, strings and arrays, written in LLVM IR and
+// linked into every module before translation.
+const RUNTIME_IR: &str = include_str!("runtime.ll");
+
+/// Extracts the name of an identifier token.
+fn ident(token: &SpannedToken) -> Result<&str, CompileError> {
+    match &token.token {
+        Token::Ident(name) => Ok(name.as_str()),
+        tok => Err(CompileError::new(format!("expected identifier, found {}", tok.describe()), token.span)),
+    }
+}
+
+/// Lays out a closure object as a pointer to its function, which is an
+/// index into the wasm table, followed by the variables it captured.
+fn closure_def(captures: &[(String, Type)]) -> StructDef {
+    let mut fields = vec![(String::new(), Type::I32)];
+    fields.extend(captures.iter().cloned());
+    StructDef { name: "closure".to_string(), fields }
+}
+
+/// A lambda whose body is translated once the function it appears in is
+/// done, as the builder can only emit one function at a time.
+pub struct PendingLambda<'ctx> {
+    function: FunctionValue<'ctx>,
+    params: Vec<Param>,
+    ret: Type,
+    body: Vec<Box<Stmt>>,
+    captures: Vec<(String, Type)>,
+    span: Span,
+}
 
 pub struct Translator<'a, 'ctx> {
     pub context: &'ctx Context,
     pub builder: &'a Builder<'ctx>,
     pub fpm: &'a PassManager<FunctionValue<'ctx>>,
     pub module: &'a Module<'ctx>,
-    pub variables: HashMap<String, PointerValue<'ctx>>,
+    // Stack slots of the variables in scope, one map per scope like the
+    // resolver's, so variable depths index into it from the end.
+    pub variables: Vec<HashMap<String, PointerValue<'ctx>>>,
+    pub structs: HashMap<String, StructDef>,
+    // Types of the functions declared by the program, for using them as
+    // values.
+    pub functions: HashMap<String, Type>,
+    pub pending: RefCell<Vec<PendingLambda<'ctx>>>,
     pub fn_value_opt: Option<FunctionValue<'ctx>>,
 }
 
 impl<'a, 'ctx> Translator<'a, 'ctx> {
-    fn create_stack_alloc(&self, name: &str) -> PointerValue<'ctx> {
+    /// Returns the LLVM type used to represent values of a mai type, or
+    /// `None` for `void`.
+    fn llvm_type(&self, ty: &Type) -> Option<BasicTypeEnum<'ctx>> {
+        match ty {
+            Type::I32 => Some(self.context.i32_type().into()),
+            Type::I64 => Some(self.context.i64_type().into()),
+            Type::F64 => Some(self.context.f64_type().into()),
+            Type::Bool => Some(self.context.bool_type().into()),
+            Type::Str | Type::Array(_) | Type::Struct(_) => Some(self.pointer_type().into()),
+            Type::Function { params, ret } => Some(self.closure_type(params, ret).into()),
+            Type::Void => None,
+        }
+    }
+
+    /// Returns the type of the function behind a closure, which takes the
+    /// closure itself as an extra first parameter to read its captures from.
+    fn closure_fn_type(&self, params: &[Type], ret: &Type) -> FunctionType<'ctx> {
+        let mut args: Vec<BasicMetadataTypeEnum> = vec![self.pointer_type().into()];
+        args.extend(params.iter().map(|param| BasicMetadataTypeEnum::from(self.llvm_type(param).unwrap())));
+        match self.llvm_type(ret) {
+            Some(ret) => ret.fn_type(&args, false),
+            None => self.context.void_type().fn_type(&args, false),
+        }
+    }
+
+    /// Closures are pointers to their first field, the function pointer, so
+    /// calls know the type of the function without any casts.
+    fn closure_type(&self, params: &[Type], ret: &Type) -> PointerType<'ctx> {
+        self.closure_fn_type(params, ret)
+            .ptr_type(AddressSpace::default())
+            .ptr_type(AddressSpace::default())
+    }
+
+    /// Strings, arrays and structs are all untyped pointers into linear
+    /// memory, as their layout is handled by the functions in `runtime.ll`
+    /// and `StructDef::layout`.
+    fn pointer_type(&self) -> PointerType<'ctx> {
+        self.context.i8_type().ptr_type(AddressSpace::default())
+    }
+
+    /// Returns the size in bytes an element of the given type takes up in an
+    /// array on wasm32.
+    fn element_size(&self, ty: BasicTypeEnum<'ctx>) -> IntValue<'ctx> {
+        let size = match ty {
+            BasicTypeEnum::IntType(ty) => (ty.get_bit_width() as u64 + 7) / 8,
+            BasicTypeEnum::PointerType(_) => 4,
+            _ => 8,
+        };
+        self.context.i32_type().const_int(size, false)
+    }
+
+    /// Returns the zero value of a type, used for variables declared without
+    /// an initializer and functions that fall off the end, or `None` for `void`.
+    fn zero_value(&self, ty: &Type) -> Option<BasicValueEnum<'ctx>> {
+        let zero = match self.llvm_type(ty)? {
+            // Pointers are never null, empty or zeroed values are used instead.
+            BasicTypeEnum::PointerType(_) => match ty {
+                Type::Str => self.string_literal("").into(),
+                Type::Array(elem) => self.new_array(elem, 0),
+                Type::Struct(name) => {
+                    let def = &self.structs[name];
+                    let object = self.new_struct(def);
+                    for (field, ty) in def.fields.iter() {
+                        let zero = self.zero_value(ty).unwrap();
+                        self.builder.build_store(self.field_pointer(object, def, field), zero);
+                    }
+                    object.into()
+                },
+                // Calling the zero value of a function type returns zero.
+                Type::Function { params, ret } => {
+                    let fn_type = self.closure_fn_type(params, ret);
+                    let name = format!("{}.zero", ty);
+                    let function = match self.module.get_function(&name) {
+                        Some(function) => function,
+                        None => self.build_helper(&name, fn_type, |_| {
+                            match self.zero_value(ret) {
+                                Some(zero) => self.builder.build_return(Some(&zero)),
+                                None => self.builder.build_return(None),
+                            };
+                        }),
+                    };
+                    self.new_closure(function, params, ret, &[]).into()
+                },
+                _ => unreachable!("only strings, arrays, structs and closures are pointers"),
+            },
+            BasicTypeEnum::IntType(ty) => ty.const_zero().into(),
+            BasicTypeEnum::FloatType(ty) => ty.const_zero().into(),
+            BasicTypeEnum::StructType(ty) => ty.const_zero().into(),
+            BasicTypeEnum::ArrayType(ty) => ty.const_zero().into(),
+            BasicTypeEnum::VectorType(ty) => ty.const_zero().into(),
+        };
+        Some(zero)
+    }
+
+    /// Emits a constant string, laid out as its length followed by its bytes.
+    fn string_literal(&self, value: &str) -> PointerValue<'ctx> {
+        let i8_type = self.context.i8_type();
+        let bytes = value
+            .bytes()
+            .map(|byte| i8_type.const_int(byte as u64, false))
+            .collect::<Vec<IntValue>>();
+        let fields: [BasicValueEnum; 2] = [
+            self.context.i32_type().const_int(value.len() as u64, false).into(),
+            i8_type.const_array(&bytes).into(),
+        ];
+        let init = self.context.const_struct(&fields, false);
+        let global = self.module.add_global(init.get_type(), None, "str");
+        global.set_initializer(&init);
+        global.set_constant(true);
+        global.set_linkage(Linkage::Private);
+        global.set_unnamed_addr(true);
+        global.as_pointer_value().const_cast(self.pointer_type())
+    }
+
+    /// Allocates an array of `len` elements, which are left for the caller to
+    /// initialize.
+    fn new_array(&self, elem: &Type, len: u64) -> BasicValueEnum<'ctx> {
+        let i32_type = self.context.i32_type();
+        let size = self.element_size(self.llvm_type(elem).unwrap());
+        self.runtime_call("__mai_array_new", &[i32_type.const_int(len, false).into(), size.into()])
+    }
+
+    /// Returns a pointer to an array element, trapping if the index is out of
+    /// bounds.
+    fn element_pointer(
+        &self,
+        array: BasicValueEnum<'ctx>,
+        index: IntValue<'ctx>,
+        elem: BasicTypeEnum<'ctx>,
+    ) -> PointerValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let index = if index.get_type().get_bit_width() < 64 {
+            self.builder.build_int_s_extend(index, i64_type, "index")
+        } else {
+            index
+        };
+        let size = self.element_size(elem);
+        let ptr = self.runtime_call("__mai_array_index", &[array.into(), index.into(), size.into()]);
+        self.builder.build_pointer_cast(
+            ptr.into_pointer_value(),
+            elem.ptr_type(AddressSpace::default()),
+            "elemptr",
+        )
+    }
+
+    /// Allocates a struct, whose fields are left for the caller to initialize.
+    fn new_struct(&self, def: &StructDef) -> PointerValue<'ctx> {
+        let (_, size) = def.layout();
+        let size = self.context.i32_type().const_int(size as u64, false);
+        self.runtime_call("__mai_alloc", &[size.into()]).into_pointer_value()
+    }
+
+    /// Returns a pointer to a field of a struct, cast to the field's type.
+    fn field_pointer(&self, object: PointerValue<'ctx>, def: &StructDef, field: &str) -> PointerValue<'ctx> {
+        let (offsets, _) = def.layout();
+        let (index, ty) = def.field(field).expect("the type checker only allows declared fields");
+        let offset = self.context.i32_type().const_int(offsets[index] as u64, false);
+        let ptr = unsafe { self.builder.build_in_bounds_gep(object, &[offset], "fieldptr") };
+        self.builder.build_pointer_cast(
+            ptr,
+            self.llvm_type(ty).unwrap().ptr_type(AddressSpace::default()),
+            "fieldptr",
+        )
+    }
+
+    /// Emits an internal function whose body is built by `build`, leaving the
+    /// builder where it was afterwards.
+    fn build_helper(
+        &self,
+        name: &str,
+        fn_type: FunctionType<'ctx>,
+        build: impl FnOnce(FunctionValue<'ctx>),
+    ) -> FunctionValue<'ctx> {
+        let function = self.module.add_function(name, fn_type, Some(Linkage::Internal));
+        let current = self.builder.get_insert_block();
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+        build(function);
+        if let Some(current) = current {
+            self.builder.position_at_end(current);
+        }
+        function
+    }
+
+    /// Allocates a closure calling the given function, storing the current
+    /// values of the variables it captures.
+    fn new_closure(
+        &self,
+        function: FunctionValue<'ctx>,
+        params: &[Type],
+        ret: &Type,
+        captures: &[(String, Type)],
+    ) -> PointerValue<'ctx> {
+        let def = closure_def(captures);
+        let object = self.new_struct(&def);
+        let closure = self.builder.build_pointer_cast(object, self.closure_type(params, ret), "closure");
+        self.builder.build_store(closure, function.as_global_value().as_pointer_value());
+        for (name, _) in captures.iter() {
+            // The closure sees the variable in scope where it is created.
+            let var = self.variables.iter().rev().find_map(|scope| scope.get(name)).unwrap();
+            let value = self.builder.build_load(*var, name);
+            self.builder.build_store(self.field_pointer(object, &def, name), value);
+        }
+        closure
+    }
+
+    /// Returns a closure calling a function declared by the program, through
+    /// a trampoline that ignores the closure argument.
+    fn function_closure(&self, name: &str, fun: FunctionValue<'ctx>) -> PointerValue<'ctx> {
+        let Type::Function { params, ret } = &self.functions[name] else {
+            unreachable!("functions have function types");
+        };
+        let trampoline_name = format!("{}.closure", name);
+        let trampoline = match self.module.get_function(&trampoline_name) {
+            Some(trampoline) => trampoline,
+            None => self.build_helper(&trampoline_name, self.closure_fn_type(params, ret), |trampoline| {
+                let args = trampoline
+                    .get_param_iter()
+                    .skip(1)
+                    .map(|arg| arg.into())
+                    .collect::<Vec<BasicMetadataValueEnum>>();
+                let result = self.builder.build_call(fun, &args, "tmpcall").try_as_basic_value().left();
+                match result {
+                    Some(result) => self.builder.build_return(Some(&result)),
+                    None => self.builder.build_return(None),
+                };
+            }),
+        };
+        self.new_closure(trampoline, params, ret, &[])
+    }
+
+    /// Calls one of the functions defined in `runtime.ll`.
+    fn runtime_call(&self, name: &str, args: &[BasicMetadataValueEnum<'ctx>]) -> BasicValueEnum<'ctx> {
+        let fun = self.module
+            .get_function(name)
+            .expect("runtime functions are linked in before translation");
+        self.builder
+            .build_call(fun, args, "tmpcall")
+            .try_as_basic_value()
+            .left()
+            .expect("runtime functions used as values return one")
+    }
+
+    fn translate_builtin(&self, builtin: Builtin, args: &[Box<Expr>]) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        let mut values: Vec<BasicValueEnum> = Vec::with_capacity(args.len());
+        for arg in args.iter() {
+            values.push(self.translate_expr(arg)?);
+        }
+        match builtin {
+            Builtin::Len => {
+                let len = self.runtime_call("__mai_len", &[values[0].into()]).into_int_value();
+                Ok(self.builder.build_int_z_extend(len, self.context.i64_type(), "len").into())
+            },
+            Builtin::Concat => Ok(self.runtime_call("__mai_str_concat", &[values[0].into(), values[1].into()])),
+            Builtin::Push => {
+                let (array, value) = (values[0], values[1]);
+                let ty = value.get_type();
+                let size = self.element_size(ty);
+                let slot = self.runtime_call("__mai_array_push", &[array.into(), size.into()]);
+                let slot = self.builder.build_pointer_cast(
+                    slot.into_pointer_value(),
+                    ty.ptr_type(AddressSpace::default()),
+                    "elemptr",
+                );
+                self.builder.build_store(slot, value);
+                // Like calls to `void` functions, the value is discarded.
+                Ok(self.context.bool_type().const_zero().into())
+            },
+        }
+    }
+
+    fn create_stack_alloc(&self, name: &str, ty: BasicTypeEnum<'ctx>) -> PointerValue<'ctx> {
         let builder = self.context.create_builder();
 
         let entry = self.fn_value_opt.unwrap().get_first_basic_block().unwrap();
@@ -31,210 +345,642 @@ impl<'a, 'ctx> Translator<'a, 'ctx> {
             None => builder.position_at_end(entry),
         }
 
-        builder.build_alloca(self.context.f64_type(), name)
+        builder.build_alloca(ty, name)
     }
 
-    pub fn translate_function_sig(&self, fun: &Stmt) -> Result<FunctionValue<'ctx>, &'static str> {
-        let Stmt::Function { name: Token::Ident(fn_name), params, body: _ } = fun else {
-            panic!("Not a function");
+    pub fn translate_function_sig(&self, fun: &Stmt) -> Result<FunctionValue<'ctx>, CompileError> {
+        let (Stmt::Function { name, params, ret, .. } | Stmt::Extern { name, params, ret }) = fun else {
+            return Err(CompileError {
+                message: "only function declarations are allowed at the top level".to_string(),
+                span: fun.span(),
+            });
         };
-        let return_type = self.context.f64_type();
-        let arg_types = std::iter::repeat(return_type)
-            .take(params.len())
-            .map(|f| f.into())
+        let fn_name = ident(name)?;
+        let arg_types = params
+            .iter()
+            .map(|param| self.llvm_type(&checked(&param.ty)).unwrap().into())
             .collect::<Vec<BasicMetadataTypeEnum>>();
         let args = arg_types.as_slice();
 
-        let fn_type = self.context.f64_type().fn_type(args, false); // No var args.
-        let fn_val = self.module.add_function(fn_name.as_str(), fn_type, None);
+        let fn_type = match self.llvm_type(&checked(ret)) {
+            Some(ret) => ret.fn_type(args, false), // No var args.
+            None => self.context.void_type().fn_type(args, false),
+        };
+        let fn_val = self.module.add_function(fn_name, fn_type, None);
 
         for (i, arg) in fn_val.get_param_iter().enumerate() {
-            let param = params[i].clone();
-            let Token::Ident(arg_ident) = param else {
-                panic!("Not an arg ident");
-            };
-            arg.into_float_value().set_name(arg_ident.as_str());
+            let arg_name = ident(&params[i].name)?;
+            match arg {
+                BasicValueEnum::IntValue(arg) => arg.set_name(arg_name),
+                BasicValueEnum::FloatValue(arg) => arg.set_name(arg_name),
+                _ => {},
+            }
         }
 
         Ok(fn_val)
     }
 
-    pub fn translate_function(&mut self, fun: &Stmt) -> Result<FunctionValue<'ctx>, &'static str> {
-        let Stmt::Function { name: _, params, body } = fun else {
-            panic!("Not a function");
+    pub fn translate_function(&mut self, fun: &Stmt) -> Result<FunctionValue<'ctx>, CompileError> {
+        let (Stmt::Function { name, .. } | Stmt::Extern { name, .. }) = fun else {
+            return Err(CompileError {
+                message: "only function declarations are allowed at the top level".to_string(),
+                span: fun.span(),
+            });
         };
-        let sig = self.translate_function_sig(fun)?;
-        if body.is_empty() {
+        // Signatures are declared ahead of time by `translate`, so only fall back
+        // to declaring one here if this function has not been seen yet.
+        let sig = match self.module.get_function(ident(name)?) {
+            Some(sig) => sig,
+            None => self.translate_function_sig(fun)?,
+        };
+        // Externs are left as declarations, which become imports of the wasm
+        // module for the host to provide.
+        let Stmt::Function { params, ret, body, .. } = fun else {
             return Ok(sig);
-        }
-        let entry = self.context.append_basic_block(sig, "entry");
-        self.builder.position_at_end(entry);
-        self.fn_value_opt = Some(sig);
-        self.variables.reserve(params.len());
+        };
+        self.translate_body(sig, params, &checked(ret), body, None, name.span)
+    }
 
-        for (i, arg) in sig.get_param_iter().enumerate() {
-            let param = params[i].clone();
-            let Token::Ident(arg_ident) = param else {
-                panic!("Not an arg ident");
-            };
-            let alloca = self.create_stack_alloc(arg_ident.as_str());
+    /// Translates a lambda queued up while translating an earlier function.
+    fn translate_lambda(&mut self, lambda: PendingLambda<'ctx>) -> Result<FunctionValue<'ctx>, CompileError> {
+        let PendingLambda { function, params, ret, body, captures, span } = lambda;
+        self.translate_body(function, &params, &ret, &body, Some(captures.as_slice()), span)
+    }
+
+    /// Translates the body of a function into its declaration. Lambdas take
+    /// their closure as the first argument, whose captures are copied into
+    /// local variables.
+    fn translate_body(
+        &mut self,
+        function: FunctionValue<'ctx>,
+        params: &[Param],
+        ret: &Type,
+        body: &[Box<Stmt>],
+        captures: Option<&[(String, Type)]>,
+        span: Span,
+    ) -> Result<FunctionValue<'ctx>, CompileError> {
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+        self.fn_value_opt = Some(function);
+        self.variables = vec![HashMap::new()];
+
+        let mut args = function.get_param_iter();
+        if let Some(captures) = captures {
+            let env = args.next().unwrap().into_pointer_value();
+            let def = closure_def(captures);
+            for (name, ty) in captures.iter() {
+                let value = self.builder.build_load(self.field_pointer(env, &def, name), name);
+                let alloca = self.create_stack_alloc(name, self.llvm_type(ty).unwrap());
+                self.builder.build_store(alloca, value);
+                self.variables[0].insert(name.clone(), alloca);
+            }
+        }
+        for (param, arg) in params.iter().zip(args) {
+            let arg_ident = ident(&param.name)?;
+            let alloca = self.create_stack_alloc(arg_ident, arg.get_type());
             self.builder.build_store(alloca, arg);
-            self.variables.insert(arg_ident, alloca);
+            self.variables[0].insert(arg_ident.to_string(), alloca);
         }
 
-        let body = self.translate_stmt(body.first().unwrap())?;
+        self.translate_block(body)?;
 
-        self.builder.build_return(Some(&body));
+        // Functions that fall off the end without a `return` yield zero.
+        if !self.is_terminated() {
+            match self.zero_value(ret) {
+                Some(zero) => self.builder.build_return(Some(&zero)),
+                None => self.builder.build_return(None),
+            };
+        }
 
-        if sig.verify(true) {
-            self.fpm.run_on(&sig);
-            return Ok(sig);
+        if function.verify(true) {
+            self.fpm.run_on(&function);
+            return Ok(function);
         }
         unsafe {
-            sig.delete();
+            function.delete();
+        }
+
+        Err(CompileError::new("invalid generated function", span))
+    }
+
+    /// Returns whether the block we are currently emitting into already ends in
+    /// a terminator such as `ret` or `br`, in which case nothing more may be
+    /// appended to it.
+    fn is_terminated(&self) -> bool {
+        self.builder
+            .get_insert_block()
+            .and_then(|bb| bb.get_terminator())
+            .is_some()
+    }
+
+    fn translate_block(&mut self, statements: &[Box<Stmt>]) -> Result<(), CompileError> {
+        self.variables.push(HashMap::new());
+        for stmt in statements.iter() {
+            // Anything after an early `return` is unreachable.
+            if self.is_terminated() {
+                break;
+            }
+            self.translate_stmt(stmt)?;
         }
+        self.variables.pop();
+        Ok(())
+    }
 
-        Err("Invalid generated function")
+    /// Returns the stack slot of a variable, declared `depth` scopes out.
+    fn variable(&self, name: &SpannedToken, depth: usize) -> Result<PointerValue<'ctx>, CompileError> {
+        let id = ident(name)?;
+        self.variables
+            .iter()
+            .rev()
+            .nth(depth)
+            .and_then(|scope| scope.get(id))
+            .copied()
+            .ok_or_else(|| CompileError::new(format!("cannot find variable `{}` in this scope", id), name.span))
     }
 
-    fn translate_stmt(&mut self, stmt: &Box<Stmt>) -> Result<FloatValue<'ctx>, &'static str> {
+    fn translate_stmt(&mut self, stmt: &Box<Stmt>) -> Result<(), CompileError> {
         match stmt.as_ref() {
-            Stmt::Expr(expr) => self.translate_expr(expr),
+            Stmt::Expr(expr) => {
+                self.translate_expr(expr)?;
+                Ok(())
+            },
             Stmt::If { 
                 cond, 
                 then_branch, 
                 else_branch,
             } => self.translate_conditional(cond, then_branch, else_branch),
-            Stmt::Block(statements) => {
-                return self.translate_stmt(statements.first().unwrap());
-            }
+            Stmt::Block(statements) => self.translate_block(statements),
             Stmt::Return { keyword: _, value } => {
-                if value.is_some() {
-                    let value = value.as_ref().unwrap();
-                    return self.translate_expr(value);
+                match value {
+                    Some(value) => {
+                        let value = self.translate_expr(value)?;
+                        self.builder.build_return(Some(&value));
+                    },
+                    None => {
+                        self.builder.build_return(None);
+                    },
                 }
-                return Ok(self.context.f64_type().const_zero());
+                Ok(())
+            },
+            Stmt::Var { name, ty, initializer } => {
+                let var_name = ident(name)?;
+                let var_type = self.llvm_type(&checked(ty)).unwrap();
+                let initial_value = match initializer {
+                    Some(initializer) => self.translate_expr(initializer)?,
+                    None => self.zero_value(&checked(ty)).unwrap(),
+                };
+                let alloca = self.create_stack_alloc(var_name, var_type);
+                self.builder.build_store(alloca, initial_value);
+                self.variables.last_mut().unwrap().insert(var_name.to_string(), alloca);
+                Ok(())
+            },
+            Stmt::While { condition, body } => self.translate_loop(condition, body),
+            Stmt::Function { name, .. } | Stmt::Extern { name, .. } => {
+                Err(CompileError::new("nested function declarations are not supported", name.span))
+            },
+            Stmt::Struct { name, .. } => {
+                Err(CompileError::new("nested struct declarations are not supported", name.span))
+            },
+            Stmt::Import { keyword, .. } => {
+                Err(CompileError::new("imports are only allowed at the top level", keyword.span))
             },
-            item => panic!("Could not handle value: {:?}", item)
         }
     }
 
+    /// Translates a condition, which the type checker guarantees is an `i1`.
+    fn translate_condition(&self, cond: &Box<Expr>) -> Result<IntValue<'ctx>, CompileError> {
+        Ok(self.translate_expr(cond)?.into_int_value())
+    }
+
     pub fn translate_conditional(
         &mut self,
         cond: &Box<Expr>, 
         then_branch: &Box<Stmt>, 
         else_branch: &Option<Box<Stmt>>
-    ) -> Result<FloatValue<'ctx>, &'static str> {
+    ) -> Result<(), CompileError> {
         let parent = self.fn_value_opt.unwrap();
-        let zero_const = self.context.f64_type().const_float(0.0);
 
-        // create condition by comparing without 0.0 and returning an int
-        let cond = self.translate_expr(cond)?;
-        let cond = self
-            .builder
-            .build_float_compare(FloatPredicate::ONE, cond, zero_const, "ifcond");
+        let cond = self.translate_condition(cond)?;
 
         // build branch
         let then_bb = self.context.append_basic_block(parent, "then");
-        let else_bb = self.context.append_basic_block(parent, "else");
+        let else_bb = match else_branch {
+            Some(_) => Some(self.context.append_basic_block(parent, "else")),
+            None => None,
+        };
         let cont_bb = self.context.append_basic_block(parent, "ifcont");
 
-        self.builder.build_conditional_branch(cond, then_bb, else_bb);
+        self.builder.build_conditional_branch(cond, then_bb, else_bb.unwrap_or(cont_bb));
 
-        // build then block
+        // build then block, only falling through if it did not return
         self.builder.position_at_end(then_bb);
-        let then_val = self.translate_stmt(then_branch)?;
-        self.builder.build_unconditional_branch(cont_bb);
-
-        let then_bb = self.builder.get_insert_block().unwrap();
-
-        let mut incoming: Vec<(&dyn BasicValue, BasicBlock)> = vec![];
-        incoming.push((&then_val, then_bb));
+        self.translate_block(std::slice::from_ref(then_branch))?;
+        if !self.is_terminated() {
+            self.builder.build_unconditional_branch(cont_bb);
+        }
 
         // build else block
-        self.builder.position_at_end(else_bb);
-        let else_b = *else_branch.clone().unwrap();
-        let else_box = Box::new(else_b);
-        let else_val = self.translate_stmt(&else_box)?;
-        let else_bb = self.builder.get_insert_block().unwrap();
-        self.builder.build_unconditional_branch(cont_bb);
-        incoming.push((&else_val, else_bb));
-
-        // emit merge block
+        if let (Some(else_bb), Some(else_branch)) = (else_bb, else_branch) {
+            self.builder.position_at_end(else_bb);
+            self.translate_block(std::slice::from_ref(else_branch))?;
+            if !self.is_terminated() {
+                self.builder.build_unconditional_branch(cont_bb);
+            }
+        }
+
+        // continue emitting after the conditional
         self.builder.position_at_end(cont_bb);
+        Ok(())
+    }
+
+    pub fn translate_loop(
+        &mut self,
+        condition: &Box<Expr>,
+        body: &Box<Stmt>,
+    ) -> Result<(), CompileError> {
+        let parent = self.fn_value_opt.unwrap();
 
-        let phi = self.builder.build_phi(self.context.f64_type(), "iftmp");
-        phi.add_incoming(incoming.as_slice());
+        let header_bb = self.context.append_basic_block(parent, "loop");
+        let body_bb = self.context.append_basic_block(parent, "loopbody");
+        let exit_bb = self.context.append_basic_block(parent, "afterloop");
 
-        Ok(phi.as_basic_value().into_float_value())
+        self.builder.build_unconditional_branch(header_bb);
+
+        // re-evaluate the condition every time we enter the loop header
+        self.builder.position_at_end(header_bb);
+        let cond = self.translate_condition(condition)?;
+        self.builder.build_conditional_branch(cond, body_bb, exit_bb);
+
+        // build body and jump back to the header
+        self.builder.position_at_end(body_bb);
+        self.translate_block(std::slice::from_ref(body))?;
+        if !self.is_terminated() {
+            self.builder.build_unconditional_branch(header_bb);
+        }
+
+        self.builder.position_at_end(exit_bb);
+        Ok(())
+    }
+
+    /// Translates `and` / `or` with short-circuiting, only evaluating the
+    /// right hand side when the left hand side does not decide the result.
+    pub fn translate_logical(
+        &self,
+        op: &SpannedToken,
+        left: &Box<Expr>,
+        right: &Box<Expr>,
+    ) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        let parent = self.fn_value_opt.unwrap();
+        let bool_type = self.context.bool_type();
+
+        let lhs = self.translate_condition(left)?;
+        let lhs_bb = self.builder.get_insert_block().unwrap();
+
+        let rhs_bb = self.context.append_basic_block(parent, "logicrhs");
+        let merge_bb = self.context.append_basic_block(parent, "logicmerge");
+
+        // the value produced when the right hand side is skipped
+        let short_circuit = match op.token {
+            Token::And => {
+                self.builder.build_conditional_branch(lhs, rhs_bb, merge_bb);
+                bool_type.const_zero()
+            },
+            Token::Or => {
+                self.builder.build_conditional_branch(lhs, merge_bb, rhs_bb);
+                bool_type.const_all_ones()
+            },
+            _ => return Err(CompileError::new("unsupported logical operation", op.span)),
+        };
+
+        self.builder.position_at_end(rhs_bb);
+        let rhs = self.translate_condition(right)?;
+        let rhs_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_bb);
+
+        self.builder.position_at_end(merge_bb);
+        let phi = self.builder.build_phi(bool_type, "logictmp");
+        phi.add_incoming(&[(&short_circuit, lhs_bb), (&rhs, rhs_bb)]);
+
+        Ok(phi.as_basic_value())
     }
 
-    pub fn translate_expr(&self, expr: &Box<Expr>) -> Result<FloatValue<'ctx>, &'static str> {
+    fn translate_binary(
+        &self,
+        op: &SpannedToken,
+        lhs: BasicValueEnum<'ctx>,
+        rhs: BasicValueEnum<'ctx>,
+    ) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        let unsupported = || CompileError::new("unsupported binary operation", op.span);
+        match (lhs, rhs) {
+            (BasicValueEnum::IntValue(lhs), BasicValueEnum::IntValue(rhs)) => {
+                let predicate = match op.token {
+                    Token::Plus => return Ok(self.builder.build_int_add(lhs, rhs, "tmpadd").into()),
+                    Token::Minus => return Ok(self.builder.build_int_sub(lhs, rhs, "tmpsub").into()),
+                    Token::Times => return Ok(self.builder.build_int_mul(lhs, rhs, "tmpmul").into()),
+                    Token::Div => return Ok(self.builder.build_int_signed_div(lhs, rhs, "tmpdiv").into()),
+                    Token::Less => IntPredicate::SLT,
+                    Token::Greater => IntPredicate::SGT,
+                    Token::Leq => IntPredicate::SLE,
+                    Token::Geq => IntPredicate::SGE,
+                    Token::Eqq => IntPredicate::EQ,
+                    Token::BangEq => IntPredicate::NE,
+                    _ => return Err(unsupported()),
+                };
+                Ok(self.builder.build_int_compare(predicate, lhs, rhs, "tmpcmp").into())
+            },
+            (BasicValueEnum::FloatValue(lhs), BasicValueEnum::FloatValue(rhs)) => {
+                let predicate = match op.token {
+                    Token::Plus => return Ok(self.builder.build_float_add(lhs, rhs, "tmpadd").into()),
+                    Token::Minus => return Ok(self.builder.build_float_sub(lhs, rhs, "tmpsub").into()),
+                    Token::Times => return Ok(self.builder.build_float_mul(lhs, rhs, "tmpmul").into()),
+                    Token::Div => return Ok(self.builder.build_float_div(lhs, rhs, "tmpdiv").into()),
+                    Token::Less => FloatPredicate::OLT,
+                    Token::Greater => FloatPredicate::OGT,
+                    Token::Leq => FloatPredicate::OLE,
+                    Token::Geq => FloatPredicate::OGE,
+                    Token::Eqq => FloatPredicate::OEQ,
+                    Token::BangEq => FloatPredicate::UNE,
+                    _ => return Err(unsupported()),
+                };
+                Ok(self.builder.build_float_compare(predicate, lhs, rhs, "tmpcmp").into())
+            },
+            _ => Err(unsupported()),
+        }
+    }
+
+    pub fn translate_expr(&self, expr: &Box<Expr>) -> Result<BasicValueEnum<'ctx>, CompileError> {
         match expr.as_ref() {
-            Expr::Literal{ value: nb } => {
-                let f: f64 = nb.parse::<f64>().unwrap();
-                Ok(self.context.f64_type().const_float(f))
+            Expr::Literal { value, ty, span } => {
+                let invalid = || CompileError::new(format!("invalid literal `{}`", value), *span);
+                match checked(ty) {
+                    Type::Bool => Ok(self.context.bool_type().const_int((value == "true") as u64, false).into()),
+                    Type::F64 => {
+                        let f = float_literal(value).ok_or_else(invalid)?;
+                        Ok(self.context.f64_type().const_float(f).into())
+                    },
+                    ty => {
+                        let int_type = self.llvm_type(&ty).ok_or_else(invalid)?.into_int_type();
+                        let i = integer_literal(value, &ty).ok_or_else(invalid)?;
+                        Ok(int_type.const_int(i as u64, true).into())
+                    },
+                }
+            },
+            Expr::Str { value, .. } => Ok(self.string_literal(value).into()),
+            Expr::Array { elements, ty, .. } => {
+                let elem = checked(ty);
+                let array = self.new_array(&elem, elements.len() as u64);
+                let elem = self.llvm_type(&elem).unwrap();
+                for (i, element) in elements.iter().enumerate() {
+                    let value = self.translate_expr(element)?;
+                    let index = self.context.i64_type().const_int(i as u64, false);
+                    let slot = self.element_pointer(array, index, elem);
+                    self.builder.build_store(slot, value);
+                }
+                Ok(array)
+            },
+            Expr::Struct { name, fields } => {
+                let def = &self.structs[ident(name)?];
+                let object = self.new_struct(def);
+                for (field, value) in fields.iter() {
+                    let value = self.translate_expr(value)?;
+                    self.builder.build_store(self.field_pointer(object, def, ident(field)?), value);
+                }
+                Ok(object.into())
+            },
+            Expr::Get { object, name, ty } => {
+                let Type::Struct(struct_name) = checked(ty) else {
+                    unreachable!("the type checker only allows accessing fields of structs");
+                };
+                let object = self.translate_expr(object)?.into_pointer_value();
+                let field = self.field_pointer(object, &self.structs[&struct_name], ident(name)?);
+                Ok(self.builder.build_load(field, ident(name)?))
             },
-            Expr::Variable { name } => {
-                let Token::Ident(id) = name else {
-                    panic!("Not an ident");
+            Expr::Set { object, name, value, ty } => {
+                let Type::Struct(struct_name) = checked(ty) else {
+                    unreachable!("the type checker only allows accessing fields of structs");
                 };
-                match self.variables.get(id.as_str()) {
-                    Some(var) => Ok(self.builder.build_load(*var, id.as_str()).into_float_value()),
-                    None => Err("Could not find a matching variable"),
+                let object = self.translate_expr(object)?.into_pointer_value();
+                let field = self.field_pointer(object, &self.structs[&struct_name], ident(name)?);
+                let value = self.translate_expr(value)?;
+                self.builder.build_store(field, value);
+                Ok(value)
+            },
+            Expr::Index { target, index, ty, .. } => {
+                let array = self.translate_expr(target)?;
+                let index = self.translate_expr(index)?.into_int_value();
+                let slot = self.element_pointer(array, index, self.llvm_type(&checked(ty)).unwrap());
+                Ok(self.builder.build_load(slot, "elem"))
+            },
+            Expr::IndexAssign { target, index, value, ty, .. } => {
+                let array = self.translate_expr(target)?;
+                let index = self.translate_expr(index)?.into_int_value();
+                // The bounds check happens before the value is evaluated.
+                let slot = self.element_pointer(array, index, self.llvm_type(&checked(ty)).unwrap());
+                let value = self.translate_expr(value)?;
+                self.builder.build_store(slot, value);
+                Ok(value)
+            },
+            Expr::Variable { name, depth } => {
+                let id = ident(name)?;
+                match depth {
+                    Some(depth) => Ok(self.builder.build_load(self.variable(name, *depth)?, id)),
+                    None => match self.module.get_function(id) {
+                        Some(fun) if self.functions.contains_key(id) => Ok(self.function_closure(id, fun).into()),
+                        _ => Err(CompileError::new(format!("cannot find variable `{}` in this scope", id), name.span)),
+                    },
                 }
             },
+            Expr::Assign { name, value, depth } => {
+                let value = self.translate_expr(value)?;
+                let Some(depth) = depth else {
+                    return Err(CompileError::new(format!("cannot assign to function `{}`", ident(name)?), name.span));
+                };
+                self.builder.build_store(self.variable(name, *depth)?, value);
+                Ok(value)
+            },
             Expr::BinaryExpr {
                 op,
                 ref left,
                 ref right,
+                ..
             } => {
                     let lhs = self.translate_expr(left)?;
                     let rhs = self.translate_expr(right)?;
+                    self.translate_binary(op, lhs, rhs)
+            },
+            Expr::UnaryExpr { op, right } => {
+                let value = self.translate_expr(right)?;
+                match (&op.token, value) {
+                    (Token::Minus, BasicValueEnum::IntValue(value)) => Ok(self.builder.build_int_neg(value, "tmpneg").into()),
+                    (Token::Minus, BasicValueEnum::FloatValue(value)) => Ok(self.builder.build_float_neg(value, "tmpneg").into()),
+                    (Token::Bang, BasicValueEnum::IntValue(value)) => Ok(self.builder.build_not(value, "tmpnot").into()),
+                    _ => Err(CompileError::new("unsupported unary operation", op.span)),
+                }
+            },
+            Expr::Grouping { expr } => self.translate_expr(expr),
+            Expr::Logical { op, left, right } => self.translate_logical(op, left, right),
+            Expr::Call { callee, args, .. } => {
+                // Named functions are called directly, unless a variable
+                // shadows them.
+                let name = match callee.as_ref() {
+                    Expr::Variable { name, depth: None } => name,
+                    _ => return self.translate_closure_call(callee, args),
+                };
+                let fn_name = ident(name)?;
+                let Some(fun) = self.module.get_function(fn_name) else {
+                    return match Builtin::from_name(fn_name) {
+                        Some(builtin) => self.translate_builtin(builtin, args),
+                        None => Err(CompileError::new(format!("cannot find function `{}`", fn_name), name.span)),
+                    };
+                };
+                if fun.count_params() as usize != args.len() {
+                    return Err(CompileError::new(
+                        format!(
+                            "function `{}` takes {} arguments but {} were supplied",
+                            fn_name,
+                            fun.count_params(),
+                            args.len(),
+                        ),
+                        name.span,
+                    ));
+                }
 
-                    match op {
-                        Token::Plus => Ok(self.builder.build_float_add(lhs, rhs, "tmpadd")),
-                        Token::Minus => Ok(self.builder.build_float_sub(lhs, rhs, "tmpsub")),
-                        Token::Less => Ok({
-                            let cmp = self
-                                .builder
-                                .build_float_compare(FloatPredicate::ULT, lhs, rhs, "tmpcmp");
-
-                            self.builder
-                                .build_unsigned_int_to_float(cmp, self.context.f64_type(), "tmpbool")
-                        }),
-                        Token::Greater => Ok({
-                            let cmp = self
-                                .builder
-                                .build_float_compare(FloatPredicate::ULT, rhs, lhs, "tmpcmp");
-
-                            self.builder
-                                .build_unsigned_int_to_float(cmp, self.context.f64_type(), "tmpbool")
-                        }),
-                        _ => Err("unsupported binary operation"),
-                    }
+                let mut compiled_args: Vec<BasicMetadataValueEnum> = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    compiled_args.push(self.translate_expr(arg)?.into());
+                }
+                Ok(self.build_call(fun, &compiled_args))
+            },
+            Expr::Lambda { params, ret, body, captures, keyword } => {
+                let param_types = params.iter().map(|param| checked(&param.ty)).collect::<Vec<Type>>();
+                let ret = checked(ret);
+                let parent = self.fn_value_opt.unwrap().get_name().to_string_lossy().into_owned();
+                let function = self.module.add_function(
+                    &format!("{}.lambda", parent),
+                    self.closure_fn_type(&param_types, &ret),
+                    Some(Linkage::Internal),
+                );
+                let closure = self.new_closure(function, &param_types, &ret, captures);
+                self.pending.borrow_mut().push(PendingLambda {
+                    function,
+                    params: params.clone(),
+                    ret,
+                    body: body.clone(),
+                    captures: captures.clone(),
+                    span: keyword.span,
+                });
+                Ok(closure.into())
             },
-            _ => Err("unable to compile expression to LLVM")
         }
     }
 
+    /// Calls a closure indirectly through the function pointer it starts
+    /// with, passing the closure along so its captures can be read.
+    fn translate_closure_call(&self, callee: &Box<Expr>, args: &[Box<Expr>]) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        let closure = self.translate_expr(callee)?.into_pointer_value();
+        let mut compiled_args: Vec<BasicMetadataValueEnum> = Vec::with_capacity(args.len() + 1);
+        compiled_args.push(self.builder.build_pointer_cast(closure, self.pointer_type(), "env").into());
+        for arg in args.iter() {
+            compiled_args.push(self.translate_expr(arg)?.into());
+        }
+        let fn_ptr = self.builder.build_load(closure, "fnptr").into_pointer_value();
+        let fun = CallableValue::try_from(fn_ptr).expect("closures start with a function pointer");
+        Ok(self.build_call(fun, &compiled_args))
+    }
+
+    // Calls to `void` functions only appear as expression statements, where
+    // the value is discarded.
+    fn build_call(&self, fun: impl Into<CallableValue<'ctx>>, args: &[BasicMetadataValueEnum<'ctx>]) -> BasicValueEnum<'ctx> {
+        self.builder
+            .build_call(fun, args, "tmpcall")
+            .try_as_basic_value()
+            .left()
+            .unwrap_or_else(|| self.context.bool_type().const_zero().into())
+    }
+
     pub fn translate(
         context: &'ctx Context,
         builder: &'a Builder<'ctx>,
         pass_manager: &'a PassManager<FunctionValue<'ctx>>,
         module: &'a Module<'ctx>,
-        stmt: &Stmt,
-    ) -> Result<FunctionValue<'ctx>, &'static str> {
+        statements: &[Box<Stmt>],
+    ) -> Result<Vec<FunctionValue<'ctx>>, CompileError> {
+        let runtime = MemoryBuffer::create_from_memory_range_copy(RUNTIME_IR.as_bytes(), "runtime.ll");
+        let runtime = context
+            .create_module_from_ir(runtime)
+            .map_err(|err| CompileError::unspanned(err.to_string()))?;
+        module
+            .link_in_module(runtime)
+            .map_err(|err| CompileError::unspanned(err.to_string()))?;
+
         let mut tr = Translator {
             context,
             builder,
             fpm: pass_manager,
             module,
             fn_value_opt: None,
-            variables: HashMap::new(),
+            variables: vec![],
+            structs: structs(statements),
+            functions: HashMap::new(),
+            pending: RefCell::new(vec![]),
         };
 
-        tr.translate_function(stmt)
+        // Structs only exist in the type checker and the layout of memory.
+        let functions = statements
+            .iter()
+            .filter(|stmt| !matches!(stmt.as_ref(), Stmt::Struct { .. }))
+            .collect::<Vec<&Box<Stmt>>>();
+
+        // Declare every signature up front so functions can call each other
+        // regardless of the order they appear in the file.
+        for stmt in functions.iter() {
+            tr.translate_function_sig(stmt)?;
+            if let Stmt::Function { name, params, ret, .. } | Stmt::Extern { name, params, ret } = stmt.as_ref() {
+                let ty = Type::Function {
+                    params: params.iter().map(|param| checked(&param.ty)).collect(),
+                    ret: Box::new(checked(ret)),
+                };
+                tr.functions.insert(ident(name)?.to_string(), ty);
+            }
+        }
+        let mut translated = functions
+            .iter()
+            .map(|stmt| tr.translate_function(stmt))
+            .collect::<Result<Vec<FunctionValue>, CompileError>>()?;
+
+        // Lambdas are queued up as they are found, including ones nested in
+        // other lambdas, so keep going until none are left.
+        loop {
+            let Some(lambda) = tr.pending.borrow_mut().pop() else {
+                break;
+            };
+            translated.push(tr.translate_lambda(lambda)?);
+        }
+        Ok(translated)
     }
 }
 
+/// Compiles a translated module into a relocatable wasm32 object file, to be
+/// linked by `linker::link`.
+pub fn emit_wasm_object(module: &Module) -> Result<Vec<u8>, CompileError> {
+    Target::initialize_webassembly(&InitializationConfig::default());
+    let triple = TargetTriple::create("wasm32-unknown-unknown");
+    let target = Target::from_triple(&triple)
+        .map_err(|err| CompileError::unspanned(err.to_string()))?;
+    let machine: TargetMachine = target
+        .create_target_machine(
+            &triple,
+            "generic",
+            "",
+            OptimizationLevel::Default,
+            RelocMode::Default,
+            CodeModel::Default,
+        )
+        .ok_or_else(|| CompileError::unspanned("could not create a wasm32 target machine"))?;
+    module.set_triple(&triple);
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+    let buffer = machine
+        .write_to_memory_buffer(module, FileType::Object)
+        .map_err(|err| CompileError::unspanned(err.to_string()))?;
+    Ok(buffer.as_slice().to_vec())
+}
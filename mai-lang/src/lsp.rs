@@ -0,0 +1,414 @@
+use std::collections::{HashMap,HashSet};
+use std::io::{self,BufRead,Write};
+
+use serde_json::{json,Value};
+
+use crate::diagnostic::{CompileError,SourceMap};
+use crate::lexer::TokenLexer;
+use crate::parser::{Param,Stmt};
+use crate::resolver::{self,Reference};
+use crate::token::{Span,Token};
+use crate::types::Type;
+use crate::{modules,type_checker};
+
+/// The kinds of semantic tokens the server reports, indexed by the tokens it
+/// sends.
+const TOKEN_TYPES: [&str; 6] = ["keyword", "function", "variable", "string", "number", "operator"];
+
+/// Runs a language server speaking JSON-RPC over stdin and stdout, until the
+/// client asks it to exit.
+///
+/// Documents are checked whenever they are opened or saved, and go to
+/// definition, hovers and semantic tokens work on the latest text the client
+/// sent, even if it doesn't compile.
+pub fn run() -> eyre::Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut input)? {
+        if !server.handle(&message)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a message framed by a `Content-Length` header, or `None` once the
+/// input is closed.
+fn read_message(input: &mut impl BufRead) -> eyre::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>()?);
+        }
+    }
+    let Some(length) = length else {
+        eyre::bail!("message without a `Content-Length` header");
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn send(message: Value) -> eyre::Result<()> {
+    let body = message.to_string();
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// The text of every open document, keyed by URI.
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, String>,
+}
+
+impl Server {
+    /// Handles a request or notification, returning whether to keep going.
+    fn handle(&mut self, message: &Value) -> eyre::Result<bool> {
+        let params = &message["params"];
+        match (message["method"].as_str(), message.get("id")) {
+            (Some("exit"), _) => return Ok(false),
+            (Some(method), Some(id)) => match self.request(method, params) {
+                Ok(result) => send(json!({ "jsonrpc": "2.0", "id": id, "result": result }))?,
+                Err((code, message)) => send(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": code, "message": message },
+                }))?,
+            },
+            (Some(method), None) => self.notify(method, params)?,
+            // The server never sends requests, so there are no responses to
+            // handle.
+            (None, _) => {},
+        }
+        Ok(true)
+    }
+
+    fn request(&self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 1, "save": { "includeText": true } },
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": [] },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "mai" },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/definition" => {
+                let Some((analysis, offset)) = self.locate(params) else {
+                    return Ok(Value::Null);
+                };
+                match analysis.reference(offset) {
+                    Some(reference) => Ok(analysis.location(reference.declaration)),
+                    None => Ok(Value::Null),
+                }
+            },
+            "textDocument/hover" => {
+                let Some((analysis, offset)) = self.locate(params) else {
+                    return Ok(Value::Null);
+                };
+                Ok(analysis.hover(offset).unwrap_or(Value::Null))
+            },
+            "textDocument/semanticTokens/full" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let Some(text) = self.documents.get(uri) else {
+                    return Ok(Value::Null);
+                };
+                Ok(json!({ "data": Analysis::new(uri, text).semantic_tokens() }))
+            },
+            _ => Err((-32601, format!("unknown method `{}`", method))),
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: &Value) -> eyre::Result<()> {
+        let document = &params["textDocument"];
+        let uri = document["uri"].as_str().unwrap_or_default().to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = document["text"].as_str().unwrap_or_default().to_string();
+                self.publish_diagnostics(&uri, &text)?;
+                self.documents.insert(uri, text);
+            },
+            // Changes are synced in full, so the last one holds the whole text.
+            "textDocument/didChange" => {
+                if let Some(text) = params["contentChanges"].as_array().and_then(|changes| changes.last()) {
+                    let text = text["text"].as_str().unwrap_or_default().to_string();
+                    self.documents.insert(uri, text);
+                }
+            },
+            "textDocument/didSave" => {
+                if let Some(text) = params["text"].as_str() {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                if let Some(text) = self.documents.get(&uri) {
+                    self.publish_diagnostics(&uri, text)?;
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                send(json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                }))?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn publish_diagnostics(&self, uri: &str, text: &str) -> eyre::Result<()> {
+        let analysis = Analysis::new(uri, text);
+        let diagnostics = analysis.errors.iter().map(|err| analysis.diagnostic(err)).collect::<Vec<Value>>();
+        send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+
+    /// Analyzes the document a request points into, returning the offset it
+    /// points at.
+    fn locate(&self, params: &Value) -> Option<(Analysis, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let text = self.documents.get(uri)?;
+        let offset = offset(text, &params["position"])?;
+        Some((Analysis::new(uri, text), offset))
+    }
+}
+
+/// A document loaded along with its imports, resolved and type checked.
+struct Analysis {
+    files: SourceMap,
+    statements: Vec<Box<Stmt>>,
+    references: Vec<Reference>,
+    // Errors of the first pass that found any, as the later ones rely on it.
+    errors: Vec<CompileError>,
+}
+
+impl Analysis {
+    fn new(uri: &str, text: &str) -> Self {
+        let mut files = SourceMap::default();
+        files.add(uri_to_path(uri), text.to_string());
+        let (mut statements, mut errors) = modules::load(&mut files);
+        let (references, resolve_errors) = resolver::references(&mut statements);
+        // The type checker fills in the type of every signature before
+        // checking the bodies, so hovers show them even if it finds errors.
+        let type_errors = type_checker::check(&mut statements).err().unwrap_or_default();
+        if errors.is_empty() {
+            errors = resolve_errors;
+        }
+        if errors.is_empty() {
+            errors = type_errors;
+        }
+        Self { files, statements, references, errors }
+    }
+
+    fn reference(&self, offset: usize) -> Option<&Reference> {
+        self.references.iter().find(|reference| contains(reference.span, offset))
+    }
+
+    /// Shows the signature of the function declared or referred to at an
+    /// offset.
+    fn hover(&self, offset: usize) -> Option<Value> {
+        let (span, declaration) = match self.reference(offset) {
+            Some(reference) => (reference.span, reference.declaration),
+            None => {
+                let declaration = self.functions().find(|span| contains(*span, offset))?;
+                (declaration, declaration)
+            },
+        };
+        let signature = self.statements.iter().find_map(|stmt| match stmt.as_ref() {
+            Stmt::Function { name, params, ret, .. } if name.span == declaration => {
+                Some(signature("fun", &name.token, params, ret))
+            },
+            Stmt::Extern { name, params, ret } if name.span == declaration => {
+                Some(signature("extern fun", &name.token, params, ret))
+            },
+            _ => None,
+        })?;
+        Some(json!({
+            "contents": { "kind": "markdown", "value": format!("```mai\n{}\n```", signature) },
+            "range": self.range(span),
+        }))
+    }
+
+    /// Returns the names of every declared function.
+    fn functions(&self) -> impl Iterator<Item = Span> + '_ {
+        self.statements.iter().filter_map(|stmt| match stmt.as_ref() {
+            Stmt::Function { name, .. } | Stmt::Extern { name, .. } => Some(name.span),
+            _ => None,
+        })
+    }
+
+    /// Encodes the tokens of the document relative to the previous one.
+    /// Identifiers naming a function are told apart from variables through
+    /// the declarations they refer to.
+    fn semantic_tokens(&self) -> Vec<u32> {
+        let functions = self.functions().map(|span| span.offset).collect::<HashSet<usize>>();
+        let text = &self.files.files[0].input;
+        let (tokens, _) = TokenLexer::new(text).tokenize();
+        let mut data = vec![];
+        let (mut line, mut character) = (0, 0);
+        for tok in tokens.iter() {
+            let kind = match &tok.token {
+                Token::Ident(_) => {
+                    let declaration = self.references
+                        .iter()
+                        .find(|reference| reference.span == tok.span)
+                        .map_or(tok.span, |reference| reference.declaration);
+                    if functions.contains(&declaration.offset) { "function" } else { "variable" }
+                },
+                token => match token_type(token) {
+                    Some(kind) => kind,
+                    None => continue,
+                },
+            };
+            let (start_line, start_character) = position(text, tok.span.offset);
+            let len = text[tok.span.offset..tok.span.offset + tok.span.len].encode_utf16().count();
+            let delta = if start_line == line { start_character - character } else { start_character };
+            let kind = TOKEN_TYPES.iter().position(|ty| *ty == kind).unwrap();
+            data.extend([start_line - line, delta, len, kind, 0].map(|value| value as u32));
+            (line, character) = (start_line, start_character);
+        }
+        data
+    }
+
+    /// Returns the file and range a span points to.
+    fn location(&self, span: Span) -> Value {
+        json!({ "uri": path_to_uri(&self.files.file(Some(span)).path), "range": self.range(span) })
+    }
+
+    /// Returns the range a span covers within its file.
+    fn range(&self, span: Span) -> Value {
+        let file = self.files.file(Some(span));
+        let start = span.offset - file.start;
+        let (start_line, start_character) = position(&file.input, start);
+        let (end_line, end_character) = position(&file.input, start + span.len);
+        json!({
+            "start": { "line": start_line, "character": start_character },
+            "end": { "line": end_line, "character": end_character },
+        })
+    }
+
+    /// Converts an error into an LSP diagnostic on the document. Errors in
+    /// imported files are shown at the top, prefixed by where they are.
+    fn diagnostic(&self, err: &CompileError) -> Value {
+        let start = json!({ "line": 0, "character": 0 });
+        let (range, message) = match err.span {
+            Some(span) if self.files.file(Some(span)).start == 0 => (self.range(span), err.message.clone()),
+            Some(span) => {
+                let file = self.files.file(Some(span));
+                let message = format!("{}:{}:{}: {}", file.path, span.line, span.column, err.message);
+                (json!({ "start": start, "end": start }), message)
+            },
+            None => (json!({ "start": start, "end": start }), err.message.clone()),
+        };
+        json!({ "range": range, "severity": 1, "source": "mai", "message": message })
+    }
+}
+
+fn signature(keyword: &str, name: &Token, params: &[Param], ret: &Option<Type>) -> String {
+    let params = params
+        .iter()
+        .map(|param| match &param.ty {
+            Some(ty) => format!("{}: {}", param.name.token, ty),
+            None => param.name.token.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    match ret {
+        Some(Type::Void) | None => format!("{} {}({})", keyword, name, params),
+        Some(ret) => format!("{} {}({}) -> {}", keyword, name, params, ret),
+    }
+}
+
+fn token_type(token: &Token) -> Option<&'static str> {
+    match token {
+        Token::If | Token::While | Token::For | Token::Fun | Token::Return | Token::Export | Token::Extern
+        | Token::Struct | Token::Import | Token::Or | Token::And | Token::Else | Token::True | Token::False
+        | Token::Var | Token::Wagmi => Some("keyword"),
+        Token::Str(_) => Some("string"),
+        Token::Number(_) => Some("number"),
+        Token::Eq | Token::Eqq | Token::BangEq | Token::Greater | Token::Less | Token::Geq | Token::Leq
+        | Token::Plus | Token::Minus | Token::Times | Token::Div | Token::Bang | Token::Arrow => Some("operator"),
+        _ => None,
+    }
+}
+
+// Includes the end of the span, where the cursor is right after a name.
+fn contains(span: Span, offset: usize) -> bool {
+    span.offset <= offset && offset <= span.offset + span.len
+}
+
+/// Converts a byte offset into a file to the line and character of an LSP
+/// position, which counts UTF-16 code units.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (before.matches('\n').count(), before[line_start..].encode_utf16().count())
+}
+
+/// Converts an LSP position back into a byte offset.
+fn offset(text: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let line_start = match line {
+        0 => 0,
+        _ => text.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+    let mut units = 0;
+    for (index, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + index);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri).as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < path.len() {
+        let escaped = path.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (path[i], escaped.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn path_to_uri(path: &str) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
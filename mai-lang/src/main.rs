@@ -1,58 +1,288 @@
-use std::path::PathBuf;
+use std::path::{Path,PathBuf};
 use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
-use std::process::{Command,Stdio};
-use execute::Execute;
+use std::str::FromStr;
 
 use structopt::StructOpt;
+use structopt::clap::AppSettings;
 
+#[cfg(feature = "llvm")]
 use inkwell::context::Context;
+#[cfg(feature = "llvm")]
+use inkwell::module::Module;
+#[cfg(feature = "llvm")]
 use inkwell::passes::PassManager;
+#[cfg(feature = "llvm")]
+use inkwell::values::FunctionValue;
 
 mod token;
 mod lexer;
 mod parser;
+mod diagnostic;
+mod types;
+mod builtins;
+mod resolver;
+mod type_checker;
+#[cfg(feature = "llvm")]
 mod llvm_translator;
+#[cfg(feature = "llvm")]
+mod linker;
+mod wasm_translator;
+mod interp;
+mod runtime;
+mod modules;
+#[cfg(feature = "llvm")]
+mod repl;
+mod lsp;
+mod formatter;
+mod optimizer;
 
+use diagnostic::{CompileError,Diagnostic,SourceMap};
+use interp::{Interpreter,Value};
+#[cfg(feature = "llvm")]
 use llvm_translator::Translator;
-use parser::Parser;
+use parser::{Parser,Stmt};
+use runtime::Host;
 use lexer::TokenLexer;
-use token::Token;
+use types::{checked,Type};
+
+/// How a program is executed once it has been type checked.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Backend {
+    /// Compile to wasm through LLVM and run it with wasmer.
+    #[cfg(feature = "llvm")]
+    Llvm,
+    /// Compile to wasm straight from the AST and run it with wasmer, for
+    /// programs that don't allocate.
+    Wasm,
+    /// Walk the AST directly, needing no external toolchain.
+    Interp,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            #[cfg(feature = "llvm")]
+            "llvm" => Ok(Backend::Llvm),
+            #[cfg(not(feature = "llvm"))]
+            "llvm" => Err("mai was built without the `llvm` feature".to_string()),
+            "wasm" => Ok(Backend::Wasm),
+            "interp" => Ok(Backend::Interp),
+            _ => Err(format!("unknown backend `{}`, expected `llvm`, `wasm` or `interp`", s)),
+        }
+    }
+}
+
+/// The backend used unless another is given with `--backend`.
+#[cfg(feature = "llvm")]
+const DEFAULT_BACKEND: &str = "llvm";
+#[cfg(not(feature = "llvm"))]
+const DEFAULT_BACKEND: &str = "wasm";
+
+/// The levels of `optimizer::optimize`, given with `-O`.
+const OPT_LEVELS: &[&str] = &["0", "1", "2"];
 
 #[derive(StructOpt,Debug)]
 #[structopt(name = "mai")]
-struct Opts {
-    #[structopt(short,long,default_value="main.mai")]
-    input: PathBuf,
+enum Opts {
+    /// Prints the tokens of a source file, one per line.
+    Lex {
+        input: PathBuf,
+    },
+    /// Prints the syntax tree of a source file.
+    Parse {
+        input: PathBuf,
+        /// Print the tree as JSON instead of debug output.
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Prints the LLVM IR generated for a source file.
+    #[cfg(feature = "llvm")]
+    Ir {
+        input: PathBuf,
+        /// Write the IR to this path instead of stdout.
+        #[structopt(short,long)]
+        output: Option<PathBuf>,
+        /// How much to optimize the program before translating it.
+        #[structopt(short = "O",default_value = "0",possible_values = OPT_LEVELS)]
+        opt_level: u8,
+    },
+    /// Compiles a source file to a wasm module.
+    Build {
+        input: PathBuf,
+        /// Where to write the module, next to the input by default.
+        #[structopt(short,long)]
+        output: Option<PathBuf>,
+        /// Write the text format instead of the binary format.
+        #[structopt(long)]
+        wat: bool,
+        #[structopt(short,long,default_value=DEFAULT_BACKEND)]
+        backend: Backend,
+        /// How much to optimize the program before translating it.
+        #[structopt(short = "O",default_value = "0",possible_values = OPT_LEVELS)]
+        opt_level: u8,
+    },
+    /// Runs a function of a source file and prints its result.
+    #[structopt(setting = AppSettings::AllowNegativeNumbers)]
+    Run {
+        input: PathBuf,
+        /// Name of the function to call.
+        #[structopt(long,default_value="main")]
+        entry: String,
+        /// Arguments to pass, parsed according to the parameter types.
+        #[structopt(long)]
+        args: Vec<String>,
+        #[structopt(short,long,default_value=DEFAULT_BACKEND)]
+        backend: Backend,
+        /// How much to optimize the program before running it.
+        #[structopt(short = "O",default_value = "0",possible_values = OPT_LEVELS)]
+        opt_level: u8,
+    },
+    /// Formats a source file in place.
+    Fmt {
+        input: PathBuf,
+        /// Only check the file is formatted, failing if it isn't.
+        #[structopt(long)]
+        check: bool,
+    },
+    /// Starts an interactive session, compiling each input to native code.
+    #[cfg(feature = "llvm")]
+    Repl,
+    /// Runs a language server over stdin and stdout, for editors.
+    Lsp,
 }
 
-fn main() -> eyre::Result<()> {
-    let opts = Opts::from_args();
-    println!("Input file path: {:?}", opts.input);
+/// Prints a rendered diagnostic to stderr and exits.
+fn report(files: &SourceMap, err: &dyn Diagnostic) -> ! {
+    report_all(files, &[err]);
+    unreachable!();
+}
+
+/// Prints every diagnostic to stderr, exiting if there were any.
+fn report_all(files: &SourceMap, errors: &[&dyn Diagnostic]) {
+    if errors.is_empty() {
+        return;
+    }
+    for err in errors.iter() {
+        eprintln!("{}", err.render(files));
+    }
+    let path = &files.files[0].path;
+    match errors.len() {
+        1 => eprintln!("error: could not compile `{}` due to previous error", path),
+        n => eprintln!("error: could not compile `{}` due to {} previous errors", path, n),
+    }
+    std::process::exit(1);
+}
 
-    let input = fs::read_to_string(opts.input).unwrap();
-    println!("Raw input contents:");
-    println!("{:?}", input);
-    println!("");
+/// A source file read from disk, along with any files it imports once they
+/// have been loaded, for rendering diagnostics.
+struct Source {
+    files: SourceMap,
+}
 
-    let lexer_res = TokenLexer::new(input.as_str()).collect::<Vec<Token>>();
-    println!("Lexed tokens:");
-    println!("{:?}", lexer_res);
-    println!("");
+impl Source {
+    fn read(path: &Path) -> eyre::Result<Self> {
+        let input = fs::read_to_string(path)?;
+        let mut files = SourceMap::default();
+        files.add(path.display().to_string(), input);
+        Ok(Self { files })
+    }
 
+    fn input(&self) -> &str {
+        &self.files.files[0].input
+    }
 
-    let parsed_statements = Parser::new(lexer_res).parse();
-    println!("Parsed expression:");
-    println!("{:?}", parsed_statements);
-    println!("");
+    /// Parses the source file alone, reporting every lexing and parsing error.
+    fn parse(&self) -> Vec<Box<Stmt>> {
+        let (tokens, lex_errors) = TokenLexer::new(self.input()).tokenize();
+        let (statements, parse_errors) = Parser::new(tokens).parse();
+        let errors = lex_errors
+            .iter()
+            .map(|err| err as &dyn Diagnostic)
+            .chain(parse_errors.iter().map(|err| err as &dyn Diagnostic))
+            .collect::<Vec<&dyn Diagnostic>>();
+        report_all(&self.files, &errors);
+        statements
+    }
 
-    let context = Context::create();
-    let module = context.create_module("tmp");
-    let builder = context.create_builder();
+    /// Loads the source along with every file it imports, then resolves and
+    /// type checks them, reporting any error.
+    fn check(&mut self) -> Vec<Box<Stmt>> {
+        let (mut statements, mut errors) = modules::load(&mut self.files);
+        // Each pass relies on the previous one, so only carry on while there
+        // are no errors to report.
+        if errors.is_empty() {
+            errors = resolver::resolve(&mut statements).err().unwrap_or_default();
+        }
+        if errors.is_empty() {
+            errors = type_checker::check(&mut statements).err().unwrap_or_default();
+        }
+        let errors = errors
+            .iter()
+            .map(|err| err as &dyn Diagnostic)
+            .collect::<Vec<&dyn Diagnostic>>();
+        report_all(&self.files, &errors);
+        statements
+    }
+
+    /// Translates type checked statements into an LLVM module.
+    #[cfg(feature = "llvm")]
+    fn translate<'ctx>(&self, context: &'ctx Context, statements: &[Box<Stmt>]) -> Module<'ctx> {
+        let module = context.create_module("tmp");
+        let builder = context.create_builder();
+        let fpm = function_passes(&module);
+        if let Err(err) = Translator::translate(
+            context,
+            &builder,
+            &fpm,
+            &module,
+            statements,
+        ) {
+            report(&self.files, &err);
+        }
+        module
+    }
+
+    /// Compiles type checked statements into a wasm module with the given
+    /// backend, exporting the functions marked with `export` along with any
+    /// extra ones given.
+    fn compile(&self, statements: &[Box<Stmt>], extra_exports: &[String], backend: Backend) -> eyre::Result<Vec<u8>> {
+        let mut exports = statements
+            .iter()
+            .filter_map(|stmt| match stmt.as_ref() {
+                Stmt::Function { name, exported: true, .. } => Some(name.token.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+        for name in extra_exports.iter() {
+            if !exports.contains(name) {
+                exports.push(name.clone());
+            }
+        }
+        match backend {
+            #[cfg(feature = "llvm")]
+            Backend::Llvm => {
+                let context = Context::create();
+                let module = self.translate(&context, statements);
+                let object = match llvm_translator::emit_wasm_object(&module) {
+                    Ok(object) => object,
+                    Err(err) => report(&self.files, &err),
+                };
+                Ok(linker::link(&object, &exports)?)
+            },
+            Backend::Wasm => match wasm_translator::translate(statements, &exports) {
+                Ok(wasm) => Ok(wasm),
+                Err(err) => report(&self.files, &err),
+            },
+            Backend::Interp => eyre::bail!("the interpreter can't compile programs, use `--backend wasm` instead"),
+        }
+    }
+}
 
-    // Pass manager for functions.
-    let fpm = PassManager::create(&module);
+/// Creates the pass manager run on every translated function.
+#[cfg(feature = "llvm")]
+fn function_passes<'ctx>(module: &Module<'ctx>) -> PassManager<FunctionValue<'ctx>> {
+    let fpm = PassManager::create(module);
 
     fpm.add_instruction_combining_pass();
     fpm.add_reassociate_pass();
@@ -64,84 +294,165 @@ fn main() -> eyre::Result<()> {
     fpm.add_reassociate_pass();
 
     fpm.initialize();
+    fpm
+}
 
-    // TODO: Translate all statements into LLVM IR.
-    let first_stmt = parsed_statements.first().unwrap();
-    let translated = Translator::translate(
-        &context, 
-        &builder, 
-        &fpm, 
-        &module, 
-        &first_stmt,
-    ).unwrap();
-    let result = translated
-        .to_string()
-        .replace("\"", "")
-        .replace("\\n", "\n");
-
-    // Write an IR file to the temporary dir.
-    let mut file = File::create("/tmp/main.ll")?;
-    file.write_all(result.into_bytes().as_slice())?;
-
-    // Execute LLC to translate into an object file targeted at the 
-    // wasm32-unknown-unknown triple.
-    // TODO: Use llvm-sys to programmatically perform the following actions rather than
-    // hardcoding llvm 15 toolchain commands.
-    let mut command = Command::new("llc-15");
-    command.arg("-march=wasm32");
-    command.arg("-filetype=obj");
-    command.arg("/tmp/main.ll");
-    command.arg("-o=/tmp/main.o");
-
-    let Some(0) = command.execute().unwrap() else {
-        panic!("Could not compile bitcode");
-    };
-
-    // Execute wasm-ld to translate the bitcode into web assembly.
-    let mut command = Command::new("wasm-ld-15");
-    command.arg("/tmp/main.o");
-    command.arg("-o");
-    command.arg("/tmp/main.wasm");
-    command.arg("--no-entry");
-    // TODO: Do not export all, as it is dangerous.
-    command.arg("--export-all");
-
-    let Some(0) = command.execute().unwrap() else {
-        panic!("Could not compile wasm binary");
-    };
-
-    let mut command = Command::new("wasm2wat");
-    command.arg("/tmp/main.wasm");
-
-    command.stdout(Stdio::piped());
-    command.stderr(Stdio::piped());
+/// Finds the parameter and return types of a type checked function.
+fn signature(statements: &[Box<Stmt>], entry: &str) -> Option<(Vec<Type>, Type)> {
+    statements.iter().find_map(|stmt| match stmt.as_ref() {
+        Stmt::Function { name, params, ret, .. } if name.token.to_string() == entry => {
+            let params = params.iter().map(|param| checked(&param.ty)).collect();
+            Some((params, checked(ret)))
+        },
+        _ => None,
+    })
+}
 
-    let output = command.execute_output()?;
-    let Some(0) = output.status.code() else {
-        panic!("Could not show wat for compiled wasm");
+fn run(source: &mut Source, entry: &str, args: &[String], backend: Backend, opt_level: u8) -> eyre::Result<Value> {
+    let mut statements = source.check();
+    optimizer::optimize(&mut statements, opt_level);
+    let Some((params, ret)) = signature(&statements, entry) else {
+        report(&source.files, &CompileError::unspanned(format!("cannot find function `{}`", entry)));
     };
+    if params.len() != args.len() {
+        eyre::bail!("`{}` takes {} arguments but {} were supplied", entry, params.len(), args.len());
+    }
+    let mut values = vec![];
+    for (ty, arg) in params.iter().zip(args.iter()) {
+        match Value::parse(ty, arg) {
+            Some(value) => values.push(value),
+            None => eyre::bail!("invalid argument `{}`, expected a value of type `{}`", arg, ty),
+        }
+    }
 
-    let wat_output = String::from_utf8(output.stdout)?;
-    println!("Compiled wasm to wat:");
-    println!("{}", wat_output);
+    // `mai run` interprets programs on a thread from `interp::with_stack`.
+    if backend == Backend::Interp {
+        return match Interpreter::new(&statements).with_stack_size(interp::STACK_SIZE).call(entry, values) {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                eprintln!("{}", err.render(&source.files));
+                std::process::exit(1);
+            },
+        };
+    }
 
-    let mut file = File::create("/tmp/main.wat")?;
-    file.write_all(wat_output.clone().into_bytes().as_slice())?;
-
-    // Running the web assembly module with wasmer;
+    let wasm = source.compile(&statements, &[entry.to_string()], backend)?;
     let mut store = wasmer::Store::default();
-    let module = wasmer::Module::new(&store, &wat_output)?;
-    // The module doesn't import anything, so we create an empty import object.
-    let import_object = wasmer::imports! {};
+    let module = wasmer::Module::new(&store, &wasm)?;
+    let env = wasmer::FunctionEnv::new(&mut store, Host::new());
+    let import_object = match runtime::imports(&mut store, &env, &statements) {
+        Ok(import_object) => import_object,
+        Err(err) => report(&source.files, &err),
+    };
     let instance = wasmer::Instance::new(&mut store, &module, &import_object)?;
+    let memory = instance.exports.get_memory("memory")?.clone();
+    env.as_mut(&mut store).memory = Some(memory.clone());
+    let function = instance.exports.get_function(entry)?;
+    let mut args = vec![];
+    for value in values.iter() {
+        match runtime::to_wasm(value) {
+            Some(arg) => args.push(arg),
+            None => eyre::bail!("string, array and struct arguments can only be passed with `--backend interp`"),
+        }
+    }
+    let results = function.call(&mut store, &args)?;
+    let value = match (&ret, results.first()) {
+        (Type::Void, _) => Some(Value::Void),
+        (ty, Some(result)) => runtime::from_wasm(ty, result, &memory.view(&store), &types::structs(&statements)),
+        (_, None) => None,
+    };
+    value.ok_or_else(|| eyre::eyre!("expected `{}` to return `{}`, got {:?}", entry, ret, results))
+}
 
-    let safe_sub = instance.exports.get_function("safe_sub")?;
-    println!("Trying inputs 3.0 and 4.0 into safe_sub");
-    let result = safe_sub.call(&mut store, &[wasmer::Value::F64(3.0), wasmer::Value::F64(4.0)])?;
-    println!("{:?}", result[0]);
-    println!("Trying inputs 3.0 and 1.0 into safe_sub");
-    let result = safe_sub.call(&mut store, &[wasmer::Value::F64(3.0), wasmer::Value::F64(1.0)])?;
-    println!("{:?}", result[0]);
+fn main() -> eyre::Result<()> {
+    match Opts::from_args() {
+        Opts::Lex { input } => {
+            let source = Source::read(&input)?;
+            let (tokens, errors) = TokenLexer::new(source.input()).tokenize();
+            for tok in tokens.iter() {
+                println!("{}:{}\t{:?}", tok.span.line, tok.span.column, tok.token);
+            }
+            let errors = errors
+                .iter()
+                .map(|err| err as &dyn Diagnostic)
+                .collect::<Vec<&dyn Diagnostic>>();
+            report_all(&source.files, &errors);
+        },
+        Opts::Parse { input, json } => {
+            let source = Source::read(&input)?;
+            let statements = source.parse();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&statements)?);
+            } else {
+                println!("{:#?}", statements);
+            }
+        },
+        #[cfg(feature = "llvm")]
+        Opts::Ir { input, output, opt_level } => {
+            let mut source = Source::read(&input)?;
+            let mut statements = source.check();
+            optimizer::optimize(&mut statements, opt_level);
+            let context = Context::create();
+            let module = source.translate(&context, &statements);
+            let ir = module.print_to_string().to_string();
+            match output {
+                Some(output) => fs::write(output, ir)?,
+                None => print!("{}", ir),
+            }
+        },
+        Opts::Build { input, output, wat, backend, opt_level } => {
+            let mut source = Source::read(&input)?;
+            let mut statements = source.check();
+            optimizer::optimize(&mut statements, opt_level);
+            let wasm = source.compile(&statements, &[], backend)?;
+            if wat {
+                let wat = wasmprinter::print_bytes(&wasm).map_err(|err| eyre::eyre!("{}", err))?;
+                fs::write(output.unwrap_or_else(|| input.with_extension("wat")), wat)?;
+            } else {
+                fs::write(output.unwrap_or_else(|| input.with_extension("wasm")), wasm)?;
+            }
+        },
+        Opts::Run { input, entry, args, backend, opt_level } => {
+            let command = move || -> eyre::Result<()> {
+                let mut source = Source::read(&input)?;
+                match run(&mut source, &entry, &args, backend, opt_level)? {
+                    Value::Void => (),
+                    value => println!("{}", value),
+                }
+                Ok(())
+            };
+            // The interpreter recurses on the host stack, so it runs on a
+            // thread with a deeper one than the main thread has.
+            match backend {
+                Backend::Interp => interp::with_stack(command)?,
+                _ => command()?,
+            }
+        },
+        Opts::Fmt { input, check } => {
+            let source = Source::read(&input)?;
+            let formatted = match formatter::format(source.input()) {
+                Ok(formatted) => formatted,
+                Err(errors) => {
+                    let errors = errors
+                        .iter()
+                        .map(|err| err as &dyn Diagnostic)
+                        .collect::<Vec<&dyn Diagnostic>>();
+                    report_all(&source.files, &errors);
+                    unreachable!();
+                },
+            };
+            if formatted == source.input() {
+                return Ok(());
+            }
+            if check {
+                eprintln!("error: `{}` is not formatted", input.display());
+                std::process::exit(1);
+            }
+            fs::write(&input, formatted)?;
+        },
+        #[cfg(feature = "llvm")]
+        Opts::Repl => repl::run()?,
+        Opts::Lsp => lsp::run()?,
+    }
     Ok(())
 }
-
@@ -0,0 +1,424 @@
+use std::collections::{HashMap,HashSet};
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use crate::diagnostic::{CompileError,SourceMap};
+use crate::lexer::TokenLexer;
+use crate::parser::*;
+use crate::token::{Span,SpannedToken,Token};
+use crate::types::Type;
+
+/// Loads the first file of `files` along with every file it imports,
+/// directly or not, adding them to `files` and returning their declarations
+/// merged into a single program.
+///
+/// A file such as `math.mai` is imported as the module `math`, so the
+/// importing file refers to its declarations as `math.square`. They keep
+/// that name in the merged program, which is how declarations of different
+/// files with the same name are told apart. Each file is only loaded once,
+/// however many files import it, and only the first file's functions are
+/// exported from the compiled wasm module.
+pub fn load(files: &mut SourceMap) -> (Vec<Box<Stmt>>, Vec<CompileError>) {
+    let path = PathBuf::from(&files.files[0].path);
+    let mut loader = Loader {
+        files,
+        modules: HashMap::new(),
+        loading: vec![],
+        prefixes: HashSet::new(),
+        externs: HashMap::new(),
+        statements: vec![],
+        errors: vec![],
+    };
+    loader.load_file(&path, 0, None);
+    (loader.statements, loader.errors)
+}
+
+/// The names a module declares, mapped to the names they have in the merged
+/// program.
+#[derive(Debug,Clone,Default)]
+struct Module {
+    // Functions and externs.
+    values: HashMap<String, String>,
+    structs: HashMap<String, String>,
+}
+
+impl Module {
+    fn new(statements: &[Box<Stmt>], prefix: Option<&str>) -> Self {
+        let mangle = |name: &SpannedToken| match prefix {
+            Some(prefix) => format!("{}.{}", prefix, name.token),
+            None => name.token.to_string(),
+        };
+        let mut module = Module::default();
+        for stmt in statements.iter() {
+            match stmt.as_ref() {
+                Stmt::Function { name, .. } => {
+                    module.values.insert(name.token.to_string(), mangle(name));
+                },
+                // Externs are imported from the host by name, so they are
+                // never renamed.
+                Stmt::Extern { name, .. } => {
+                    module.values.insert(name.token.to_string(), name.token.to_string());
+                },
+                Stmt::Struct { name, .. } => {
+                    module.structs.insert(name.token.to_string(), mangle(name));
+                },
+                _ => {},
+            }
+        }
+        module
+    }
+}
+
+struct Loader<'a> {
+    files: &'a mut SourceMap,
+    // Every module loaded so far, by the canonical path of its file.
+    modules: HashMap<PathBuf, Module>,
+    // Files currently being loaded, each imported by the one before it.
+    loading: Vec<PathBuf>,
+    prefixes: HashSet<String>,
+    // Signatures of the externs declared so far, so several modules can
+    // declare the same host function.
+    externs: HashMap<String, (Vec<Option<Type>>, Option<Type>)>,
+    statements: Vec<Box<Stmt>>,
+    errors: Vec<CompileError>,
+}
+
+impl<'a> Loader<'a> {
+    // Parses a file that has been added to the source map, loads its imports
+    // and adds its renamed declarations to the program.
+    fn load_file(&mut self, path: &Path, index: usize, prefix: Option<String>) -> Module {
+        let file = &self.files.files[index];
+        let (tokens, lex_errors) = TokenLexer::starting_at(&file.input, file.start).tokenize();
+        let (statements, parse_errors) = Parser::new(tokens).parse();
+        self.errors.extend(lex_errors.into_iter().map(CompileError::from));
+        self.errors.extend(parse_errors.into_iter().map(CompileError::from));
+
+        self.loading.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut namespaces = HashMap::new();
+        let mut declarations = vec![];
+        for stmt in statements.into_iter() {
+            let Stmt::Import { path: import, .. } = stmt.as_ref() else {
+                declarations.push(stmt);
+                continue;
+            };
+            match self.import(dir, import) {
+                Ok((namespace, module)) => {
+                    if namespaces.insert(namespace.clone(), module).is_some() {
+                        self.errors.push(CompileError::new(
+                            format!("the module `{}` is imported multiple times", namespace),
+                            import.span,
+                        ));
+                    }
+                },
+                Err(err) => self.errors.push(err),
+            }
+        }
+        self.loading.pop();
+
+        let module = Module::new(&declarations, prefix.as_deref());
+        let mut renamer = Renamer { module: &module, namespaces: &namespaces, scopes: vec![], errors: vec![] };
+        for stmt in declarations.iter_mut() {
+            renamer.declaration(stmt, prefix.is_some());
+        }
+        self.errors.append(&mut renamer.errors);
+
+        for stmt in declarations.into_iter() {
+            if let Stmt::Extern { name, params, ret } = stmt.as_ref() {
+                let sig = (params.iter().map(|param| param.ty.clone()).collect(), ret.clone());
+                // Identical externs are merged, while conflicting ones are
+                // left for the type checker to report.
+                if self.externs.get(&name.token.to_string()) == Some(&sig) {
+                    continue;
+                }
+                self.externs.insert(name.token.to_string(), sig);
+            }
+            self.statements.push(stmt);
+        }
+        module
+    }
+
+    // Loads the file imported by an `import` statement, relative to the
+    // directory of the importing file, returning the name it is imported as.
+    fn import(&mut self, dir: &Path, import: &SpannedToken) -> Result<(String, Module), CompileError> {
+        let Token::Str(relative) = &import.token else {
+            unreachable!("the parser only accepts string literals as import paths");
+        };
+        let path = dir.join(relative);
+        let namespace = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        // The module is referred to by name, so it has to lex as one.
+        let is_identifier = matches!(
+            TokenLexer::new(&namespace).tokenize().0.as_slice(),
+            [SpannedToken { token: Token::Ident(name), .. }, _] if *name == namespace,
+        );
+        if !is_identifier {
+            return Err(CompileError::new(
+                format!("cannot import `{}`, as `{}` is not a valid module name", relative, namespace),
+                import.span,
+            ));
+        }
+
+        let unreadable = |err: std::io::Error| {
+            CompileError::new(format!("cannot read `{}`: {}", path.display(), err), import.span)
+        };
+        let canonical = fs::canonicalize(&path).map_err(unreadable)?;
+        if let Some(start) = self.loading.iter().position(|loading| *loading == canonical) {
+            let cycle = self.loading[start..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|path| path.file_name().unwrap_or_default().to_string_lossy().into_owned())
+                .collect::<Vec<String>>();
+            return Err(CompileError::new(format!("import cycle: {}", cycle.join(" -> ")), import.span));
+        }
+        if let Some(module) = self.modules.get(&canonical) {
+            return Ok((namespace, module.clone()));
+        }
+
+        let input = fs::read_to_string(&path).map_err(unreadable)?;
+        self.files.add(path.display().to_string(), input);
+        let index = self.files.files.len() - 1;
+
+        // Files in different directories may share a name, so only the
+        // first one loaded is prefixed with the name alone.
+        let mut prefix = namespace.clone();
+        let mut n = 2;
+        while !self.prefixes.insert(prefix.clone()) {
+            prefix = format!("{}{}", namespace, n);
+            n += 1;
+        }
+        let module = self.load_file(&path, index, Some(prefix));
+        self.modules.insert(canonical, module.clone());
+        Ok((namespace, module))
+    }
+}
+
+/// Renames the declarations of a module and every reference to them, as
+/// well as references to the modules it imports.
+struct Renamer<'a> {
+    module: &'a Module,
+    namespaces: &'a HashMap<String, Module>,
+    // Local variables, which shadow declarations and modules of the same name.
+    scopes: Vec<HashSet<String>>,
+    errors: Vec<CompileError>,
+}
+
+impl<'a> Renamer<'a> {
+    fn declaration(&mut self, stmt: &mut Stmt, imported: bool) {
+        match stmt {
+            Stmt::Function { name, params, ret, body, exported } => {
+                self.rename(name, &self.module.values);
+                *exported &= !imported;
+                self.scopes.clear();
+                self.function(params, ret, name.span, body);
+            },
+            Stmt::Extern { name, params, ret } => {
+                for param in params.iter_mut() {
+                    self.ty(&mut param.ty, param.name.span);
+                }
+                self.ty(ret, name.span);
+            },
+            Stmt::Struct { name, fields } => {
+                self.rename(name, &self.module.structs);
+                for field in fields.iter_mut() {
+                    self.ty(&mut field.ty, field.name.span);
+                }
+            },
+            // Anything else is reported by the type checker, and is renamed
+            // in a scope of its own until then.
+            stmt => {
+                self.scopes = vec![HashSet::new()];
+                self.stmt(stmt);
+            },
+        }
+    }
+
+    // Renames the types of a function and the references in its body, with
+    // its parameters in scope. Lambdas can also refer to the variables of the
+    // functions around them.
+    fn function(&mut self, params: &mut [Param], ret: &mut Option<Type>, span: Span, body: &mut [Box<Stmt>]) {
+        let mut scope = HashSet::new();
+        for param in params.iter_mut() {
+            self.ty(&mut param.ty, param.name.span);
+            scope.insert(param.name.token.to_string());
+        }
+        self.ty(ret, span);
+        self.scopes.push(scope);
+        self.block(body);
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, statements: &mut [Box<Stmt>]) {
+        self.scopes.push(HashSet::new());
+        for stmt in statements.iter_mut() {
+            self.stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::Expr(expr) => self.expr(expr),
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            },
+            Stmt::If { cond, then_branch, else_branch } => {
+                self.expr(cond);
+                self.block(std::slice::from_mut(then_branch));
+                if let Some(else_branch) = else_branch {
+                    self.block(std::slice::from_mut(else_branch));
+                }
+            },
+            Stmt::While { condition, body } => {
+                self.expr(condition);
+                self.block(std::slice::from_mut(body));
+            },
+            Stmt::Var { name, ty, initializer } => {
+                self.ty(ty, name.span);
+                if let Some(initializer) = initializer {
+                    self.expr(initializer);
+                }
+                self.scopes.last_mut().unwrap().insert(name.token.to_string());
+            },
+            // Nested declarations and imports are reported by the type checker.
+            Stmt::Function { .. } | Stmt::Extern { .. } | Stmt::Struct { .. } | Stmt::Import { .. } => {},
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Variable { name, .. } => {
+                if !self.is_local(name) {
+                    self.rename(name, &self.module.values);
+                }
+            },
+            Expr::Get { object, name, .. } => {
+                // `math.square` refers to a declaration of an imported module,
+                // unless `math` is a local variable.
+                let module = match object.as_ref() {
+                    Expr::Variable { name: namespace, .. } => self.namespace(namespace).map(|module| (namespace, module)),
+                    _ => None,
+                };
+                let Some((namespace, module)) = module else {
+                    self.expr(object);
+                    return;
+                };
+                let mut name = qualified(namespace, name);
+                self.resolve(&mut name, &module.values);
+                *expr = Expr::Variable { name, depth: None };
+            },
+            Expr::Struct { name, fields } => {
+                self.struct_name(name);
+                for (_, value) in fields.iter_mut() {
+                    self.expr(value);
+                }
+            },
+            Expr::Lambda { params, ret, body, keyword, .. } => self.function(params, ret, keyword.span, body),
+            Expr::BinaryExpr { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            },
+            Expr::UnaryExpr { right, .. } => self.expr(right),
+            Expr::Grouping { expr } => self.expr(expr),
+            Expr::Literal { .. } | Expr::Str { .. } => {},
+            Expr::Array { elements, .. } => {
+                for element in elements.iter_mut() {
+                    self.expr(element);
+                }
+            },
+            Expr::Index { target, index, .. } => {
+                self.expr(target);
+                self.expr(index);
+            },
+            Expr::IndexAssign { target, index, value, .. } => {
+                self.expr(target);
+                self.expr(index);
+                self.expr(value);
+            },
+            Expr::Set { object, value, .. } => {
+                self.expr(object);
+                self.expr(value);
+            },
+            Expr::Assign { value, .. } => self.expr(value),
+            Expr::Call { callee, args, .. } => {
+                self.expr(callee);
+                for arg in args.iter_mut() {
+                    self.expr(arg);
+                }
+            },
+        }
+    }
+
+    fn ty(&mut self, ty: &mut Option<Type>, span: Span) {
+        if let Some(ty) = ty {
+            self.rename_type(ty, span);
+        }
+    }
+
+    fn rename_type(&mut self, ty: &mut Type, span: Span) {
+        match ty {
+            Type::Array(elem) => self.rename_type(elem, span),
+            Type::Function { params, ret } => {
+                for param in params.iter_mut() {
+                    self.rename_type(param, span);
+                }
+                self.rename_type(ret, span);
+            },
+            Type::Struct(name) => {
+                let mut token = SpannedToken { token: Token::Ident(name.clone()), span };
+                self.struct_name(&mut token);
+                *name = token.token.to_string();
+            },
+            _ => {},
+        }
+    }
+
+    // Renames a struct name, which may be qualified by the module it is
+    // declared in, as in `math.Point`.
+    fn struct_name(&mut self, name: &mut SpannedToken) {
+        let id = name.token.to_string();
+        let namespaces = self.namespaces;
+        match id.split_once('.') {
+            Some((namespace, _)) => {
+                if let Some(module) = namespaces.get(namespace) {
+                    self.resolve(name, &module.structs);
+                }
+            },
+            None => self.rename(name, &self.module.structs),
+        }
+    }
+
+    fn is_local(&self, name: &SpannedToken) -> bool {
+        let id = name.token.to_string();
+        self.scopes.iter().any(|scope| scope.contains(&id))
+    }
+
+    fn namespace(&self, name: &SpannedToken) -> Option<&'a Module> {
+        if self.is_local(name) {
+            return None;
+        }
+        self.namespaces.get(&name.token.to_string())
+    }
+
+    fn rename(&self, name: &mut SpannedToken, names: &HashMap<String, String>) {
+        if let Some(renamed) = names.get(&name.token.to_string()) {
+            name.token = Token::Ident(renamed.clone());
+        }
+    }
+
+    // Renames a qualified name such as `math.square` to the name of the
+    // declaration it refers to in the imported module.
+    fn resolve(&mut self, name: &mut SpannedToken, names: &HashMap<String, String>) {
+        let id = name.token.to_string();
+        let (namespace, member) = id.split_once('.').unwrap();
+        match names.get(member) {
+            Some(renamed) => name.token = Token::Ident(renamed.clone()),
+            None => self.errors.push(CompileError::new(
+                format!("cannot find `{}` in module `{}`", member, namespace),
+                name.span,
+            )),
+        }
+    }
+}
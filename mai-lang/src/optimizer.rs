@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use crate::interp::{binary,unary,Value};
+use crate::parser::*;
+use crate::token::{Span,Token};
+use crate::types::Type;
+
+/// Optimizes a type checked program in place, at the given level:
+///
+/// - `0` leaves the program as it is.
+/// - `1` folds operations on constants, such as `2 * 3` or `!true`, prunes
+///   the branches of an `if` whose condition is constant and removes the
+///   code after a `return`.
+/// - `2` also inlines trivial functions, whose body only returns an
+///   expression of their parameters, at the calls to them.
+///
+/// This works on the syntax tree, so every backend benefits from it.
+/// Constants are folded the way the interpreter evaluates them, and
+/// operations that would fail at runtime, such as dividing by zero, are left
+/// for the backend to report.
+pub fn optimize(statements: &mut [Box<Stmt>], level: u8) {
+    if level == 0 {
+        return;
+    }
+    let inlined = match level {
+        1 => HashMap::new(),
+        _ => statements.iter().filter_map(|stmt| trivial(stmt)).collect(),
+    };
+    let optimizer = Optimizer { inlined };
+    for stmt in statements.iter_mut() {
+        optimizer.stmt(stmt);
+    }
+}
+
+// A function simple enough to be inlined, by name.
+struct Inlined {
+    params: Vec<String>,
+    body: Expr,
+}
+
+// Finds whether a function is trivial enough to inline: its body returns an
+// expression only made of its parameters and operations on them. Anything
+// with side effects or calls is left out, so inlining never recurses.
+fn trivial(stmt: &Stmt) -> Option<(String, Inlined)> {
+    let Stmt::Function { name, params, body, .. } = stmt else {
+        return None;
+    };
+    let [stmt] = body.as_slice() else {
+        return None;
+    };
+    let Stmt::Return { value: Some(value), .. } = stmt.as_ref() else {
+        return None;
+    };
+    let params = params.iter().map(|param| param.name.token.to_string()).collect::<Vec<String>>();
+    if !inlinable(value, &params) {
+        return None;
+    }
+    Some((name.token.to_string(), Inlined { params, body: value.as_ref().clone() }))
+}
+
+fn inlinable(expr: &Expr, params: &[String]) -> bool {
+    match expr {
+        Expr::Call { .. } | Expr::Lambda { .. } | Expr::Assign { .. } | Expr::IndexAssign { .. } | Expr::Set { .. } => false,
+        Expr::Variable { name, depth: Some(_) } => params.contains(&name.token.to_string()),
+        expr => expr.subexpressions().into_iter().all(|expr| inlinable(expr, params)),
+    }
+}
+
+// Whether an expression can be evaluated in a different place without
+// changing what the program does, as it has no side effects. Dividing and
+// indexing can trap, which moving them into the operand of `&&` or `||`
+// could skip.
+fn pure(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal { .. } | Expr::Str { .. } | Expr::Variable { .. } => true,
+        Expr::BinaryExpr { op, .. } if op.token == Token::Div => false,
+        Expr::Grouping { .. } | Expr::UnaryExpr { .. } | Expr::BinaryExpr { .. } | Expr::Logical { .. } | Expr::Get { .. } => {
+            expr.subexpressions().into_iter().all(pure)
+        },
+        _ => false,
+    }
+}
+
+// Counts the uses of a parameter in the body of an inlined function.
+fn uses(expr: &Expr, param: &str) -> usize {
+    match expr {
+        Expr::Variable { name, depth: Some(_) } if name.token.to_string() == param => 1,
+        expr => expr.subexpressions().into_iter().map(|expr| uses(expr, param)).sum(),
+    }
+}
+
+// Replaces the parameters in the body of an inlined function with the
+// arguments of a call.
+fn substitute(expr: &mut Expr, args: &HashMap<String, Expr>) {
+    if let Expr::Variable { name, depth: Some(_) } = expr {
+        if let Some(arg) = args.get(&name.token.to_string()) {
+            *expr = arg.clone();
+        }
+        return;
+    }
+    for expr in expr.subexpressions_mut() {
+        substitute(expr, args);
+    }
+}
+
+// The value of an expression, if it is a literal.
+fn constant(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Literal { value, ty: Some(ty), .. } => Value::literal(ty, value),
+        _ => None,
+    }
+}
+
+// Turns a value back into a literal, unless it has no literal such as an
+// infinite float.
+fn literal(value: Value, span: Span) -> Option<Expr> {
+    let (value, ty) = match value {
+        Value::I32(v) => (v.to_string(), Type::I32),
+        Value::I64(v) => (v.to_string(), Type::I64),
+        Value::F64(v) if v.is_finite() => (format!("{:?}", v), Type::F64),
+        Value::Bool(v) => (v.to_string(), Type::Bool),
+        _ => return None,
+    };
+    Some(Expr::Literal { value, ty: Some(ty), span })
+}
+
+// Whether a statement always returns, so nothing after it can run.
+fn returns(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return { .. } => true,
+        Stmt::Block(statements) => statements.iter().any(|stmt| returns(stmt)),
+        Stmt::If { then_branch, else_branch: Some(else_branch), .. } => returns(then_branch) && returns(else_branch),
+        _ => false,
+    }
+}
+
+struct Optimizer {
+    inlined: HashMap<String, Inlined>,
+}
+
+impl Optimizer {
+    fn statements(&self, statements: &mut Vec<Box<Stmt>>) {
+        for stmt in statements.iter_mut() {
+            self.stmt(stmt);
+        }
+        if let Some(last) = statements.iter().position(|stmt| returns(stmt)) {
+            statements.truncate(last + 1);
+        }
+    }
+
+    fn stmt(&self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Block(statements) => self.statements(statements),
+            Stmt::Expr(expr) => self.expr(expr),
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            },
+            Stmt::Function { body, .. } => self.statements(body),
+            Stmt::Extern { .. } | Stmt::Struct { .. } | Stmt::Import { .. } => {},
+            Stmt::If { cond, then_branch, else_branch } => {
+                self.expr(cond);
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch);
+                }
+                // The branch taken stays in a block of its own, as it had
+                // its own scope in the `if`.
+                let taken = match constant(cond) {
+                    Some(Value::Bool(true)) => Some(then_branch.clone()),
+                    Some(Value::Bool(false)) => Some(else_branch.take().unwrap_or_else(|| Box::new(Stmt::Block(vec![])))),
+                    _ => None,
+                };
+                if let Some(taken) = taken {
+                    *stmt = Stmt::Block(vec![taken]);
+                }
+            },
+            Stmt::While { condition, body } => {
+                self.expr(condition);
+                self.stmt(body);
+            },
+            Stmt::Var { initializer, .. } => {
+                if let Some(initializer) = initializer {
+                    self.expr(initializer);
+                }
+            },
+        }
+    }
+
+    fn expr(&self, expr: &mut Expr) {
+        match expr {
+            Expr::Lambda { body, .. } => self.statements(body),
+            _ => {
+                for expr in expr.subexpressions_mut() {
+                    self.expr(expr);
+                }
+            },
+        }
+        if let Some(folded) = self.fold(expr) {
+            *expr = folded;
+        }
+    }
+
+    // Simplifies an expression whose subexpressions have been optimized.
+    fn fold(&self, expr: &Expr) -> Option<Expr> {
+        match expr {
+            Expr::Grouping { expr } if matches!(expr.as_ref(), Expr::Literal { .. }) => Some(expr.as_ref().clone()),
+            Expr::UnaryExpr { op, right } => {
+                let value = unary(&op.token, constant(right)?).ok()?;
+                literal(value, op.span)
+            },
+            Expr::BinaryExpr { op, left, right, .. } => {
+                let value = binary(op.token.clone(), constant(left)?, constant(right)?).ok()?;
+                literal(value, op.span)
+            },
+            // The right hand side is only evaluated when the left doesn't
+            // decide the result.
+            Expr::Logical { op, left, right } => match (&op.token, constant(left)?) {
+                (Token::And, Value::Bool(false)) | (Token::Or, Value::Bool(true)) => Some(left.as_ref().clone()),
+                (Token::And, Value::Bool(true)) | (Token::Or, Value::Bool(false)) => Some(right.as_ref().clone()),
+                _ => None,
+            },
+            Expr::Call { callee, args, .. } => {
+                let Expr::Variable { name, depth: None } = callee.as_ref() else {
+                    return None;
+                };
+                let inlined = self.inlined.get(&name.token.to_string())?;
+                self.inline(inlined, args)
+            },
+            _ => None,
+        }
+    }
+
+    // Inlines a call to a trivial function. Arguments are substituted for
+    // the parameters, so an argument that takes more than reading a value is
+    // only inlined if it is used exactly once and has no side effects.
+    fn inline(&self, inlined: &Inlined, args: &[Box<Expr>]) -> Option<Expr> {
+        let mut substitutes = HashMap::new();
+        for (param, arg) in inlined.params.iter().zip(args.iter()) {
+            let simple = matches!(arg.as_ref(), Expr::Literal { .. } | Expr::Str { .. } | Expr::Variable { .. });
+            if !simple && !(pure(arg) && uses(&inlined.body, param) == 1) {
+                return None;
+            }
+            substitutes.insert(param.clone(), arg.as_ref().clone());
+        }
+        let mut body = inlined.body.clone();
+        substitute(&mut body, &substitutes);
+        // Folds what the arguments make constant, as in `square(3)`.
+        self.expr(&mut body);
+        Some(body)
+    }
+}
@@ -1,18 +1,24 @@
-use crate::token::Token;
+use serde::Serialize;
+use thiserror::Error;
 
-#[derive(Debug,Clone)]
+use crate::token::{Span,SpannedToken,Token};
+use crate::types::Type;
+
+#[derive(Debug,Clone,Serialize)]
 pub enum Expr {
     BinaryExpr {
-        op: Token,
+        op: SpannedToken,
         left: Box<Expr>,
         right: Box<Expr>,
+        // The type of both operands, filled in by the type checker.
+        ty: Option<Type>,
     },
     UnaryExpr {
-        op: Token,
+        op: SpannedToken,
         right: Box<Expr>,
     },
     Logical {
-        op: Token,
+        op: SpannedToken,
         left: Box<Expr>,
         right: Box<Expr>,
     },
@@ -21,33 +27,120 @@ pub enum Expr {
     },
     Literal {
         value: String,
+        // Filled in by the type checker, as integer literals take their
+        // type from the context they are used in.
+        ty: Option<Type>,
+        span: Span,
+    },
+    Str {
+        value: String,
+        span: Span,
+    },
+    Array {
+        elements: Vec<Box<Expr>>,
+        // The element type, filled in by the type checker as an empty array
+        // takes it from the context it is used in.
+        ty: Option<Type>,
+        span: Span,
+    },
+    Index {
+        target: Box<Expr>,
+        bracket: SpannedToken,
+        index: Box<Expr>,
+        // The element type, filled in by the type checker.
+        ty: Option<Type>,
+    },
+    // A struct literal such as `Point { x: 1.0, y: 2.0 }`.
+    Struct {
+        name: SpannedToken,
+        fields: Vec<(SpannedToken, Box<Expr>)>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: SpannedToken,
+        // The struct type of the object, filled in by the type checker.
+        ty: Option<Type>,
     },
     Assign {
-        name: Token,
+        name: SpannedToken,
         value: Box<Expr>,
+        // Filled in by the resolver, like the depth of a variable.
+        depth: Option<usize>,
+    },
+    IndexAssign {
+        target: Box<Expr>,
+        bracket: SpannedToken,
+        index: Box<Expr>,
+        value: Box<Expr>,
+        // The element type, filled in by the type checker.
+        ty: Option<Type>,
+    },
+    Set {
+        object: Box<Expr>,
+        name: SpannedToken,
+        value: Box<Expr>,
+        // The struct type of the object, filled in by the type checker.
+        ty: Option<Type>,
     },
     Variable {
-        name: Token,
+        name: SpannedToken,
+        // How many scopes out the variable is declared, filled in by the
+        // resolver. Variables captured by a closure are declared in the
+        // closure's outermost scope, and names of functions have no depth.
+        depth: Option<usize>,
     },
     Call {
         callee: Box<Expr>,
-        paren: Token,
+        paren: SpannedToken,
         args: Vec<Box<Expr>>,
     },
+    // An anonymous function such as `fun (x: i64) -> i64 { return x + 1; }`.
+    Lambda {
+        keyword: SpannedToken,
+        params: Vec<Param>,
+        ret: Option<Type>,
+        body: Vec<Box<Stmt>>,
+        // The variables of enclosing functions the body refers to, filled in
+        // by the type checker. They are copied into the closure when it is
+        // created.
+        captures: Vec<(String, Type)>,
+    },
 }
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,Serialize)]
 pub enum Stmt {
     Block(Vec<Box<Stmt>>),
     Expr(Box<Expr>),
     Return {
-        keyword: Token,
+        keyword: SpannedToken,
         value: Option<Box<Expr>>,
     },
     Function {
-        name: Token,
-        params: Vec<Token>,
+        name: SpannedToken,
+        params: Vec<Param>,
+        ret: Option<Type>,
         body: Vec<Box<Stmt>>,
+        // Whether the function is exported from the compiled wasm module.
+        exported: bool,
+    },
+    // A function provided by the host, such as `extern fun print(x: i64);`.
+    Extern {
+        name: SpannedToken,
+        params: Vec<Param>,
+        ret: Option<Type>,
+    },
+    // A struct declaration such as `struct Point { x, y }`, whose fields
+    // default to `f64` like parameters do.
+    Struct {
+        name: SpannedToken,
+        fields: Vec<Param>,
+    },
+    // An import of another file such as `import "math.mai";`, whose
+    // declarations are then referred to as `math.square`. Imports are
+    // resolved by `modules::load` and never reach the type checker.
+    Import {
+        keyword: SpannedToken,
+        path: SpannedToken,
     },
     If {
         cond: Box<Expr>,
@@ -59,326 +152,676 @@ pub enum Stmt {
         body: Box<Stmt>,
     },
     Var {
-        name: Token,
-        initializer: Box<Expr>,
+        name: SpannedToken,
+        ty: Option<Type>,
+        initializer: Option<Box<Expr>>,
     },
 }
 
+/// A function parameter or struct field along with its type annotation, if
+/// any.
+#[derive(Debug,Clone,Serialize)]
+pub struct Param {
+    pub name: SpannedToken,
+    pub ty: Option<Type>,
+}
+
+impl Expr {
+    /// Returns the location best describing this expression in diagnostics.
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::BinaryExpr { op, .. } => op.span,
+            Expr::UnaryExpr { op, .. } => op.span,
+            Expr::Logical { op, .. } => op.span,
+            Expr::Grouping { expr } => expr.span(),
+            Expr::Literal { span, .. } => *span,
+            Expr::Str { span, .. } => *span,
+            Expr::Array { span, .. } => *span,
+            Expr::Index { bracket, .. } => bracket.span,
+            Expr::Struct { name, .. } => name.span,
+            Expr::Get { name, .. } => name.span,
+            Expr::Assign { name, .. } => name.span,
+            Expr::IndexAssign { bracket, .. } => bracket.span,
+            Expr::Set { name, .. } => name.span,
+            Expr::Variable { name, .. } => name.span,
+            Expr::Call { callee, .. } => callee.span(),
+            Expr::Lambda { keyword, .. } => keyword.span,
+        }
+    }
+
+    /// Returns the expressions directly within this one, leaving out the
+    /// bodies of lambdas.
+    pub fn subexpressions(&self) -> Vec<&Expr> {
+        match self {
+            Expr::BinaryExpr { left, right, .. } | Expr::Logical { left, right, .. } => vec![left, right],
+            Expr::UnaryExpr { right, .. } => vec![right],
+            Expr::Grouping { expr } => vec![expr],
+            Expr::Literal { .. } | Expr::Str { .. } | Expr::Variable { .. } | Expr::Lambda { .. } => vec![],
+            Expr::Array { elements, .. } => elements.iter().map(|element| element.as_ref()).collect(),
+            Expr::Index { target, index, .. } => vec![target, index],
+            Expr::Struct { fields, .. } => fields.iter().map(|(_, value)| value.as_ref()).collect(),
+            Expr::Get { object, .. } => vec![object],
+            Expr::Assign { value, .. } => vec![value],
+            Expr::IndexAssign { target, index, value, .. } => vec![target, index, value],
+            Expr::Set { object, value, .. } => vec![object, value],
+            Expr::Call { callee, args, .. } => std::iter::once(callee.as_ref()).chain(args.iter().map(|arg| arg.as_ref())).collect(),
+        }
+    }
+
+    /// Like `subexpressions`, but allowing them to be changed.
+    pub fn subexpressions_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Expr::BinaryExpr { left, right, .. } | Expr::Logical { left, right, .. } => vec![left, right],
+            Expr::UnaryExpr { right, .. } => vec![right],
+            Expr::Grouping { expr } => vec![expr],
+            Expr::Literal { .. } | Expr::Str { .. } | Expr::Variable { .. } | Expr::Lambda { .. } => vec![],
+            Expr::Array { elements, .. } => elements.iter_mut().map(|element| element.as_mut()).collect(),
+            Expr::Index { target, index, .. } => vec![target, index],
+            Expr::Struct { fields, .. } => fields.iter_mut().map(|(_, value)| value.as_mut()).collect(),
+            Expr::Get { object, .. } => vec![object],
+            Expr::Assign { value, .. } => vec![value],
+            Expr::IndexAssign { target, index, value, .. } => vec![target, index, value],
+            Expr::Set { object, value, .. } => vec![object, value],
+            Expr::Call { callee, args, .. } => std::iter::once(callee.as_mut()).chain(args.iter_mut().map(|arg| arg.as_mut())).collect(),
+        }
+    }
+}
+
+impl Stmt {
+    /// Returns the location best describing this statement in diagnostics,
+    /// which an empty block has none of.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Stmt::Block(statements) => statements.first().and_then(|stmt| stmt.span()),
+            Stmt::Expr(expr) => Some(expr.span()),
+            Stmt::Return { keyword, .. } => Some(keyword.span),
+            Stmt::Function { name, .. } => Some(name.span),
+            Stmt::Extern { name, .. } => Some(name.span),
+            Stmt::Struct { name, .. } => Some(name.span),
+            Stmt::Import { keyword, .. } => Some(keyword.span),
+            Stmt::If { cond, .. } => Some(cond.span()),
+            Stmt::While { condition, .. } => Some(condition.span()),
+            Stmt::Var { name, .. } => Some(name.span),
+        }
+    }
+}
+
+/// An error found while parsing, pointing at the offending token.
+#[derive(Debug,Clone,Error)]
+#[error("{message}")]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
 #[derive(Debug)]
 pub struct Parser {
-    pub tokens: Vec<Token>,
+    pub tokens: Vec<SpannedToken>,
     current: usize,
+    // How many blocks deep we are, so recovery knows whether a `}` closes
+    // an enclosing block.
+    depth: usize,
+    errors: Vec<ParseError>,
 }
 
 
+/// Joins the name of an imported module and a name declared in it into a
+/// single identifier such as `math.Point`, spanning both.
+pub fn qualified(module: &SpannedToken, member: &SpannedToken) -> SpannedToken {
+    let span = Span { len: member.span.offset + member.span.len - module.span.offset, ..module.span };
+    SpannedToken { token: Token::Ident(format!("{}.{}", module.token, member.token)), span }
+}
+
 macro_rules! bin_expr {
     ($exp1: expr, $op: expr, $exp2: expr) => {
-       Expr::BinaryExpr { left: Box::new($exp1), op: $op, right: Box::new($exp2) } 
+       Expr::BinaryExpr { left: Box::new($exp1), op: $op, right: Box::new($exp2), ty: None }
     };
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0 }
+    pub fn new(mut tokens: Vec<SpannedToken>) -> Self {
+        // Make sure we always have an EOF token to report errors against.
+        if tokens.last().map(|tok| &tok.token) != Some(&Token::EOF) {
+            let span = match tokens.last() {
+                Some(last) => Span {
+                    offset: last.span.offset + last.span.len,
+                    len: 0,
+                    line: last.span.line,
+                    column: last.span.column + last.span.len,
+                },
+                None => Span { offset: 0, len: 0, line: 1, column: 1 },
+            };
+            tokens.push(SpannedToken { token: Token::EOF, span });
+        }
+        Self { tokens, current: 0, depth: 0, errors: vec!() }
     }
-    pub fn parse(&mut self) -> Vec<Box<Stmt>> {
+    /// Parses every declaration in the token stream, recovering from errors
+    /// so that all of them can be reported at once alongside whatever could
+    /// still be parsed.
+    pub fn parse(&mut self) -> (Vec<Box<Stmt>>, Vec<ParseError>) {
         let mut statements = vec!();
         while !self.is_at_end() {
-            statements.push(self.declaration());
+            if let Some(stmt) = self.recovering_declaration() {
+                statements.push(stmt);
+            }
+        }
+        return (statements, std::mem::take(&mut self.errors));
+    }
+    fn recovering_declaration(&mut self) -> Option<Box<Stmt>> {
+        match self.declaration() {
+            Ok(stmt) => Some(stmt),
+            Err(err) => {
+                self.errors.push(err);
+                self.synchronize();
+                None
+            },
+        }
+    }
+    /// Discards tokens until we are likely at the start of the next
+    /// statement, so a single mistake doesn't cascade into many errors.
+    fn synchronize(&mut self) {
+        while !self.is_at_end() {
+            match self.peek() {
+                Token::Semicolon => {
+                    self.advance();
+                    return;
+                },
+                // The failing declaration consumed at least the token that
+                // started it, so stopping here always makes progress.
+                Token::RBrace if self.depth > 0 => return,
+                Token::Fun | Token::Export | Token::Extern | Token::Struct | Token::Import | Token::Var | Token::If | Token::While | Token::For | Token::Return => return,
+                _ => self.advance(),
+            }
         }
-        return statements;
     }
-    pub fn declaration(&mut self) -> Box<Stmt> {
-        if self.check_match(vec!(Token::Fun)) { 
-            return self.function_declaration();
+    pub fn declaration(&mut self) -> ParseResult<Box<Stmt>> {
+        if self.check_match(vec!(Token::Export)) {
+            self.consume(Token::Fun, "expected `fun` after `export`")?;
+            return self.function_declaration(true);
+        }
+        // `fun (` starts an anonymous function instead, in an expression.
+        if self.check(Token::Fun) && self.peek_next() != Token::LParen {
+            self.advance();
+            return self.function_declaration(false);
+        }
+        if self.check_match(vec!(Token::Extern)) {
+            self.consume(Token::Fun, "expected `fun` after `extern`")?;
+            return self.extern_declaration();
+        }
+        if self.check_match(vec!(Token::Struct)) {
+            return self.struct_declaration();
+        }
+        if self.check_match(vec!(Token::Import)) {
+            return self.import_declaration();
         }
         if self.check_match(vec!(Token::Var)) {
             return self.variable_declaration();
         }
         self.statement()
     }
-    pub fn function_declaration(&mut self) -> Box<Stmt> {
-        let name = self.consume_identifier();
-        self.consume(Token::LParen);
-        let mut params = vec![];
-        if !self.check_match(vec!(Token::RParen)) {
-            params.push(self.consume_identifier());
-            while self.check_match(vec!(Token::Comma)) {
-                params.push(self.consume_identifier());
+    pub fn struct_declaration(&mut self) -> ParseResult<Box<Stmt>> {
+        let name = self.consume_identifier("expected struct name")?;
+        self.consume(Token::LBrace, "expected `{` after struct name")?;
+        let mut fields = vec![];
+        while !self.check(Token::RBrace) {
+            let name = self.consume_identifier("expected field name")?;
+            let mut ty = None;
+            if self.check_match(vec!(Token::Colon)) {
+                ty = Some(self.type_annotation()?);
+            }
+            fields.push(Param { name, ty });
+            if !self.check_match(vec!(Token::Comma)) {
+                break;
             }
         }
-        self.consume(Token::RParen);
-        self.consume(Token::LBrace);
-        let body = self.block();
-        Box::new(Stmt::Function { name, params, body })
+        self.consume(Token::RBrace, "expected `}` after struct fields")?;
+        Ok(Box::new(Stmt::Struct { name, fields }))
     }
-    pub fn consume_identifier(&mut self) -> Token {
-        match self.peek() {
-            Token::Ident(_) => {
+    pub fn import_declaration(&mut self) -> ParseResult<Box<Stmt>> {
+        let keyword = self.previous();
+        let path = match self.peek() {
+            Token::Str(_) => {
                 self.advance();
                 self.previous()
             },
-            _ => panic!("cannot match")
+            _ => return Err(self.error_at_current("expected a path after `import`")),
+        };
+        self.consume(Token::Semicolon, "expected `;` after import")?;
+        Ok(Box::new(Stmt::Import { keyword, path }))
+    }
+    pub fn function_declaration(&mut self, exported: bool) -> ParseResult<Box<Stmt>> {
+        let (name, params, ret) = self.function_signature()?;
+        self.consume(Token::LBrace, "expected `{` before function body")?;
+        let body = self.block()?;
+        Ok(Box::new(Stmt::Function { name, params, ret, body, exported }))
+    }
+    pub fn extern_declaration(&mut self) -> ParseResult<Box<Stmt>> {
+        let (name, params, ret) = self.function_signature()?;
+        self.consume(Token::Semicolon, "expected `;` after extern function declaration")?;
+        Ok(Box::new(Stmt::Extern { name, params, ret }))
+    }
+    pub fn function_signature(&mut self) -> ParseResult<(SpannedToken, Vec<Param>, Option<Type>)> {
+        let name = self.consume_identifier("expected function name")?;
+        self.consume(Token::LParen, "expected `(` after function name")?;
+        let (params, ret) = self.function_params()?;
+        Ok((name, params, ret))
+    }
+    // Parses the parameters and return type of a function, after its `(`.
+    pub fn function_params(&mut self) -> ParseResult<(Vec<Param>, Option<Type>)> {
+        let mut params = vec![];
+        if !self.check(Token::RParen) {
+            params.push(self.parameter()?);
+            while self.check_match(vec!(Token::Comma)) {
+                params.push(self.parameter()?);
+            }
+        }
+        self.consume(Token::RParen, "expected `)` after parameters")?;
+        let mut ret = None;
+        if self.check_match(vec!(Token::Arrow)) {
+            ret = Some(self.type_annotation()?);
+        }
+        Ok((params, ret))
+    }
+    pub fn parameter(&mut self) -> ParseResult<Param> {
+        let name = self.consume_identifier("expected parameter name")?;
+        let mut ty = None;
+        if self.check_match(vec!(Token::Colon)) {
+            ty = Some(self.type_annotation()?);
+        }
+        Ok(Param { name, ty })
+    }
+    pub fn type_annotation(&mut self) -> ParseResult<Type> {
+        if self.check_match(vec!(Token::LBracket)) {
+            let bracket = self.previous();
+            let elem = self.type_annotation()?;
+            self.consume(Token::RBracket, "expected `]` after array element type")?;
+            if elem == Type::Void {
+                return Err(ParseError {
+                    message: "arrays cannot hold `void` elements".to_string(),
+                    span: bracket.span,
+                });
+            }
+            return Ok(Type::Array(Box::new(elem)));
+        }
+        if self.check_match(vec!(Token::Fun)) {
+            self.consume(Token::LParen, "expected `(` after `fun`")?;
+            let mut params = vec![];
+            if !self.check(Token::RParen) {
+                params.push(self.type_annotation()?);
+                while self.check_match(vec!(Token::Comma)) {
+                    params.push(self.type_annotation()?);
+                }
+            }
+            self.consume(Token::RParen, "expected `)` after parameter types")?;
+            let mut ret = Type::Void;
+            if self.check_match(vec!(Token::Arrow)) {
+                ret = self.type_annotation()?;
+            }
+            return Ok(Type::Function { params, ret: Box::new(ret) });
+        }
+        let mut name = self.consume_identifier("expected type")?;
+        if self.check_match(vec!(Token::Dot)) {
+            let member = self.consume_identifier("expected type name after `.`")?;
+            name = qualified(&name, &member);
         }
+        let Token::Ident(type_name) = &name.token else {
+            unreachable!();
+        };
+        // Any other name refers to a struct, which the type checker makes
+        // sure is declared.
+        Ok(Type::from_name(type_name).unwrap_or_else(|| Type::Struct(type_name.clone())))
     }
-    pub fn variable_declaration(&mut self) -> Box<Stmt> {
-        let name = match self.peek() {
+    pub fn consume_identifier(&mut self, message: &str) -> ParseResult<SpannedToken> {
+        match self.peek() {
             Token::Ident(_) => {
                 self.advance();
-                self.previous()
+                Ok(self.previous())
             },
-            _ => panic!("cannot match")
-        };
-        let mut initializer = Expr::Literal { value: "false".to_string() };
+            _ => Err(self.error_at_current(message)),
+        }
+    }
+    pub fn variable_declaration(&mut self) -> ParseResult<Box<Stmt>> {
+        let name = self.consume_identifier("expected variable name")?;
+        let mut ty = None;
+        if self.check_match(vec!(Token::Colon)) {
+            ty = Some(self.type_annotation()?);
+        }
+        let mut initializer = None;
         if self.check_match(vec!(Token::Eq)) {
-            initializer = self.expression();
+            initializer = Some(Box::new(self.expression()?));
         }
-        self.consume(Token::Semicolon);
-        Box::new(Stmt::Var{ name, initializer: Box::new(initializer) })
+        self.consume(Token::Semicolon, "expected `;` after variable declaration")?;
+        Ok(Box::new(Stmt::Var{ name, ty, initializer }))
     }
-    pub fn statement(&mut self) -> Box<Stmt> {
+    pub fn statement(&mut self) -> ParseResult<Box<Stmt>> {
         if self.check_match(vec!(Token::For)) {
             return self.for_statement();
         }
         if self.check_match(vec!(Token::If)) {
-            return Box::new(self.if_statement());
+            return Ok(Box::new(self.if_statement()?));
         }
         if self.check_match(vec!(Token::Return)) {
-            return Box::new(self.return_statement());
+            return Ok(Box::new(self.return_statement()?));
         }
         if self.check_match(vec!(Token::While)) {
-            return Box::new(self.while_statement());
+            return Ok(Box::new(self.while_statement()?));
         }
         if self.check_match(vec!(Token::LBrace)) {
-            return Box::new(Stmt::Block(self.block()));
+            return Ok(Box::new(Stmt::Block(self.block()?)));
         }
-        let expr = self.expression_statement();
-        Box::new(expr)
+        let expr = self.expression_statement()?;
+        Ok(Box::new(expr))
     }
-    pub fn for_statement(&mut self) -> Box<Stmt> {
-        self.consume(Token::LParen);
+    pub fn for_statement(&mut self) -> ParseResult<Box<Stmt>> {
+        let keyword = self.previous();
+        self.consume(Token::LParen, "expected `(` after `for`")?;
         let initializer: Option<Box<Stmt>>;
         if self.check_match(vec!(Token::Semicolon)) {
             initializer = None;
         } else if self.check_match(vec!(Token::Var)) {
-            initializer = Some(self.variable_declaration());
+            initializer = Some(self.variable_declaration()?);
         } else {
-            initializer = Some(Box::new(self.expression_statement()));
+            initializer = Some(Box::new(self.expression_statement()?));
         }
 
         let mut cond: Option<Expr> = None;
-        if !self.check_match(vec!(Token::Semicolon)) {
-            cond = Some(self.expression());
+        if !self.check(Token::Semicolon) {
+            cond = Some(self.expression()?);
         }
-        self.consume(Token::Semicolon);
+        self.consume(Token::Semicolon, "expected `;` after loop condition")?;
 
         let mut increment: Option<Expr> = None;
-        if !self.check_match(vec!(Token::RParen)) {
-            increment = Some(self.expression());
+        if !self.check(Token::RParen) {
+            increment = Some(self.expression()?);
         }
-        self.consume(Token::RParen);
+        self.consume(Token::RParen, "expected `)` after for clauses")?;
 
-        let mut body = self.statement();
+        let mut body = self.statement()?;
         if increment.is_some() {
             let expr = Stmt::Expr(Box::new(increment.unwrap()));
             body = Box::new(Stmt::Block(vec![body, Box::new(expr)]));
         }
 
-        if cond.is_some() {
-            cond = Some(Expr::Literal { value: "true".to_string() });
-        }
+        // A missing condition loops forever.
+        let cond = cond.unwrap_or(Expr::Literal {
+            value: "true".to_string(),
+            ty: None,
+            span: keyword.span,
+        });
 
-        body = Box::new(Stmt::While { condition: Box::new(cond.unwrap()), body });
+        body = Box::new(Stmt::While { condition: Box::new(cond), body });
         if initializer.is_some() {
             body = Box::new(Stmt::Block(vec![initializer.unwrap(), body]));
         }
-        body
+        Ok(body)
     }
-    pub fn if_statement(&mut self) -> Stmt  {
-        self.consume(Token::LParen);
-        let cond = self.expression();
-        self.consume(Token::RParen);
-        let then_branch = self.statement();
+    pub fn if_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(Token::LParen, "expected `(` after `if`")?;
+        let cond = self.expression()?;
+        self.consume(Token::RParen, "expected `)` after if condition")?;
+        let then_branch = self.statement()?;
         let mut else_branch = None;
         if self.check_match(vec!(Token::Else)) {
-            else_branch = Some(self.statement());
+            else_branch = Some(self.statement()?);
         }
-        Stmt::If { cond: Box::new(cond), then_branch, else_branch }
+        Ok(Stmt::If { cond: Box::new(cond), then_branch, else_branch })
     }
-    pub fn return_statement(&mut self) -> Stmt  {
+    pub fn return_statement(&mut self) -> ParseResult<Stmt> {
         let keyword = self.previous();
         let mut value = None;
-        if !self.check_match(vec!(Token::Semicolon)) {
-            value = Some(Box::new(self.expression()));
+        if !self.check(Token::Semicolon) {
+            value = Some(Box::new(self.expression()?));
         }
-        self.consume(Token::Semicolon);
-        Stmt::Return { keyword, value }
+        self.consume(Token::Semicolon, "expected `;` after return value")?;
+        Ok(Stmt::Return { keyword, value })
     }
-    pub fn while_statement(&mut self) -> Stmt  {
-        self.consume(Token::LParen);
-        let cond = self.expression();
-        self.consume(Token::RParen);
-        let body = self.statement();
-        return Stmt::While { condition: Box::new(cond), body }
+    pub fn while_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(Token::LParen, "expected `(` after `while`")?;
+        let cond = self.expression()?;
+        self.consume(Token::RParen, "expected `)` after loop condition")?;
+        let body = self.statement()?;
+        return Ok(Stmt::While { condition: Box::new(cond), body })
     }
-    pub fn block(&mut self) -> Vec<Box<Stmt>> {
+    pub fn block(&mut self) -> ParseResult<Vec<Box<Stmt>>> {
         let mut statements = vec!();
-        while !self.check_match(vec!(Token::RBrace)) && !self.is_at_end() {
-            statements.push(self.declaration());
+        self.depth += 1;
+        while !self.check(Token::RBrace) && !self.is_at_end() {
+            if let Some(stmt) = self.recovering_declaration() {
+                statements.push(stmt);
+            }
         }
-        self.consume(Token::RBrace);
-        return statements;
+        self.depth -= 1;
+        self.consume(Token::RBrace, "expected `}` after block")?;
+        return Ok(statements);
     }
-    pub fn expression_statement(&mut self) -> Stmt {
-        let value = self.expression();
-        self.consume(Token::Semicolon);
-        Stmt::Expr(Box::new(value))
+    pub fn expression_statement(&mut self) -> ParseResult<Stmt> {
+        let value = self.expression()?;
+        self.consume(Token::Semicolon, "expected `;` after expression")?;
+        Ok(Stmt::Expr(Box::new(value)))
     }
-    pub fn expression(&mut self) -> Expr {
+    pub fn expression(&mut self) -> ParseResult<Expr> {
         return self.assignment();
     }
-    pub fn assignment(&mut self) -> Expr {
-        let expr = self.or();
+    pub fn assignment(&mut self) -> ParseResult<Expr> {
+        let expr = self.or()?;
         if self.check_match(vec!(Token::Eq)) {
-            let value = self.assignment();
+            let equals = self.previous();
+            let value = self.assignment()?;
             return match expr {
-                Expr::Variable { name } => {
-                    Expr::Assign { name, value: Box::new(value) } 
+                Expr::Variable { name, .. } => {
+                    Ok(Expr::Assign { name, value: Box::new(value), depth: None })
+                },
+                Expr::Index { target, bracket, index, .. } => {
+                    Ok(Expr::IndexAssign { target, bracket, index, value: Box::new(value), ty: None })
+                },
+                Expr::Get { object, name, .. } => {
+                    Ok(Expr::Set { object, name, value: Box::new(value), ty: None })
                 },
-                _ => panic!("invalid assignment"),
+                _ => Err(ParseError {
+                    message: "invalid assignment target".to_string(),
+                    span: equals.span,
+                }),
             }
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn or(&mut self) -> Expr {
-        let mut expr = self.and();
+    pub fn or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.and()?;
         while self.check_match(vec!(Token::Or)) {
             let op = self.previous();
-            let right = self.and();
+            let right = self.and()?;
             expr = Expr::Logical { left: Box::new(expr), op, right: Box::new(right) }
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn and(&mut self) -> Expr {
-        let mut expr = self.equality();
+    pub fn and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.equality()?;
         while self.check_match(vec!(Token::And)) {
             let op = self.previous();
-            let right = self.equality();
+            let right = self.equality()?;
             expr = Expr::Logical { left: Box::new(expr), op, right: Box::new(right) }
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn equality(&mut self) -> Expr {
-        let mut expr = self.comparison();
+    pub fn equality(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.comparison()?;
         while self.check_match(vec!(
-            Token::Eqq, 
+            Token::Eqq,
             Token::BangEq,
         )) {
             let op = self.previous();
-            let right = self.comparison();
+            let right = self.comparison()?;
             expr = bin_expr!(expr, op, right);
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn comparison(&mut self) -> Expr {
-        let mut expr = self.term();
+    pub fn comparison(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.term()?;
         while self.check_match(vec!(
-            Token::Greater, 
-            Token::Geq, 
-            Token::Less, 
+            Token::Greater,
+            Token::Geq,
+            Token::Less,
             Token::Leq,
         )) {
             let op = self.previous();
-            let right = self.term();
+            let right = self.term()?;
             expr = bin_expr!(expr, op, right);
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn term(&mut self) -> Expr {
-        let mut expr = self.factor();
+    pub fn term(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.factor()?;
         while self.check_match(
             vec!(Token::Minus, Token::Plus)
         ) {
             let op = self.previous();
-            let right = self.factor();
+            let right = self.factor()?;
             expr = bin_expr!(expr, op, right);
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn factor(&mut self) -> Expr {
-        let mut expr = self.unary();
+    pub fn factor(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.unary()?;
         while self.check_match(
             vec!(Token::Div, Token::Times)
         ) {
             let op = self.previous();
-            let right = self.unary();
+            let right = self.unary()?;
             expr = bin_expr!(expr, op, right);
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn unary(&mut self) -> Expr {
+    pub fn unary(&mut self) -> ParseResult<Expr> {
         if self.check_match(
             vec!(Token::Bang, Token::Minus)
         ) {
             let op = self.previous();
-            let right = self.unary();
-            return Expr::UnaryExpr { op, right: Box::new(right) };
+            let right = self.unary()?;
+            return Ok(Expr::UnaryExpr { op, right: Box::new(right) });
         }
         return self.call();
     }
-    pub fn call(&mut self) -> Expr {
-        let mut expr = self.primary();
+    pub fn call(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.primary()?;
         loop {
             if self.check_match(
                 vec!(Token::LParen)
             ) {
-                expr = self.finish_call(expr);
+                expr = self.finish_call(expr)?;
+            } else if self.check_match(vec!(Token::LBracket)) {
+                let bracket = self.previous();
+                let index = self.expression()?;
+                self.consume(Token::RBracket, "expected `]` after index")?;
+                expr = Expr::Index { target: Box::new(expr), bracket, index: Box::new(index), ty: None };
+            } else if self.check_match(vec!(Token::Dot)) {
+                let name = self.consume_identifier("expected field name after `.`")?;
+                expr = Expr::Get { object: Box::new(expr), name, ty: None };
             } else {
                 break;
             }
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn finish_call(&mut self, expr: Expr) -> Expr {
+    pub fn finish_call(&mut self, expr: Expr) -> ParseResult<Expr> {
         let mut args = vec!();
         if !self.check(Token::RParen) {
-            let mut sub_expr = self.expression();
+            let mut sub_expr = self.expression()?;
             args.push(Box::new(sub_expr));
             while self.check_match(
                 vec!(Token::Comma)
             ) {
-                sub_expr = self.expression();
+                sub_expr = self.expression()?;
                 args.push(Box::new(sub_expr));
             }
         }
-        let paren = self.consume(Token::RParen);
-        return Expr::Call { callee: Box::new(expr), paren, args };
+        let paren = self.consume(Token::RParen, "expected `)` after arguments")?;
+        return Ok(Expr::Call { callee: Box::new(expr), paren, args });
     }
-    pub fn primary(&mut self) -> Expr {
-        if self.check_match(vec!(Token::False)) {
-            return Expr::Literal { value: "false".to_string() };
-        }
-        if self.check_match(vec!(Token::True)) {
-            return Expr::Literal { value: "true".to_string() };
+    pub fn primary(&mut self) -> ParseResult<Expr> {
+        if self.check_match(vec!(Token::False, Token::True)) {
+            let literal = self.previous();
+            return Ok(Expr::Literal {
+                value: literal.token.to_string(),
+                ty: None,
+                span: literal.span,
+            });
         }
         match self.peek() {
             Token::Number(n) => {
                 self.advance();
-                return Expr::Literal { value: n };
+                return Ok(Expr::Literal { value: n, ty: None, span: self.previous().span });
+            },
+            Token::Str(value) => {
+                self.advance();
+                return Ok(Expr::Str { value, span: self.previous().span });
             },
             Token::Ident(_) => {
                 self.advance();
-                return Expr::Variable { name: self.previous() };
+                let mut name = self.previous();
+                // Struct literals can name a struct declared in an imported
+                // module, as in `math.Point { ... }`.
+                if self.check(Token::Dot) && matches!(self.peek_nth(1), Token::Ident(_)) && self.peek_nth(2) == Token::LBrace {
+                    self.advance();
+                    let member = self.consume_identifier("expected struct name")?;
+                    name = qualified(&name, &member);
+                }
+                // Conditions are always parenthesized, so a name followed by
+                // `{` can only start a struct literal.
+                if self.check_match(vec!(Token::LBrace)) {
+                    return self.struct_literal(name);
+                }
+                return Ok(Expr::Variable { name, depth: None });
             },
             _ => {}
         }
         if self.check_match(vec!(Token::LParen)) {
-            let expr = self.expression();
-            self.consume(Token::RParen);
-            return Expr::Grouping { expr: Box::new(expr) };
+            let expr = self.expression()?;
+            self.consume(Token::RParen, "expected `)` after expression")?;
+            return Ok(Expr::Grouping { expr: Box::new(expr) });
+        }
+        if self.check_match(vec!(Token::Fun)) {
+            let keyword = self.previous();
+            self.consume(Token::LParen, "expected `(` after `fun`")?;
+            let (params, ret) = self.function_params()?;
+            self.consume(Token::LBrace, "expected `{` before function body")?;
+            let body = self.block()?;
+            return Ok(Expr::Lambda { keyword, params, ret, body, captures: vec!() });
+        }
+        if self.check_match(vec!(Token::LBracket)) {
+            let span = self.previous().span;
+            let mut elements = vec!();
+            if !self.check(Token::RBracket) {
+                elements.push(Box::new(self.expression()?));
+                while self.check_match(vec!(Token::Comma)) {
+                    elements.push(Box::new(self.expression()?));
+                }
+            }
+            self.consume(Token::RBracket, "expected `]` after array elements")?;
+            return Ok(Expr::Array { elements, ty: None, span });
+        }
+        Err(self.error_at_current("expected expression"))
+    }
+    pub fn struct_literal(&mut self, name: SpannedToken) -> ParseResult<Expr> {
+        let mut fields = vec!();
+        while !self.check(Token::RBrace) {
+            let field = self.consume_identifier("expected field name")?;
+            self.consume(Token::Colon, "expected `:` after field name")?;
+            fields.push((field, Box::new(self.expression()?)));
+            if !self.check_match(vec!(Token::Comma)) {
+                break;
+            }
+        }
+        self.consume(Token::RBrace, "expected `}` after struct fields")?;
+        Ok(Expr::Struct { name, fields })
+    }
+    fn error_at_current(&self, message: &str) -> ParseError {
+        let found = self.peek_token();
+        ParseError {
+            message: format!("{}, found {}", message, found.token.describe()),
+            span: found.span,
         }
-        // TODO: Handle this edge case...
-        return Expr::Literal { value: "false".to_string() };
     }
     fn check_match(&mut self, toks: Vec<Token>) -> bool {
         for tok in toks.iter() {
@@ -395,29 +838,35 @@ impl Parser {
         }
         return self.peek() == tok;
     }
-    fn consume(&mut self, tok: Token) -> Token {
+    fn consume(&mut self, tok: Token, message: &str) -> ParseResult<SpannedToken> {
         if self.check(tok) {
             self.advance();
-        } 
-        self.previous()
+            return Ok(self.previous());
+        }
+        Err(self.error_at_current(message))
     }
     fn advance(&mut self) {
-        self.current += 1;
-        self.previous();
+        if !self.is_at_end() {
+            self.current += 1;
+        }
     }
     fn is_at_end(&self) -> bool {
         return self.peek() == Token::EOF
     }
-    fn previous(&self) -> Token {
-        if let Some(tok) = self.tokens.get(self.current-1) {
-            return tok.clone();
-        }
-        return Token::EOF;
+    fn previous(&self) -> SpannedToken {
+        self.tokens[self.current.saturating_sub(1)].clone()
+    }
+    fn peek_token(&self) -> SpannedToken {
+        self.tokens[self.current.min(self.tokens.len() - 1)].clone()
     }
     fn peek(&self) -> Token {
-        if let Some(tok) = self.tokens.get(self.current) {
-            return tok.clone();
-        }
-        return Token::EOF;
+        self.peek_token().token
+    }
+    fn peek_next(&self) -> Token {
+        self.peek_nth(1)
+    }
+    // Looks `n` tokens ahead, stopping at the final EOF.
+    fn peek_nth(&self, n: usize) -> Token {
+        self.tokens[(self.current + n).min(self.tokens.len() - 1)].token.clone()
     }
 }
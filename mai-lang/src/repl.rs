@@ -0,0 +1,362 @@
+use std::io::{self,BufRead,Write};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use inkwell::OptimizationLevel;
+use inkwell::context::Context;
+use inkwell::passes::PassManager;
+use inkwell::targets::{InitializationConfig,Target};
+
+use crate::diagnostic::{CompileError,Diagnostic,SourceMap};
+use crate::interp::{Interpreter,Value};
+use crate::lexer::TokenLexer;
+use crate::llvm_translator::Translator;
+use crate::parser::{Expr,Parser,Stmt};
+use crate::runtime::HostFunction;
+use crate::token::{Span,SpannedToken,Token};
+use crate::types::{checked,Type};
+use crate::{function_passes,resolver,type_checker};
+
+/// Reads inputs from stdin until it is closed, printing the value of each.
+pub fn run() -> eyre::Result<()> {
+    Target::initialize_native(&InitializationConfig::default()).map_err(|err| eyre::eyre!(err))?;
+    START.get_or_init(Instant::now);
+    let mut session = Session::default();
+    let stdin = io::stdin();
+    let mut buffer = String::new();
+    loop {
+        print!("{}", if buffer.is_empty() { "> " } else { "... " });
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        buffer.push_str(&line);
+        if buffer.trim().is_empty() {
+            buffer.clear();
+            continue;
+        }
+        // Keep reading while a brace or parenthesis is left open, so
+        // functions can be written over several lines.
+        if unclosed(&buffer) {
+            continue;
+        }
+        match session.evaluate(std::mem::take(&mut buffer)) {
+            Ok(Value::Void) => {},
+            Ok(value) => println!("{}", value),
+            Err(errors) => {
+                for err in errors.iter() {
+                    eprintln!("{}", err.render(&session.files));
+                }
+            },
+        }
+    }
+}
+
+fn unclosed(input: &str) -> bool {
+    let (tokens, _) = TokenLexer::new(input).tokenize();
+    let depth = tokens.iter().fold(0, |depth, tok| match tok.token {
+        Token::LParen | Token::LBrace | Token::LBracket => depth + 1,
+        Token::RParen | Token::RBrace | Token::RBracket => depth - 1,
+        _ => depth,
+    });
+    depth > 0
+}
+
+/// The declarations entered so far, which later inputs can use.
+///
+/// Every input is compiled into a fresh module along with all of them, rather
+/// than only adding its own functions to a running engine, so redefining a
+/// function is checked against everything calling it.
+#[derive(Default)]
+struct Session {
+    files: SourceMap,
+    declarations: Vec<Box<Stmt>>,
+}
+
+impl Session {
+    /// Compiles and runs an input. Its declarations are kept if it checks,
+    /// replacing earlier ones of the same name, and any other statements are
+    /// run as the body of a function, returning the value of the final
+    /// expression statement.
+    fn evaluate(&mut self, mut input: String) -> Result<Value, Vec<CompileError>> {
+        // Allow leaving out the semicolon after a lone expression.
+        let trimmed = input.trim_end();
+        if !trimmed.ends_with(';') && !trimmed.ends_with('}') {
+            input.truncate(trimmed.len());
+            input.push(';');
+        }
+        let index = self.files.files.len() + 1;
+        let start = self.files.add("<repl>".to_string(), input);
+        let input = &self.files.files.last().unwrap().input;
+        let (tokens, lex_errors) = TokenLexer::starting_at(input, start).tokenize();
+        let (statements, parse_errors) = Parser::new(tokens).parse();
+        let mut errors = lex_errors.into_iter().map(CompileError::from).collect::<Vec<CompileError>>();
+        errors.extend(parse_errors.into_iter().map(CompileError::from));
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut declarations = self.declarations.clone();
+        let mut body = vec![];
+        for stmt in statements.into_iter() {
+            match stmt.as_ref() {
+                Stmt::Import { keyword, .. } => {
+                    return Err(vec![CompileError::new("imports are not supported in the REPL", keyword.span)]);
+                },
+                Stmt::Function { .. } | Stmt::Extern { .. } | Stmt::Struct { .. } => {
+                    let key = declared(&stmt);
+                    declarations.retain(|decl| declared(decl) != key);
+                    declarations.push(stmt);
+                },
+                _ => body.push(stmt),
+            }
+        }
+        if body.is_empty() {
+            check(&mut declarations.clone())?;
+            self.declarations = declarations;
+            return Ok(Value::Void);
+        }
+
+        // The name can't be written in the source, so it never clashes with
+        // one of the declarations. Errors about the function as a whole point
+        // at the start of the input.
+        let name = format!("repl.{}", index);
+        let span = Span { offset: start, len: 0, line: 1, column: 1 };
+        let last = match body.last().map(|stmt| stmt.as_ref()) {
+            Some(Stmt::Expr(expr)) => Some(expr.clone()),
+            _ => None,
+        };
+        let ret = match &last {
+            Some(expr) => {
+                body.pop();
+                // Find out the type of the final expression by storing it in
+                // a variable, which fails if it is `void`.
+                let mut probe = body.clone();
+                probe.push(Box::new(Stmt::Var {
+                    name: SpannedToken { token: Token::Ident("it".to_string()), span: expr.span() },
+                    ty: None,
+                    initializer: Some(expr.clone()),
+                }));
+                let mut program = declarations.clone();
+                program.push(function(&name, span, probe, Type::Void));
+                match check(&mut program) {
+                    Ok(()) => match program.last().map(|stmt| stmt.as_ref()) {
+                        Some(Stmt::Function { body, .. }) => match body.last().map(|stmt| stmt.as_ref()) {
+                            Some(Stmt::Var { ty, .. }) => checked(ty),
+                            _ => unreachable!("the probe ends with its variable"),
+                        },
+                        _ => unreachable!("the probe is the last function"),
+                    },
+                    Err(_) => Type::Void,
+                }
+            },
+            None => Type::Void,
+        };
+        match last {
+            Some(expr) if ret == Type::Void => body.push(Box::new(Stmt::Expr(expr))),
+            Some(expr) => body.push(Box::new(Stmt::Return {
+                keyword: SpannedToken { token: Token::Return, span: expr.span() },
+                value: Some(expr),
+            })),
+            None => {},
+        }
+
+        let mut program = declarations.clone();
+        program.push(function(&name, span, body, ret.clone()));
+        check(&mut program)?;
+        self.declarations = declarations;
+        execute(&program, &name, &ret).map_err(|err| vec![err])
+    }
+}
+
+/// Returns what a declaration declares, as functions and externs share one
+/// namespace and structs another.
+fn declared(stmt: &Stmt) -> Option<(bool, String)> {
+    match stmt {
+        Stmt::Function { name, .. } | Stmt::Extern { name, .. } => Some((false, name.token.to_string())),
+        Stmt::Struct { name, .. } => Some((true, name.token.to_string())),
+        _ => None,
+    }
+}
+
+fn function(name: &str, span: Span, body: Vec<Box<Stmt>>, ret: Type) -> Box<Stmt> {
+    Box::new(Stmt::Function {
+        name: SpannedToken { token: Token::Ident(name.to_string()), span },
+        params: vec![],
+        ret: Some(ret),
+        body,
+        exported: false,
+    })
+}
+
+/// Resolves and type checks a program, and checks the REPL provides each
+/// of its externs.
+fn check(statements: &mut [Box<Stmt>]) -> Result<(), Vec<CompileError>> {
+    resolver::resolve(statements)?;
+    type_checker::check(statements)?;
+    let errors = statements
+        .iter()
+        .filter_map(|stmt| match stmt.as_ref() {
+            Stmt::Extern { name, params, ret } => {
+                let params = params.iter().map(|param| checked(&param.ty)).collect::<Vec<Type>>();
+                let address = host_address(&name.token.to_string(), &params, &checked(ret));
+                address.err().map(|message| CompileError::new(message, name.span))
+            },
+            _ => None,
+        })
+        .collect::<Vec<CompileError>>();
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors)
+}
+
+/// Compiles a type checked program to native code and calls the function of
+/// the given name.
+///
+/// Strings, arrays, structs and closures are laid out for wasm32 and
+/// allocated by growing wasm memory, so code needing the runtime to work on
+/// them is rejected. String literals are laid out the same natively, so they
+/// can still be printed and returned.
+///
+/// Dividing an integer by zero, or the smallest one by -1, raises a hardware
+/// exception in native code that would end the session. So programs that
+/// divide integers are still compiled, to reject the same code, but run with
+/// the interpreter, which reports those as errors.
+fn execute(statements: &[Box<Stmt>], name: &str, ret: &Type) -> Result<Value, CompileError> {
+    let context = Context::create();
+    let module = context.create_module("repl");
+    let builder = context.create_builder();
+    let fpm = function_passes(&module);
+    Translator::translate(&context, &builder, &fpm, &module, statements)?;
+
+    // The runtime only builds for wasm32, so drop the parts nothing calls.
+    let passes = PassManager::create(());
+    passes.add_global_dce_pass();
+    passes.run_on(&module);
+    if module.get_functions().any(|function| function.get_name().to_bytes().starts_with(b"__mai_")) {
+        return Err(CompileError::unspanned(
+            "the REPL can't run code allocating strings, arrays, structs or closures",
+        ));
+    }
+    if divides(statements) {
+        return Interpreter::new(statements).call(name, vec![]);
+    }
+
+    let engine = module
+        .create_jit_execution_engine(OptimizationLevel::None)
+        .map_err(|err| CompileError::unspanned(err.to_string()))?;
+    for stmt in statements.iter() {
+        let Stmt::Extern { name, params, ret } = stmt.as_ref() else {
+            continue;
+        };
+        let params = params.iter().map(|param| checked(&param.ty)).collect::<Vec<Type>>();
+        let address = host_address(&name.token.to_string(), &params, &checked(ret))
+            .map_err(|message| CompileError::new(message, name.span))?;
+        if let Some(function) = module.get_function(&name.token.to_string()) {
+            engine.add_global_mapping(&function, address);
+        }
+    }
+
+    let lookup = |err| CompileError::unspanned(format!("cannot find `{}` in the compiled module: {}", name, err));
+    let value = unsafe {
+        match ret {
+            Type::I32 => Value::I32(engine.get_function::<unsafe extern "C" fn() -> i32>(name).map_err(lookup)?.call()),
+            Type::I64 => Value::I64(engine.get_function::<unsafe extern "C" fn() -> i64>(name).map_err(lookup)?.call()),
+            Type::F64 => Value::F64(engine.get_function::<unsafe extern "C" fn() -> f64>(name).map_err(lookup)?.call()),
+            // Only the lowest bit of an `i1` is defined.
+            Type::Bool => {
+                let result = engine.get_function::<unsafe extern "C" fn() -> u8>(name).map_err(lookup)?.call();
+                Value::Bool(result & 1 != 0)
+            },
+            Type::Str => {
+                let result = engine.get_function::<unsafe extern "C" fn() -> *const u8>(name).map_err(lookup)?.call();
+                Value::Str(read_str(result))
+            },
+            Type::Void => {
+                engine.get_function::<unsafe extern "C" fn()>(name).map_err(lookup)?.call();
+                Value::Void
+            },
+            ty => return Err(CompileError::unspanned(format!("the REPL can't show values of type `{}`", ty))),
+        }
+    };
+    Ok(value)
+}
+
+/// Whether a type checked program divides integers anywhere, including in
+/// lambdas.
+fn divides(statements: &[Box<Stmt>]) -> bool {
+    statements.iter().any(|stmt| match stmt.as_ref() {
+        Stmt::Block(body) | Stmt::Function { body, .. } => divides(body),
+        Stmt::Expr(expr) | Stmt::Return { value: Some(expr), .. } | Stmt::Var { initializer: Some(expr), .. } => {
+            divides_in(expr)
+        },
+        Stmt::If { cond, then_branch, else_branch } => {
+            divides_in(cond) || divides(std::slice::from_ref(then_branch)) || else_branch.as_ref().is_some_and(|branch| divides(std::slice::from_ref(branch)))
+        },
+        Stmt::While { condition, body } => divides_in(condition) || divides(std::slice::from_ref(body)),
+        _ => false,
+    })
+}
+
+fn divides_in(expr: &Expr) -> bool {
+    match expr {
+        Expr::BinaryExpr { op, ty: Some(ty), .. } if op.token == Token::Div && ty.is_integer() => true,
+        Expr::Lambda { body, .. } => divides(body),
+        expr => expr.subexpressions().into_iter().any(divides_in),
+    }
+}
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Returns the address of the native function providing a host function,
+/// which only supports printing scalars and strings.
+fn host_address(name: &str, params: &[Type], ret: &Type) -> Result<usize, String> {
+    let address = match (HostFunction::resolve(name, params, ret)?, params) {
+        (HostFunction::Print, [Type::I32]) => print_i32 as usize,
+        (HostFunction::Print, [Type::I64]) => print_i64 as usize,
+        (HostFunction::Print, [Type::F64]) => print_f64 as usize,
+        (HostFunction::Print, [Type::Bool]) => print_bool as usize,
+        (HostFunction::Print, [Type::Str]) => print_str as usize,
+        (HostFunction::Print, [ty]) => return Err(format!("the REPL can't print values of type `{}`", ty)),
+        (HostFunction::Clock, _) => clock as usize,
+        // Native code has no trap to unwind from, so stopping the program
+        // would end the session.
+        (HostFunction::Abort, _) => return Err("host function `abort` is not available in the REPL".to_string()),
+        (HostFunction::Print, _) => unreachable!("`print` takes one argument"),
+    };
+    Ok(address)
+}
+
+extern "C" fn print_i32(value: i32) {
+    println!("{}", Value::I32(value));
+}
+
+extern "C" fn print_i64(value: i64) {
+    println!("{}", Value::I64(value));
+}
+
+extern "C" fn print_f64(value: f64) {
+    println!("{}", Value::F64(value));
+}
+
+extern "C" fn print_bool(value: u8) {
+    println!("{}", Value::Bool(value & 1 != 0));
+}
+
+extern "C" fn print_str(s: *const u8) {
+    println!("{}", Value::Str(unsafe { read_str(s) }));
+}
+
+extern "C" fn clock() -> i64 {
+    START.get_or_init(Instant::now).elapsed().as_nanos() as i64
+}
+
+/// Reads a string laid out as its 32-bit length followed by its bytes.
+unsafe fn read_str(s: *const u8) -> String {
+    let len = (s as *const u32).read_unaligned() as usize;
+    let bytes = std::slice::from_raw_parts(s.add(4), len);
+    String::from_utf8_lossy(bytes).into_owned()
+}
@@ -0,0 +1,222 @@
+use std::collections::{HashMap,HashSet};
+
+use crate::builtins::Builtin;
+use crate::diagnostic::CompileError;
+use crate::parser::*;
+use crate::token::{Span,SpannedToken};
+
+/// Resolves every variable of a program to the scope it is declared in,
+/// filling in the depth of each variable reference and assignment for the
+/// backends.
+///
+/// Functions, blocks and the bodies of `if` and `while` each open a scope,
+/// and a variable can shadow one of the same name in an enclosing scope. A
+/// variable is only in scope after its declaration, so `var x = x;` refers
+/// to an `x` declared further out.
+pub fn resolve(statements: &mut [Box<Stmt>]) -> Result<(), Vec<CompileError>> {
+    let (_, errors) = references(statements);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors)
+}
+
+/// A use of a variable or function, along with the name it refers to in its
+/// declaration.
+#[derive(Debug,Clone,Copy)]
+pub struct Reference {
+    pub span: Span,
+    pub declaration: Span,
+}
+
+/// Resolves a program like `resolve`, returning every reference to a
+/// variable or a declared function along with any errors.
+pub fn references(statements: &mut [Box<Stmt>]) -> (Vec<Reference>, Vec<CompileError>) {
+    let functions = statements
+        .iter()
+        .filter_map(|stmt| match stmt.as_ref() {
+            Stmt::Function { name, .. } | Stmt::Extern { name, .. } => Some((name.token.to_string(), name.span)),
+            _ => None,
+        })
+        .collect();
+    let mut resolver = Resolver {
+        functions,
+        scopes: vec![],
+        boundaries: vec![],
+        references: vec![],
+        errors: vec![],
+    };
+    for stmt in statements.iter_mut() {
+        match stmt.as_mut() {
+            Stmt::Function { params, body, .. } => resolver.function(params, body),
+            Stmt::Return { keyword, .. } => resolver.errors.push(CompileError::new(
+                "`return` outside of a function",
+                keyword.span,
+            )),
+            // Anything else at the top level is reported by the type checker.
+            _ => {},
+        }
+    }
+    (resolver.references, resolver.errors)
+}
+
+#[derive(Default)]
+struct Scope {
+    // Variables declared so far, along with their names in the declaration.
+    declared: HashMap<String, Span>,
+    // Every variable declared directly in the scope, including those further
+    // down, to tell a variable used before its declaration from a typo.
+    all: HashSet<String>,
+}
+
+struct Resolver {
+    functions: HashMap<String, Span>,
+    scopes: Vec<Scope>,
+    // Index of the outermost scope of every function being resolved, which
+    // holds its parameters. Lambdas nest within the function around them.
+    boundaries: Vec<usize>,
+    references: Vec<Reference>,
+    errors: Vec<CompileError>,
+}
+
+impl Resolver {
+    fn function(&mut self, params: &[Param], body: &mut [Box<Stmt>]) {
+        let mut scope = Scope::default();
+        for param in params.iter() {
+            let id = param.name.token.to_string();
+            if scope.declared.insert(id.clone(), param.name.span).is_some() {
+                self.errors.push(CompileError::new(
+                    format!("parameter `{}` is declared multiple times", id),
+                    param.name.span,
+                ));
+            }
+        }
+        self.boundaries.push(self.scopes.len());
+        self.scopes.push(scope);
+        self.block(body);
+        self.scopes.pop();
+        self.boundaries.pop();
+    }
+
+    fn block(&mut self, statements: &mut [Box<Stmt>]) {
+        let all = statements
+            .iter()
+            .filter_map(|stmt| match stmt.as_ref() {
+                Stmt::Var { name, .. } => Some(name.token.to_string()),
+                _ => None,
+            })
+            .collect();
+        self.scopes.push(Scope { declared: HashMap::new(), all });
+        for stmt in statements.iter_mut() {
+            self.stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::Expr(expr) => self.expr(expr),
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            },
+            Stmt::If { cond, then_branch, else_branch } => {
+                self.expr(cond);
+                self.block(std::slice::from_mut(then_branch));
+                if let Some(else_branch) = else_branch {
+                    self.block(std::slice::from_mut(else_branch));
+                }
+            },
+            Stmt::While { condition, body } => {
+                self.expr(condition);
+                self.block(std::slice::from_mut(body));
+            },
+            Stmt::Var { name, initializer, .. } => {
+                if let Some(initializer) = initializer {
+                    self.expr(initializer);
+                }
+                self.scopes.last_mut().unwrap().declared.insert(name.token.to_string(), name.span);
+            },
+            // Nested declarations and imports are reported by the type checker.
+            Stmt::Function { .. } | Stmt::Extern { .. } | Stmt::Struct { .. } | Stmt::Import { .. } => {},
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Variable { name, depth } => *depth = self.lookup(name),
+            Expr::Assign { name, value, depth } => {
+                self.expr(value);
+                *depth = self.lookup(name);
+            },
+            Expr::Lambda { params, body, .. } => self.function(params, body),
+            Expr::BinaryExpr { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            },
+            Expr::UnaryExpr { right, .. } => self.expr(right),
+            Expr::Grouping { expr } => self.expr(expr),
+            Expr::Literal { .. } | Expr::Str { .. } => {},
+            Expr::Array { elements, .. } => {
+                for element in elements.iter_mut() {
+                    self.expr(element);
+                }
+            },
+            Expr::Struct { fields, .. } => {
+                for (_, value) in fields.iter_mut() {
+                    self.expr(value);
+                }
+            },
+            Expr::Get { object, .. } => self.expr(object),
+            Expr::Set { object, value, .. } => {
+                self.expr(object);
+                self.expr(value);
+            },
+            Expr::Index { target, index, .. } => {
+                self.expr(target);
+                self.expr(index);
+            },
+            Expr::IndexAssign { target, index, value, .. } => {
+                self.expr(target);
+                self.expr(index);
+                self.expr(value);
+            },
+            Expr::Call { callee, args, .. } => {
+                self.expr(callee);
+                for arg in args.iter_mut() {
+                    self.expr(arg);
+                }
+            },
+        }
+    }
+
+    // Returns how many scopes out a variable is declared, or `None` if the
+    // name refers to a function.
+    fn lookup(&mut self, name: &SpannedToken) -> Option<usize> {
+        let id = name.token.to_string();
+        if let Some(index) = self.scopes.iter().rposition(|scope| scope.declared.contains_key(&id)) {
+            let declaration = self.scopes[index].declared[&id];
+            self.references.push(Reference { span: name.span, declaration });
+            // Variables of enclosing functions are captured, which copies
+            // them into the outermost scope of the closure.
+            let boundary = *self.boundaries.last().unwrap();
+            return Some(self.scopes.len() - 1 - index.max(boundary));
+        }
+        if let Some(declaration) = self.functions.get(&id) {
+            self.references.push(Reference { span: name.span, declaration: *declaration });
+            return None;
+        }
+        if Builtin::from_name(&id).is_some() {
+            return None;
+        }
+        let message = if self.scopes.iter().any(|scope| scope.all.contains(&id)) {
+            format!("cannot use `{}` before it is declared", id)
+        } else {
+            format!("cannot find variable `{}` in this scope", id)
+        };
+        self.errors.push(CompileError::new(message, name.span));
+        None
+    }
+}
@@ -0,0 +1,200 @@
+; Support functions linked into every compiled mai module.
+;
+; Strings are pointers to a 32-bit length followed by that many bytes. Arrays
+; are pointers to a header holding their length, capacity and elements, so
+; they can grow without moving the header other values refer to. Both start
+; with their length, which lets `len` work on either. Memory is allocated by
+; bumping a pointer upwards from `__heap_base`, growing the wasm memory as
+; needed, and is never freed.
+
+%array = type { i32, i32, i8* }
+
+@__heap_base = external addrspace(1) global i32
+@__mai_heap_top = internal global i32 0
+
+declare i32 @llvm.wasm.memory.size.i32(i32)
+declare i32 @llvm.wasm.memory.grow.i32(i32, i32)
+declare void @llvm.trap()
+
+; Allocates `size` bytes aligned to 8 bytes, trapping if memory can't grow.
+define internal i8* @__mai_alloc(i32 %size) {
+entry:
+  %top = load i32, i32* @__mai_heap_top
+  %base = load i32, i32 addrspace(1)* @__heap_base
+  %started = icmp ne i32 %top, 0
+  %current = select i1 %started, i32 %top, i32 %base
+  %bumped = add i32 %current, 7
+  %start = and i32 %bumped, -8
+  %end = add i32 %start, %size
+  %wrapped = icmp ult i32 %end, %start
+  br i1 %wrapped, label %oom, label %check
+
+check:
+  store i32 %end, i32* @__mai_heap_top
+  %pages = call i32 @llvm.wasm.memory.size.i32(i32 0)
+  %limit = shl i32 %pages, 16
+  %fits = icmp ule i32 %end, %limit
+  br i1 %fits, label %done, label %grow
+
+grow:
+  %missing = sub i32 %end, %limit
+  %rounded = add i32 %missing, 65535
+  %delta = lshr i32 %rounded, 16
+  %previous = call i32 @llvm.wasm.memory.grow.i32(i32 0, i32 %delta)
+  %failed = icmp eq i32 %previous, -1
+  br i1 %failed, label %oom, label %done
+
+oom:
+  call void @llvm.trap()
+  unreachable
+
+done:
+  %ptr = inttoptr i32 %start to i8*
+  ret i8* %ptr
+}
+
+; Copies `len` bytes between regions which don't overlap. Marked as
+; `no-builtins` so the loop is never turned into a call to an imported memcpy.
+define internal void @__mai_copy(i8* %dst, i8* %src, i32 %len) #0 {
+entry:
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %entry ], [ %next, %body ]
+  %more = icmp ult i32 %i, %len
+  br i1 %more, label %body, label %done
+
+body:
+  %from = getelementptr i8, i8* %src, i32 %i
+  %to = getelementptr i8, i8* %dst, i32 %i
+  %byte = load i8, i8* %from
+  store i8 %byte, i8* %to
+  %next = add i32 %i, 1
+  br label %loop
+
+done:
+  ret void
+}
+
+; Returns the length of a string or an array.
+define internal i32 @__mai_len(i8* %s) {
+entry:
+  %header = bitcast i8* %s to i32*
+  %len = load i32, i32* %header, align 4
+  ret i32 %len
+}
+
+define internal i8* @__mai_str_concat(i8* %a, i8* %b) {
+entry:
+  %a_len = call i32 @__mai_len(i8* %a)
+  %b_len = call i32 @__mai_len(i8* %b)
+  %len = add i32 %a_len, %b_len
+  %size = add i32 %len, 4
+  %s = call i8* @__mai_alloc(i32 %size)
+  %header = bitcast i8* %s to i32*
+  store i32 %len, i32* %header, align 4
+  %dst = getelementptr i8, i8* %s, i32 4
+  %a_bytes = getelementptr i8, i8* %a, i32 4
+  call void @__mai_copy(i8* %dst, i8* %a_bytes, i32 %a_len)
+  %b_dst = getelementptr i8, i8* %dst, i32 %a_len
+  %b_bytes = getelementptr i8, i8* %b, i32 4
+  call void @__mai_copy(i8* %b_dst, i8* %b_bytes, i32 %b_len)
+  ret i8* %s
+}
+
+; Allocates an array of `len` elements of `size` bytes each, which the caller
+; initializes.
+define internal i8* @__mai_array_new(i32 %len, i32 %size) {
+entry:
+  %header = call i8* @__mai_alloc(i32 12)
+  %bytes = call i32 @__mai_array_bytes(i32 %len, i32 %size)
+  %data = call i8* @__mai_alloc(i32 %bytes)
+  %array = bitcast i8* %header to %array*
+  %len_ptr = getelementptr %array, %array* %array, i32 0, i32 0
+  store i32 %len, i32* %len_ptr
+  %cap_ptr = getelementptr %array, %array* %array, i32 0, i32 1
+  store i32 %len, i32* %cap_ptr
+  %data_ptr = getelementptr %array, %array* %array, i32 0, i32 2
+  store i8* %data, i8** %data_ptr
+  ret i8* %header
+}
+
+; Returns the size in bytes of `len` elements of `size` bytes, trapping if it
+; doesn't fit in memory.
+define internal i32 @__mai_array_bytes(i32 %len, i32 %size) {
+entry:
+  %wide_len = zext i32 %len to i64
+  %wide_size = zext i32 %size to i64
+  %bytes = mul i64 %wide_len, %wide_size
+  %too_large = icmp ugt i64 %bytes, 4294967295
+  br i1 %too_large, label %oom, label %done
+
+oom:
+  call void @llvm.trap()
+  unreachable
+
+done:
+  %result = trunc i64 %bytes to i32
+  ret i32 %result
+}
+
+; Returns a pointer to the element at `index`, trapping if it is out of bounds.
+; Negative indices wrap around to large unsigned ones, so they trap too.
+define internal i8* @__mai_array_index(i8* %header, i64 %index, i32 %size) {
+entry:
+  %array = bitcast i8* %header to %array*
+  %len_ptr = getelementptr %array, %array* %array, i32 0, i32 0
+  %len = load i32, i32* %len_ptr
+  %wide_len = zext i32 %len to i64
+  %in_bounds = icmp ult i64 %index, %wide_len
+  br i1 %in_bounds, label %found, label %out_of_bounds
+
+out_of_bounds:
+  call void @llvm.trap()
+  unreachable
+
+found:
+  %data_ptr = getelementptr %array, %array* %array, i32 0, i32 2
+  %data = load i8*, i8** %data_ptr
+  %narrow = trunc i64 %index to i32
+  %offset = mul i32 %narrow, %size
+  %element = getelementptr i8, i8* %data, i32 %offset
+  ret i8* %element
+}
+
+; Appends an element of `size` bytes, doubling the capacity when it runs out,
+; and returns a pointer to it for the caller to store the value.
+define internal i8* @__mai_array_push(i8* %header, i32 %size) {
+entry:
+  %array = bitcast i8* %header to %array*
+  %len_ptr = getelementptr %array, %array* %array, i32 0, i32 0
+  %len = load i32, i32* %len_ptr
+  %cap_ptr = getelementptr %array, %array* %array, i32 0, i32 1
+  %cap = load i32, i32* %cap_ptr
+  %data_ptr = getelementptr %array, %array* %array, i32 0, i32 2
+  %full = icmp eq i32 %len, %cap
+  br i1 %full, label %grow, label %append
+
+grow:
+  %empty = icmp eq i32 %cap, 0
+  %doubled = shl i32 %cap, 1
+  %new_cap = select i1 %empty, i32 4, i32 %doubled
+  %new_bytes = call i32 @__mai_array_bytes(i32 %new_cap, i32 %size)
+  %new_data = call i8* @__mai_alloc(i32 %new_bytes)
+  %old_data = load i8*, i8** %data_ptr
+  %old_bytes = mul i32 %len, %size
+  call void @__mai_copy(i8* %new_data, i8* %old_data, i32 %old_bytes)
+  store i8* %new_data, i8** %data_ptr
+  store i32 %new_cap, i32* %cap_ptr
+  br label %append
+
+append:
+  %next = add i32 %len, 1
+  store i32 %next, i32* %len_ptr
+  %data = load i8*, i8** %data_ptr
+  %offset = mul i32 %len, %size
+  %element = getelementptr i8, i8* %data, i32 %offset
+  ret i8* %element
+}
+
+attributes #0 = { "no-builtins" }
@@ -0,0 +1,209 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
+
+use wasmer::{Function,FunctionEnv,FunctionEnvMut,FunctionType,Imports,Memory,MemoryView,RuntimeError,Store};
+
+use crate::diagnostic::CompileError;
+use crate::interp::Value;
+use crate::parser::Stmt;
+use crate::types::{checked,structs,StructDef,Type};
+
+/// A function the host provides to mai programs, declared in the program
+/// with `extern fun`.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum HostFunction {
+    /// `extern fun print(value: T);` prints a value of whichever type it is
+    /// declared with on its own line.
+    Print,
+    /// `extern fun clock() -> i64;` returns the nanoseconds elapsed since the
+    /// program started.
+    Clock,
+    /// `extern fun abort(code: i32);` stops the program with an error.
+    Abort,
+}
+
+impl HostFunction {
+    /// Finds the host function an extern declaration refers to, checking it
+    /// is declared with a signature the host supports.
+    pub fn resolve(name: &str, params: &[Type], ret: &Type) -> Result<HostFunction, String> {
+        let (function, supported) = match name {
+            "print" => (HostFunction::Print, params.len() == 1 && *ret == Type::Void),
+            "clock" => (HostFunction::Clock, params.is_empty() && *ret == Type::I64),
+            "abort" => (HostFunction::Abort, params == [Type::I32] && *ret == Type::Void),
+            _ => return Err(format!("cannot find host function `{}`", name)),
+        };
+        if !supported {
+            return Err(format!("host function `{}` must be declared as `{}`", name, function.declaration()));
+        }
+        Ok(function)
+    }
+
+    fn declaration(&self) -> &'static str {
+        match self {
+            HostFunction::Print => "extern fun print(value: T);",
+            HostFunction::Clock => "extern fun clock() -> i64;",
+            HostFunction::Abort => "extern fun abort(code: i32);",
+        }
+    }
+}
+
+/// State shared by the host functions of a running program.
+pub struct Host {
+    start: Instant,
+    /// Memory of the wasm instance calling into the host, set once it has
+    /// been instantiated so strings can be read out of it.
+    pub memory: Option<Memory>,
+}
+
+impl Host {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), memory: None }
+    }
+
+    /// Calls a host function with arguments matching its declaration.
+    pub fn call(&mut self, function: HostFunction, args: &[Value]) -> Result<Value, String> {
+        match function {
+            HostFunction::Print => {
+                println!("{}", args[0]);
+                Ok(Value::Void)
+            },
+            HostFunction::Clock => Ok(Value::I64(self.start.elapsed().as_nanos() as i64)),
+            HostFunction::Abort => Err(format!("program aborted with code {}", args[0])),
+        }
+    }
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn wasm_type(ty: &Type) -> Option<wasmer::Type> {
+    match ty {
+        // Strings, arrays and structs are passed as pointers into linear memory,
+        // and closures as pointers to their function table index.
+        Type::I32 | Type::Bool | Type::Str | Type::Array(_) | Type::Struct(_) | Type::Function { .. } => {
+            Some(wasmer::Type::I32)
+        },
+        Type::I64 => Some(wasmer::Type::I64),
+        Type::F64 => Some(wasmer::Type::F64),
+        Type::Void => None,
+    }
+}
+
+/// Converts a scalar value into its representation in wasm, which has no
+/// booleans. Strings, arrays and structs live in linear memory and closures
+/// in the function table, so they can't be converted.
+pub fn to_wasm(value: &Value) -> Option<wasmer::Value> {
+    match value {
+        Value::I32(v) => Some(wasmer::Value::I32(*v)),
+        Value::I64(v) => Some(wasmer::Value::I64(*v)),
+        Value::F64(v) => Some(wasmer::Value::F64(*v)),
+        Value::Bool(v) => Some(wasmer::Value::I32(*v as i32)),
+        Value::Str(_) | Value::Array(_) | Value::Struct(..) | Value::Function(_) | Value::Void => None,
+    }
+}
+
+/// Converts a wasm value back into a value of the given type, reading
+/// strings, arrays and structs out of the instance's memory.
+pub fn from_wasm(
+    ty: &Type,
+    value: &wasmer::Value,
+    memory: &MemoryView,
+    structs: &HashMap<String, StructDef>,
+) -> Option<Value> {
+    match (ty, value) {
+        (Type::I32, wasmer::Value::I32(v)) => Some(Value::I32(*v)),
+        (Type::I64, wasmer::Value::I64(v)) => Some(Value::I64(*v)),
+        (Type::F64, wasmer::Value::F64(v)) => Some(Value::F64(*v)),
+        (Type::Bool, wasmer::Value::I32(v)) => Some(Value::Bool(*v != 0)),
+        (Type::Str, wasmer::Value::I32(ptr)) => read_str(memory, *ptr as u32).map(Value::Str),
+        (Type::Array(elem), wasmer::Value::I32(ptr)) => read_array(memory, structs, elem, *ptr as u32),
+        (Type::Struct(name), wasmer::Value::I32(ptr)) => {
+            let def = structs.get(name)?;
+            let (offsets, _) = def.layout();
+            let mut fields = Vec::with_capacity(def.fields.len());
+            for ((field, ty), offset) in def.fields.iter().zip(offsets) {
+                fields.push((field.clone(), read_value(memory, structs, ty, *ptr as u32 + offset)?));
+            }
+            Some(Value::Struct(name.clone(), Rc::new(RefCell::new(fields))))
+        },
+        _ => None,
+    }
+}
+
+// Reads a value stored in memory as an array element or struct field.
+fn read_value(memory: &MemoryView, structs: &HashMap<String, StructDef>, ty: &Type, ptr: u32) -> Option<Value> {
+    let mut bytes = vec![0; ty.size() as usize];
+    memory.read(ptr as u64, &mut bytes).ok()?;
+    let value = match ty {
+        Type::I64 => wasmer::Value::I64(i64::from_le_bytes(bytes.try_into().ok()?)),
+        Type::F64 => wasmer::Value::F64(f64::from_le_bytes(bytes.try_into().ok()?)),
+        Type::Bool => wasmer::Value::I32(bytes[0] as i32),
+        _ => wasmer::Value::I32(i32::from_le_bytes(bytes.try_into().ok()?)),
+    };
+    from_wasm(ty, &value, memory, structs)
+}
+
+// Reads an array laid out as a header holding its length, capacity and a
+// pointer to its elements.
+fn read_array(memory: &MemoryView, structs: &HashMap<String, StructDef>, elem: &Type, ptr: u32) -> Option<Value> {
+    let mut header = [0; 12];
+    memory.read(ptr as u64, &mut header).ok()?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let data = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let mut elements = Vec::with_capacity(len as usize);
+    for i in 0..len {
+        elements.push(read_value(memory, structs, elem, data + i * elem.size())?);
+    }
+    Some(Value::array(elements))
+}
+
+// Reads a string laid out as a 32-bit length followed by its bytes.
+fn read_str(memory: &MemoryView, ptr: u32) -> Option<String> {
+    let mut len = [0; 4];
+    memory.read(ptr as u64, &mut len).ok()?;
+    let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+    memory.read(ptr as u64 + 4, &mut bytes).ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Provides every extern declared by a type checked program as an import of
+/// its compiled module.
+pub fn imports(store: &mut Store, env: &FunctionEnv<Host>, statements: &[Box<Stmt>]) -> Result<Imports, CompileError> {
+    let mut imports = Imports::new();
+    let defs = structs(statements);
+    for stmt in statements.iter() {
+        let Stmt::Extern { name, params, ret } = stmt.as_ref() else {
+            continue;
+        };
+        let fn_name = name.token.to_string();
+        let params = params.iter().map(|param| checked(&param.ty)).collect::<Vec<Type>>();
+        let ret = checked(ret);
+        let function = HostFunction::resolve(&fn_name, &params, &ret)
+            .map_err(|message| CompileError::new(message, name.span))?;
+
+        let ty = FunctionType::new(
+            params.iter().filter_map(wasm_type).collect::<Vec<wasmer::Type>>(),
+            wasm_type(&ret).into_iter().collect::<Vec<wasmer::Type>>(),
+        );
+        let defs = defs.clone();
+        let host_fn = move |mut env: FunctionEnvMut<Host>, args: &[wasmer::Value]| {
+            let (host, store) = env.data_and_store_mut();
+            let memory = host.memory.clone().ok_or_else(|| RuntimeError::new("host called before instantiation"))?;
+            let view = memory.view(&store);
+            let args = params
+                .iter()
+                .zip(args.iter())
+                .map(|(ty, arg)| from_wasm(ty, arg, &view, &defs).ok_or_else(|| RuntimeError::new("invalid argument")))
+                .collect::<Result<Vec<Value>, RuntimeError>>()?;
+            let result = host.call(function, &args).map_err(RuntimeError::new)?;
+            Ok(to_wasm(&result).into_iter().collect())
+        };
+        imports.define("env", &fn_name, Function::new_with_env(store, env, ty, host_fn));
+    }
+    Ok(imports)
+}
@@ -1,9 +1,12 @@
+use std::fmt;
+
+use serde::Serialize;
+
 /// Represents a primitive syntax token.
-#[derive(Debug,Clone,PartialEq)]
+#[derive(Debug,Clone,PartialEq,Serialize)]
 pub enum Token {
     // Equalty and comparison operations.
     Eq,
-    Neq,
     Eqq,
     BangEq,
     Greater,
@@ -21,24 +24,124 @@ pub enum Token {
     LParen,
     RParen,
     Comma,
+    Dot,
     Semicolon,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
+    // Type annotations.
+    Colon,
+    Arrow,
     // Literals and identifiers.
     If,
     While,
     For,
     Fun,
     Return,
+    Export,
+    Extern,
+    Struct,
+    Import,
     Or,
     And,
     Else,
     True,
     False,
+    // A number literal in plain decimal, whatever base it was written in.
     Number(String),
+    // The decoded contents of a string literal.
+    Str(String),
     Ident(String),
+    // A `//` comment running to the end of the line, or a `/* */` one
+    // spanning any number of lines. Only lexers created with
+    // `TokenLexer::with_trivia` produce them, for tools such as the
+    // formatter that keep comments.
+    Comment(String),
     Var,
     Wagmi,
     EOF,
 }
 
+impl Token {
+    /// Describes the token for use in error messages, such as
+    /// "expected `;`, found end of file".
+    pub fn describe(&self) -> String {
+        match self {
+            Token::EOF => "end of file".to_string(),
+            tok => format!("`{}`", tok),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Token::Str(value) => return write!(f, "{:?}", value),
+            Token::Eq => "=",
+            Token::Eqq => "==",
+            Token::BangEq => "!=",
+            Token::Greater => ">",
+            Token::Less => "<",
+            Token::Geq => ">=",
+            Token::Leq => "<=",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Times => "*",
+            Token::Div => "/",
+            Token::Bang => "!",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Semicolon => ";",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::Colon => ":",
+            Token::Arrow => "->",
+            Token::If => "if",
+            Token::While => "while",
+            Token::For => "for",
+            Token::Fun => "fun",
+            Token::Return => "return",
+            Token::Export => "export",
+            Token::Extern => "extern",
+            Token::Struct => "struct",
+            Token::Import => "import",
+            Token::Or => "or",
+            Token::And => "and",
+            Token::Else => "else",
+            Token::True => "true",
+            Token::False => "false",
+            Token::Number(n) => n.as_str(),
+            Token::Ident(name) => name.as_str(),
+            Token::Comment(text) => text.as_str(),
+            Token::Var => "var",
+            Token::Wagmi => "wagmi",
+            Token::EOF => "",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Location of a token within the source input.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default,Serialize)]
+pub struct Span {
+    /// Byte offset of the first character.
+    pub offset: usize,
+    /// Length in bytes.
+    pub len: usize,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column, counted in characters.
+    pub column: usize,
+}
+
+/// A token annotated with where it was found in the source.
+#[derive(Debug,Clone,PartialEq,Serialize)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
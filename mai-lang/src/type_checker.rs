@@ -0,0 +1,778 @@
+use std::collections::{HashMap,HashSet};
+
+use crate::builtins::Builtin;
+use crate::diagnostic::CompileError;
+use crate::parser::*;
+use crate::token::{Span,SpannedToken,Token};
+use crate::types::{integer_literal,is_integer_literal,StructDef,Type};
+
+/// The parameter and return types of a declared function.
+#[derive(Debug,Clone)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub ret: Type,
+}
+
+/// Type checks a program, filling in every type the source left out: missing
+/// parameter annotations default to `f64`, missing return annotations default
+/// to `f64` if the function returns a value and `void` otherwise, locals are
+/// inferred from their initializers and integer literals take the type their
+/// context expects. Struct fields default to `f64` like parameters do.
+pub fn check(statements: &mut [Box<Stmt>]) -> Result<(), Vec<CompileError>> {
+    let mut checker = TypeChecker {
+        functions: HashMap::new(),
+        structs: HashMap::new(),
+        scopes: vec![],
+        closures: vec![],
+        current_return: None,
+        errors: vec![],
+    };
+
+    // Structs are collected before anything else, so any signature can
+    // refer to them.
+    for stmt in statements.iter_mut() {
+        let Stmt::Struct { name, fields } = stmt.as_mut() else {
+            continue;
+        };
+        let struct_name = name.token.to_string();
+        if Type::from_name(&struct_name).is_some() {
+            checker.errors.push(CompileError::new(
+                format!("cannot declare a struct named after the builtin type `{}`", struct_name),
+                name.span,
+            ));
+        }
+        let mut def = StructDef { name: struct_name.clone(), fields: vec![] };
+        for field in fields.iter_mut() {
+            let field_name = field.name.token.to_string();
+            if def.field(&field_name).is_some() {
+                checker.errors.push(CompileError::new(
+                    format!("field `{}` is already declared", field_name),
+                    field.name.span,
+                ));
+            }
+            def.fields.push((field_name, field.ty.get_or_insert(Type::F64).clone()));
+        }
+        if checker.structs.insert(struct_name.clone(), def).is_some() {
+            checker.errors.push(CompileError::new(
+                format!("the struct `{}` is defined multiple times", struct_name),
+                name.span,
+            ));
+        }
+    }
+    for stmt in statements.iter() {
+        let Stmt::Struct { name, fields } = stmt.as_ref() else {
+            continue;
+        };
+        for field in fields.iter() {
+            let ty = field.ty.as_ref().unwrap();
+            if let Err(err) = checker.check_type(ty, field.name.span) {
+                checker.errors.push(err);
+            } else if *ty == Type::Void {
+                checker.errors.push(CompileError::new("fields cannot have type `void`", field.name.span));
+            }
+        }
+        let struct_name = name.token.to_string();
+        if checker.contains(&struct_name, &struct_name, &mut HashSet::new()) {
+            checker.errors.push(CompileError::new(
+                format!("struct `{}` contains itself, so it can never be constructed", struct_name),
+                name.span,
+            ));
+        }
+    }
+
+    // Collect every signature first so functions can be called before they
+    // are declared.
+    for stmt in statements.iter_mut() {
+        let (name, params, ret) = match stmt.as_mut() {
+            Stmt::Function { name, params, ret, body, .. } => {
+                if ret.is_none() {
+                    *ret = Some(if returns_value(body) { Type::F64 } else { Type::Void });
+                }
+                (name, params, ret)
+            },
+            // Without a body to look at, externs return nothing unless annotated.
+            Stmt::Extern { name, params, ret } => {
+                ret.get_or_insert(Type::Void);
+                (name, params, ret)
+            },
+            _ => continue,
+        };
+        for param in params.iter_mut() {
+            if let Err(err) = checker.check_type(param.ty.get_or_insert(Type::F64), param.name.span) {
+                checker.errors.push(err);
+            }
+        }
+        if let Err(err) = checker.check_type(ret.as_ref().unwrap(), name.span) {
+            checker.errors.push(err);
+        }
+        let sig = Signature {
+            params: params.iter().map(|param| param.ty.clone().unwrap()).collect(),
+            ret: ret.clone().unwrap(),
+        };
+        let fn_name = name.token.to_string();
+        if checker.functions.insert(fn_name.clone(), sig).is_some() {
+            checker.errors.push(CompileError::new(
+                format!("the function `{}` is defined multiple times", fn_name),
+                name.span,
+            ));
+        }
+    }
+
+    for stmt in statements.iter_mut() {
+        if let Err(err) = checker.check_function(stmt) {
+            checker.errors.push(err);
+        }
+    }
+
+    if checker.errors.is_empty() {
+        return Ok(());
+    }
+    Err(checker.errors)
+}
+
+/// Returns whether any `return` within the statements produces a value.
+fn returns_value(statements: &[Box<Stmt>]) -> bool {
+    statements.iter().any(|stmt| match stmt.as_ref() {
+        Stmt::Return { value, .. } => value.is_some(),
+        Stmt::Block(body) => returns_value(body),
+        Stmt::If { then_branch, else_branch, .. } => {
+            returns_value(std::slice::from_ref(then_branch))
+                || else_branch
+                    .as_ref()
+                    .map_or(false, |stmt| returns_value(std::slice::from_ref(stmt)))
+        },
+        Stmt::While { body, .. } => returns_value(std::slice::from_ref(body)),
+        _ => false,
+    })
+}
+
+/// Returns whether an expression is an integer literal which has not been
+/// given a type yet, such as `1` or `-(2)`.
+fn is_untyped_literal(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal { value, ty: None, .. } => is_integer_literal(value),
+        Expr::UnaryExpr { op, right } => op.token == Token::Minus && is_untyped_literal(right),
+        Expr::Grouping { expr } => is_untyped_literal(expr),
+        _ => false,
+    }
+}
+
+/// Returns a negated integer literal such as `-2147483648` as a single
+/// literal, as the number on its own may not fit in its type.
+fn negated_literal(expr: &Expr) -> Option<Expr> {
+    let Expr::UnaryExpr { op, right } = expr else {
+        return None;
+    };
+    match right.as_ref() {
+        Expr::Literal { value, ty: None, span } if op.token == Token::Minus && value.chars().all(|ch| ch.is_ascii_digit()) => {
+            Some(Expr::Literal {
+                value: format!("-{}", value),
+                ty: None,
+                span: Span { len: span.offset + span.len - op.span.offset, ..op.span },
+            })
+        },
+        _ => None,
+    }
+}
+
+fn mismatch(expected: &Type, found: &Type, expr: &Expr) -> CompileError {
+    CompileError::new(
+        format!("mismatched types: expected `{}`, found `{}`", expected, found),
+        expr.span(),
+    )
+}
+
+/// An anonymous function being checked, along with the variables it captures.
+struct ClosureScope {
+    // Index of the first scope belonging to the closure, so any variable
+    // found in an earlier scope is captured.
+    boundary: usize,
+    captures: Vec<(String, Type)>,
+}
+
+impl Signature {
+    fn to_type(&self) -> Type {
+        Type::Function { params: self.params.clone(), ret: Box::new(self.ret.clone()) }
+    }
+}
+
+struct TypeChecker {
+    functions: HashMap<String, Signature>,
+    structs: HashMap<String, StructDef>,
+    scopes: Vec<HashMap<String, Type>>,
+    closures: Vec<ClosureScope>,
+    current_return: Option<Type>,
+    errors: Vec<CompileError>,
+}
+
+impl TypeChecker {
+    // Checks every struct a type annotation refers to is declared.
+    fn check_type(&self, ty: &Type, span: Span) -> Result<(), CompileError> {
+        match ty {
+            Type::Array(elem) => self.check_type(elem, span),
+            Type::Function { params, ret } => {
+                params.iter().try_for_each(|param| self.check_type(param, span))?;
+                self.check_type(ret, span)
+            },
+            Type::Struct(name) if !self.structs.contains_key(name) => Err(CompileError::new(
+                format!("cannot find type `{}` in this scope", name),
+                span,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Returns whether a struct holds a value of the target struct, directly
+    // or through the fields of another struct. Arrays can always be empty, so
+    // they don't count.
+    fn contains(&self, name: &str, target: &str, visited: &mut HashSet<String>) -> bool {
+        if !visited.insert(name.to_string()) {
+            return false;
+        }
+        let Some(def) = self.structs.get(name) else {
+            return false;
+        };
+        def.fields.iter().any(|(_, ty)| match ty {
+            Type::Struct(field) => field == target || self.contains(field, target, visited),
+            _ => false,
+        })
+    }
+
+    // Checks the object of a field access is a struct with that field,
+    // returning the struct type and the field type.
+    fn check_field(&mut self, object: &mut Expr, name: &SpannedToken) -> Result<(Type, Type), CompileError> {
+        let ty = self.check_expr(object, None)?;
+        let field = name.token.to_string();
+        let Type::Struct(struct_name) = &ty else {
+            return Err(CompileError::new(
+                format!("cannot access field `{}` on a value of type `{}`", field, ty),
+                name.span,
+            ));
+        };
+        let Some(def) = self.structs.get(struct_name) else {
+            return Err(CompileError::new(format!("cannot find type `{}` in this scope", ty), name.span));
+        };
+        match def.field(&field) {
+            Some((_, field_ty)) => Ok((ty.clone(), field_ty.clone())),
+            None => Err(CompileError::new(format!("no field `{}` on type `{}`", field, ty), name.span)),
+        }
+    }
+
+    fn check_function(&mut self, stmt: &mut Box<Stmt>) -> Result<(), CompileError> {
+        match stmt.as_ref() {
+            Stmt::Extern { .. } | Stmt::Struct { .. } => return Ok(()),
+            // Loading the program replaces imports with the declarations
+            // they refer to.
+            Stmt::Import { keyword, .. } => {
+                return Err(CompileError::new("imports must be loaded before type checking", keyword.span));
+            },
+            _ => {},
+        }
+        let Stmt::Function { params, ret, body, .. } = stmt.as_mut() else {
+            return Err(CompileError {
+                message: "only function declarations are allowed at the top level".to_string(),
+                span: stmt.span(),
+            });
+        };
+        let mut scope = HashMap::new();
+        for param in params.iter() {
+            scope.insert(param.name.token.to_string(), param.ty.clone().unwrap());
+        }
+        self.scopes = vec![scope];
+        self.current_return = ret.clone();
+        self.check_block(body);
+        self.current_return = None;
+        Ok(())
+    }
+
+    // Checks every statement in a new scope, recording errors as we go so a
+    // single mistake doesn't hide the ones after it.
+    fn check_block(&mut self, statements: &mut [Box<Stmt>]) {
+        self.scopes.push(HashMap::new());
+        for stmt in statements.iter_mut() {
+            if let Err(err) = self.check_stmt(stmt) {
+                self.errors.push(err);
+            }
+        }
+        self.scopes.pop();
+    }
+
+    // Finds the scope a variable is declared in, returning its index.
+    fn find(&self, id: &str) -> Option<usize> {
+        self.scopes.iter().rposition(|scope| scope.contains_key(id))
+    }
+
+    // Looks up the type of a variable, recording it as a capture of every
+    // closure it is referenced from but declared outside of.
+    fn lookup(&mut self, name: &SpannedToken) -> Result<Type, CompileError> {
+        let id = name.token.to_string();
+        let Some(depth) = self.find(&id) else {
+            return Err(CompileError::new(format!("cannot find variable `{}` in this scope", id), name.span));
+        };
+        let ty = self.scopes[depth][&id].clone();
+        for closure in self.closures.iter_mut().filter(|closure| closure.boundary > depth) {
+            if !closure.captures.iter().any(|(captured, _)| *captured == id) {
+                closure.captures.push((id.clone(), ty.clone()));
+            }
+        }
+        Ok(ty)
+    }
+
+    // Checks the arguments of a call against the parameter types of the
+    // function being called.
+    fn check_args(
+        &mut self,
+        callee: &str,
+        span: Span,
+        params: &[Type],
+        args: &mut [Box<Expr>],
+    ) -> Result<(), CompileError> {
+        if params.len() != args.len() {
+            return Err(CompileError::new(
+                format!("function `{}` takes {} arguments but {} were supplied", callee, params.len(), args.len()),
+                span,
+            ));
+        }
+        for (arg, expected) in args.iter_mut().zip(params.iter()) {
+            self.expect(arg, expected)?;
+        }
+        Ok(())
+    }
+
+    fn check_condition(&mut self, cond: &mut Expr) -> Result<(), CompileError> {
+        let ty = self.check_expr(cond, Some(&Type::Bool))?;
+        if ty != Type::Bool {
+            return Err(mismatch(&Type::Bool, &ty, cond));
+        }
+        Ok(())
+    }
+
+    fn check_stmt(&mut self, stmt: &mut Box<Stmt>) -> Result<(), CompileError> {
+        match stmt.as_mut() {
+            Stmt::Expr(expr) => {
+                self.check_expr(expr, None)?;
+                Ok(())
+            },
+            Stmt::Block(statements) => {
+                self.check_block(statements);
+                Ok(())
+            },
+            Stmt::If { cond, then_branch, else_branch } => {
+                self.check_condition(cond)?;
+                self.check_block(std::slice::from_mut(then_branch));
+                if let Some(else_branch) = else_branch {
+                    self.check_block(std::slice::from_mut(else_branch));
+                }
+                Ok(())
+            },
+            Stmt::While { condition, body } => {
+                self.check_condition(condition)?;
+                self.check_block(std::slice::from_mut(body));
+                Ok(())
+            },
+            Stmt::Return { keyword, value } => {
+                let expected = self.current_return.clone().unwrap_or(Type::Void);
+                match value {
+                    Some(value) if expected == Type::Void => Err(CompileError::new(
+                        "cannot return a value from a function returning `void`",
+                        value.span(),
+                    )),
+                    Some(value) => {
+                        let ty = self.check_expr(value, Some(&expected))?;
+                        if ty != expected {
+                            return Err(mismatch(&expected, &ty, value));
+                        }
+                        Ok(())
+                    },
+                    None if expected != Type::Void => Err(CompileError::new(
+                        format!("expected a return value of type `{}`", expected),
+                        keyword.span,
+                    )),
+                    None => Ok(()),
+                }
+            },
+            Stmt::Var { name, ty, initializer } => {
+                if let Some(ty) = ty {
+                    self.check_type(ty, name.span)?;
+                }
+                let var_ty = match (ty.clone(), initializer) {
+                    (Some(expected), Some(init)) => {
+                        let found = self.check_expr(init, Some(&expected))?;
+                        if found != expected {
+                            return Err(mismatch(&expected, &found, init));
+                        }
+                        expected
+                    },
+                    (Some(expected), None) => expected,
+                    (None, Some(init)) => self.check_expr(init, None)?,
+                    (None, None) => return Err(CompileError::new(
+                        "type annotations needed for a variable without an initializer",
+                        name.span,
+                    )),
+                };
+                if var_ty == Type::Void {
+                    return Err(CompileError::new("variables cannot have type `void`", name.span));
+                }
+                *ty = Some(var_ty.clone());
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.token.to_string(), var_ty);
+                Ok(())
+            },
+            Stmt::Function { name, .. } | Stmt::Extern { name, .. } => Err(CompileError::new(
+                "nested function declarations are not supported",
+                name.span,
+            )),
+            Stmt::Struct { name, .. } => Err(CompileError::new(
+                "nested struct declarations are not supported",
+                name.span,
+            )),
+            Stmt::Import { keyword, .. } => Err(CompileError::new(
+                "imports are only allowed at the top level",
+                keyword.span,
+            )),
+        }
+    }
+
+    // Checks both operands of a binary operation, which must share a type. An
+    // untyped integer literal on the left takes the type of the right hand
+    // side, so both `x - 1` and `1 - x` work for a float `x`.
+    fn check_operands(
+        &mut self,
+        left: &mut Expr,
+        right: &mut Expr,
+        expected: Option<&Type>,
+    ) -> Result<Type, CompileError> {
+        let (lhs, rhs) = if is_untyped_literal(left) && !is_untyped_literal(right) {
+            let rhs = self.check_expr(right, expected)?;
+            (self.check_expr(left, Some(&rhs))?, rhs)
+        } else {
+            let lhs = self.check_expr(left, expected)?;
+            (lhs.clone(), self.check_expr(right, Some(&lhs))?)
+        };
+        if lhs != rhs {
+            return Err(mismatch(&lhs, &rhs, right));
+        }
+        Ok(lhs)
+    }
+
+    fn check_builtin(
+        &mut self,
+        builtin: Builtin,
+        name: &SpannedToken,
+        args: &mut [Box<Expr>],
+    ) -> Result<Type, CompileError> {
+        let arity = match builtin {
+            Builtin::Len => 1,
+            Builtin::Concat | Builtin::Push => 2,
+        };
+        if arity != args.len() {
+            return Err(CompileError::new(
+                format!("function `{}` takes {} arguments but {} were supplied", name.token, arity, args.len()),
+                name.span,
+            ));
+        }
+        match builtin {
+            Builtin::Len => {
+                let ty = self.check_expr(&mut args[0], None)?;
+                if ty != Type::Str && !matches!(ty, Type::Array(_)) {
+                    return Err(CompileError::new(
+                        format!("`len` expects a string or an array, found `{}`", ty),
+                        args[0].span(),
+                    ));
+                }
+                Ok(Type::I64)
+            },
+            Builtin::Concat => {
+                for arg in args.iter_mut() {
+                    self.expect(arg, &Type::Str)?;
+                }
+                Ok(Type::Str)
+            },
+            Builtin::Push => {
+                let Type::Array(elem) = self.check_expr(&mut args[0], None)? else {
+                    return Err(CompileError::new("`push` expects an array", args[0].span()));
+                };
+                self.expect(&mut args[1], &elem)?;
+                Ok(Type::Void)
+            },
+        }
+    }
+
+    // Checks an expression against the one type it is allowed to have.
+    fn expect(&mut self, expr: &mut Expr, expected: &Type) -> Result<(), CompileError> {
+        let found = self.check_expr(expr, Some(expected))?;
+        if found != *expected {
+            return Err(mismatch(expected, &found, expr));
+        }
+        Ok(())
+    }
+
+    // Checks the array and index of an indexing expression, returning the
+    // element type.
+    fn check_index(&mut self, target: &mut Expr, index: &mut Expr) -> Result<Type, CompileError> {
+        let elem = match self.check_expr(target, None)? {
+            Type::Array(elem) => *elem,
+            ty => return Err(CompileError::new(
+                format!("cannot index into a value of type `{}`", ty),
+                target.span(),
+            )),
+        };
+        let ty = self.check_expr(index, Some(&Type::I64))?;
+        if !ty.is_integer() {
+            return Err(CompileError::new(format!("array indices must be integers, found `{}`", ty), index.span()));
+        }
+        Ok(elem)
+    }
+
+    fn check_expr(&mut self, expr: &mut Expr, expected: Option<&Type>) -> Result<Type, CompileError> {
+        if let Some(literal) = negated_literal(expr) {
+            *expr = literal;
+        }
+        match expr {
+            Expr::Literal { value, ty, span } => {
+                let literal_ty = match value.as_str() {
+                    "true" | "false" => Type::Bool,
+                    nb if is_integer_literal(nb) => match expected {
+                        Some(expected) if expected.is_numeric() => expected.clone(),
+                        _ => Type::I64,
+                    },
+                    _ => Type::F64,
+                };
+                if literal_ty.is_integer() && integer_literal(value, &literal_ty).is_none() {
+                    return Err(CompileError::new(format!("integer literal `{}` is too large", value), *span));
+                }
+                *ty = Some(literal_ty.clone());
+                Ok(literal_ty)
+            },
+            Expr::Str { .. } => Ok(Type::Str),
+            Expr::Array { elements, ty, span } => {
+                // Elements take their type from the context if it expects an
+                // array, or else from the first element.
+                let mut elem = match expected {
+                    Some(Type::Array(elem)) => Some(elem.as_ref().clone()),
+                    _ => None,
+                };
+                for element in elements.iter_mut() {
+                    match &elem {
+                        Some(elem) => self.expect(element, elem)?,
+                        None => elem = Some(self.check_expr(element, None)?),
+                    }
+                }
+                let Some(elem) = elem else {
+                    return Err(CompileError::new("type annotations needed for an empty array", *span));
+                };
+                if elem == Type::Void {
+                    return Err(CompileError::new("arrays cannot hold `void` elements", *span));
+                }
+                *ty = Some(elem.clone());
+                Ok(Type::Array(Box::new(elem)))
+            },
+            Expr::Struct { name, fields } => {
+                let struct_name = name.token.to_string();
+                let Some(def) = self.structs.get(&struct_name).cloned() else {
+                    return Err(CompileError::new(format!("cannot find struct `{}` in this scope", struct_name), name.span));
+                };
+                let mut seen = HashSet::new();
+                for (field, value) in fields.iter_mut() {
+                    let field_name = field.token.to_string();
+                    let Some((_, ty)) = def.field(&field_name) else {
+                        return Err(CompileError::new(
+                            format!("struct `{}` has no field named `{}`", struct_name, field_name),
+                            field.span,
+                        ));
+                    };
+                    if !seen.insert(field_name.clone()) {
+                        return Err(CompileError::new(
+                            format!("field `{}` specified more than once", field_name),
+                            field.span,
+                        ));
+                    }
+                    self.expect(value, ty)?;
+                }
+                if let Some((missing, _)) = def.fields.iter().find(|(field, _)| !seen.contains(field)) {
+                    return Err(CompileError::new(
+                        format!("missing field `{}` in initializer of `{}`", missing, struct_name),
+                        name.span,
+                    ));
+                }
+                Ok(Type::Struct(struct_name))
+            },
+            Expr::Get { object, name, ty } => {
+                let (struct_ty, field_ty) = self.check_field(object, name)?;
+                *ty = Some(struct_ty);
+                Ok(field_ty)
+            },
+            Expr::Set { object, name, value, ty } => {
+                let (struct_ty, field_ty) = self.check_field(object, name)?;
+                self.expect(value, &field_ty)?;
+                *ty = Some(struct_ty);
+                Ok(field_ty)
+            },
+            Expr::Index { target, index, ty, .. } => {
+                let elem = self.check_index(target, index)?;
+                *ty = Some(elem.clone());
+                Ok(elem)
+            },
+            Expr::IndexAssign { target, index, value, ty, .. } => {
+                let elem = self.check_index(target, index)?;
+                self.expect(value, &elem)?;
+                *ty = Some(elem.clone());
+                Ok(elem)
+            },
+            Expr::Variable { name, .. } => {
+                let id = name.token.to_string();
+                match self.lookup(name) {
+                    Ok(ty) => Ok(ty),
+                    // Functions declared by the program can be used as values too.
+                    Err(_) if self.functions.contains_key(&id) => Ok(self.functions[&id].to_type()),
+                    Err(_) if Builtin::from_name(&id).is_some() => Err(CompileError::new(
+                        format!("builtin function `{}` can only be called", id),
+                        name.span,
+                    )),
+                    Err(err) => Err(err),
+                }
+            },
+            Expr::Assign { name, value, .. } => {
+                let id = name.token.to_string();
+                let captured = match (self.find(&id), self.closures.last()) {
+                    (Some(depth), Some(closure)) => depth < closure.boundary,
+                    _ => false,
+                };
+                if captured {
+                    return Err(CompileError::new(
+                        format!("cannot assign to `{}`, as closures capture a copy of it", id),
+                        name.span,
+                    ));
+                }
+                let expected = self.lookup(name)?;
+                let found = self.check_expr(value, Some(&expected))?;
+                if found != expected {
+                    return Err(mismatch(&expected, &found, value));
+                }
+                Ok(expected)
+            },
+            Expr::Grouping { expr } => self.check_expr(expr, expected),
+            Expr::UnaryExpr { op, right } => match op.token {
+                Token::Minus => {
+                    let ty = self.check_expr(right, expected)?;
+                    if !ty.is_numeric() {
+                        return Err(CompileError::new(format!("cannot negate `{}`", ty), op.span));
+                    }
+                    Ok(ty)
+                },
+                Token::Bang => {
+                    self.check_condition(right)?;
+                    Ok(Type::Bool)
+                },
+                _ => Err(CompileError::new("unsupported unary operation", op.span)),
+            },
+            Expr::Logical { op: _, left, right } => {
+                self.check_condition(left)?;
+                self.check_condition(right)?;
+                Ok(Type::Bool)
+            },
+            Expr::BinaryExpr { op, left, right, ty: operands } => match op.token {
+                Token::Plus | Token::Minus | Token::Times | Token::Div => {
+                    let ty = self.check_operands(left, right, expected)?;
+                    *operands = Some(ty.clone());
+                    if !ty.is_numeric() {
+                        return Err(CompileError::new(
+                            format!("cannot apply `{}` to `{}`", op.token, ty),
+                            op.span,
+                        ));
+                    }
+                    Ok(ty)
+                },
+                Token::Less | Token::Greater | Token::Leq | Token::Geq => {
+                    let ty = self.check_operands(left, right, None)?;
+                    *operands = Some(ty.clone());
+                    if !ty.is_numeric() {
+                        return Err(CompileError::new(
+                            format!("cannot compare `{}` with `{}`", ty, op.token),
+                            op.span,
+                        ));
+                    }
+                    Ok(Type::Bool)
+                },
+                Token::Eqq | Token::BangEq => {
+                    let ty = self.check_operands(left, right, None)?;
+                    *operands = Some(ty.clone());
+                    if !ty.is_numeric() && ty != Type::Bool {
+                        return Err(CompileError::new(format!("cannot compare `{}` values", ty), op.span));
+                    }
+                    Ok(Type::Bool)
+                },
+                _ => Err(CompileError::new("unsupported binary operation", op.span)),
+            },
+            Expr::Call { callee, paren, args } => {
+                // Named functions are called directly, unless a variable
+                // shadows them.
+                if let Expr::Variable { name, .. } = callee.as_ref() {
+                    let fn_name = name.token.to_string();
+                    if self.find(&fn_name).is_none() {
+                        let Some(sig) = self.functions.get(&fn_name).cloned() else {
+                            return match Builtin::from_name(&fn_name) {
+                                Some(builtin) => self.check_builtin(builtin, name, args),
+                                None => Err(CompileError::new(format!("cannot find function `{}`", fn_name), name.span)),
+                            };
+                        };
+                        self.check_args(&fn_name, name.span, &sig.params, args)?;
+                        return Ok(sig.ret);
+                    }
+                }
+                // Anything else is a closure, called indirectly.
+                let ty = self.check_expr(callee, None)?;
+                let Type::Function { params, ret } = ty else {
+                    return Err(CompileError::new(format!("cannot call a value of type `{}`", ty), paren.span));
+                };
+                let callee_name = match callee.as_ref() {
+                    Expr::Variable { name, .. } => name.token.to_string(),
+                    _ => "closure".to_string(),
+                };
+                self.check_args(&callee_name, callee.span(), &params, args)?;
+                Ok(*ret)
+            },
+            Expr::Lambda { params, ret, body, captures, .. } => {
+                // Unannotated parameters and return types are taken from the
+                // function type the context expects, if any, or else default
+                // like they do for named functions.
+                match expected {
+                    Some(Type::Function { params: expected, ret: expected_ret }) if expected.len() == params.len() => {
+                        for (param, expected) in params.iter_mut().zip(expected.iter()) {
+                            param.ty.get_or_insert(expected.clone());
+                        }
+                        ret.get_or_insert(expected_ret.as_ref().clone());
+                    },
+                    _ => {
+                        for param in params.iter_mut() {
+                            param.ty.get_or_insert(Type::F64);
+                        }
+                        ret.get_or_insert(if returns_value(body) { Type::F64 } else { Type::Void });
+                    },
+                }
+                let mut scope = HashMap::new();
+                for param in params.iter() {
+                    let ty = param.ty.clone().unwrap();
+                    self.check_type(&ty, param.name.span)?;
+                    scope.insert(param.name.token.to_string(), ty);
+                }
+                let ret_ty = ret.clone().unwrap();
+
+                self.closures.push(ClosureScope { boundary: self.scopes.len(), captures: vec![] });
+                self.scopes.push(scope);
+                let enclosing_return = std::mem::replace(&mut self.current_return, Some(ret_ty.clone()));
+                self.check_block(body);
+                self.current_return = enclosing_return;
+                self.scopes.pop();
+                *captures = self.closures.pop().unwrap().captures;
+
+                Ok(Type::Function {
+                    params: params.iter().map(|param| param.ty.clone().unwrap()).collect(),
+                    ret: Box::new(ret_ty),
+                })
+            },
+        }
+    }
+}
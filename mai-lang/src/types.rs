@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::parser::Stmt;
+
+/// The static type of a mai value.
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+pub enum Type {
+    I32,
+    I64,
+    F64,
+    Bool,
+    // A pointer to a length followed by that many bytes of UTF-8.
+    Str,
+    // A growable array of elements of the given type, written `[T]`.
+    Array(Box<Type>),
+    // A struct declared by the program, referred to by its name.
+    Struct(String),
+    // A function or closure, written `fun(i64, i64) -> i64`.
+    Function {
+        params: Vec<Type>,
+        ret: Box<Type>,
+    },
+    // Only valid as the return type of a function.
+    Void,
+}
+
+impl Type {
+    /// Parses a type from its name in a type annotation, such as `i64`.
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "i32" => Some(Type::I32),
+            "i64" => Some(Type::I64),
+            "f64" => Some(Type::F64),
+            "bool" => Some(Type::Bool),
+            "str" => Some(Type::Str),
+            "void" => Some(Type::Void),
+            _ => None,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self, Type::I32 | Type::I64)
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        self.is_integer() || *self == Type::F64
+    }
+
+    /// Size in bytes of a value of this type stored in linear memory, such
+    /// as an array element or a struct field.
+    pub fn size(&self) -> u32 {
+        match self {
+            Type::Bool => 1,
+            // Strings, arrays, structs and closures are stored as 32-bit
+            // pointers.
+            Type::I32 | Type::Str | Type::Array(_) | Type::Struct(_) | Type::Function { .. } => 4,
+            Type::I64 | Type::F64 => 8,
+            Type::Void => 0,
+        }
+    }
+
+    /// Every value is aligned to its own size in linear memory, as on wasm32.
+    pub fn align(&self) -> u32 {
+        self.size().max(1)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Type::Array(elem) => return write!(f, "[{}]", elem),
+            Type::Struct(name) => name.as_str(),
+            Type::Function { params, ret } => {
+                write!(f, "fun(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                return match ret.as_ref() {
+                    Type::Void => write!(f, ")"),
+                    ret => write!(f, ") -> {}", ret),
+                };
+            },
+            Type::I32 => "i32",
+            Type::I64 => "i64",
+            Type::F64 => "f64",
+            Type::Bool => "bool",
+            Type::Str => "str",
+            Type::Void => "void",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Returns the type assigned to a node by the type checker.
+///
+/// Every annotation left out in the source is filled in by
+/// `type_checker::check`, so the backends can rely on it being present.
+pub fn checked(ty: &Option<Type>) -> Type {
+    ty.clone().expect("program must be type checked before translation")
+}
+
+// Splits a hexadecimal or binary literal such as `0xff` into its digits and
+// their radix.
+fn radix(literal: &str) -> Option<(&str, u32)> {
+    match literal.strip_prefix("0x") {
+        Some(digits) => Some((digits, 16)),
+        None => literal.strip_prefix("0b").map(|digits| (digits, 2)),
+    }
+}
+
+/// Returns whether the text of a number literal is an integer, such as `255`,
+/// `-1` or `0xff`, rather than a float.
+pub fn is_integer_literal(literal: &str) -> bool {
+    radix(literal).is_some() || literal.trim_start_matches('-').chars().all(|ch| ch.is_ascii_digit())
+}
+
+/// Returns the value of an integer literal as the given integer type, if it
+/// fits. Hexadecimal and binary literals are bit patterns, so `0xffffffff` is
+/// `-1` as an `i32`.
+pub fn integer_literal(literal: &str, ty: &Type) -> Option<i64> {
+    let bits = match ty {
+        Type::I32 => 32,
+        Type::I64 => 64,
+        _ => return None,
+    };
+    let Some((digits, radix)) = radix(literal) else {
+        let value = literal.parse::<i64>().ok()?;
+        return (bits == 64 || i32::try_from(value).is_ok()).then_some(value);
+    };
+    let pattern = u64::from_str_radix(digits, radix).ok()?;
+    if bits < 64 && pattern >> bits != 0 {
+        return None;
+    }
+    // Shifting back down copies the top bit of the pattern into the rest.
+    Some(((pattern << (64 - bits)) as i64) >> (64 - bits))
+}
+
+/// Returns the value of a number literal as an `f64`, which integer literals
+/// such as `0xff` can be given too.
+pub fn float_literal(literal: &str) -> Option<f64> {
+    match radix(literal) {
+        Some((digits, radix)) => u64::from_str_radix(digits, radix).ok().map(|value| value as f64),
+        None => literal.parse().ok(),
+    }
+}
+
+/// The fields of a struct declared by the program, in declaration order.
+#[derive(Debug,Clone,PartialEq)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<(String, Type)>,
+}
+
+impl StructDef {
+    pub fn field(&self, name: &str) -> Option<(usize, &Type)> {
+        self.fields
+            .iter()
+            .enumerate()
+            .find(|(_, (field, _))| field == name)
+            .map(|(i, (_, ty))| (i, ty))
+    }
+
+    /// Lays the fields out in declaration order, each aligned to its size,
+    /// returning the offset of every field and the size of the whole struct.
+    pub fn layout(&self) -> (Vec<u32>, u32) {
+        let mut offsets = Vec::with_capacity(self.fields.len());
+        let mut size = 0;
+        for (_, ty) in self.fields.iter() {
+            let offset = (size + ty.align() - 1) / ty.align() * ty.align();
+            offsets.push(offset);
+            size = offset + ty.size();
+        }
+        (offsets, size)
+    }
+}
+
+/// Collects the struct declarations of a type checked program by name.
+pub fn structs(statements: &[Box<Stmt>]) -> HashMap<String, StructDef> {
+    statements
+        .iter()
+        .filter_map(|stmt| match stmt.as_ref() {
+            Stmt::Struct { name, fields } => Some(StructDef {
+                name: name.token.to_string(),
+                fields: fields
+                    .iter()
+                    .map(|field| (field.name.token.to_string(), checked(&field.ty)))
+                    .collect(),
+            }),
+            _ => None,
+        })
+        .map(|def| (def.name.clone(), def))
+        .collect()
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, DataSection, EntityType, ExportKind, ExportSection, Function,
+    FunctionSection, ImportSection, Instruction, MemArg, MemorySection, MemoryType, Module, TypeSection, ValType,
+};
+
+use crate::builtins::Builtin;
+use crate::diagnostic::CompileError;
+use crate::parser::*;
+use crate::token::{Span,Token};
+use crate::types::{checked,float_literal,integer_literal,Type};
+
+const PAGE_SIZE: u32 = 65536;
+// Where string literals start in memory, leaving address 0 unused so no
+// string is ever a null pointer.
+const DATA_START: u32 = 8;
+
+/// Translates a type checked program straight into a wasm module, exporting
+/// the given functions, without going through LLVM.
+///
+/// Externs become imports from the `env` module, as with the LLVM backend,
+/// and the module exports its memory as `memory` for the host to read
+/// strings from. String literals are laid out in memory like the runtime
+/// expects, as their length followed by their bytes. Nothing is ever
+/// allocated, so programs using arrays, structs, closures or building
+/// strings are reported as unsupported.
+pub fn translate(statements: &[Box<Stmt>], exports: &[String]) -> Result<Vec<u8>, CompileError> {
+    let mut tr = Translator {
+        functions: HashMap::new(),
+        types: vec![],
+        strings: HashMap::new(),
+        data: vec![],
+        params: 0,
+        locals: vec![],
+        variables: vec![],
+        code: vec![],
+    };
+
+    // Imports come first in the function index space, so externs are
+    // numbered before the functions defined by the program.
+    let mut imports = ImportSection::new();
+    let mut index = 0;
+    for stmt in statements.iter() {
+        if let Stmt::Extern { name, params, ret } = stmt.as_ref() {
+            let signature = signature(params, ret);
+            let ty = tr.type_index(&signature, name.span)?;
+            imports.import("env", &name.token.to_string(), EntityType::Function(ty));
+            tr.functions.insert(name.token.to_string(), (index, signature));
+            index += 1;
+        }
+    }
+    let mut defined = FunctionSection::new();
+    for stmt in statements.iter() {
+        if let Stmt::Function { name, params, ret, .. } = stmt.as_ref() {
+            let signature = signature(params, ret);
+            defined.function(tr.type_index(&signature, name.span)?);
+            tr.functions.insert(name.token.to_string(), (index, signature));
+            index += 1;
+        }
+    }
+
+    let mut code = CodeSection::new();
+    for stmt in statements.iter() {
+        if let Stmt::Function { name, params, ret, body, .. } = stmt.as_ref() {
+            code.function(&tr.function(params, &checked(ret), body, name.span)?);
+        }
+    }
+
+    let mut export_section = ExportSection::new();
+    export_section.export("memory", ExportKind::Memory, 0);
+    for name in exports.iter() {
+        let Some((index, _)) = tr.functions.get(name) else {
+            return Err(CompileError::unspanned(format!("cannot export `{}`: no such function", name)));
+        };
+        export_section.export(name, ExportKind::Func, *index);
+    }
+
+    let mut types = TypeSection::new();
+    for (params, results) in tr.types.iter() {
+        types.ty().function(params.iter().copied(), results.iter().copied());
+    }
+    let data_end = DATA_START + tr.data.len() as u32;
+    let mut memory = MemorySection::new();
+    memory.memory(MemoryType {
+        minimum: (data_end as u64).div_ceil(PAGE_SIZE as u64),
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+    let mut data = DataSection::new();
+    data.active(0, &ConstExpr::i32_const(DATA_START as i32), tr.data.iter().copied());
+
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&imports)
+        .section(&defined)
+        .section(&memory)
+        .section(&export_section)
+        .section(&code)
+        .section(&data);
+    Ok(module.finish())
+}
+
+// The parameter and return types of a function.
+type Signature = (Vec<Type>, Type);
+
+fn signature(params: &[Param], ret: &Option<Type>) -> Signature {
+    (params.iter().map(|param| checked(&param.ty)).collect(), checked(ret))
+}
+
+fn unsupported(what: &str, span: Span) -> CompileError {
+    CompileError::new(format!("{} are not supported by the wasm backend", what), span)
+}
+
+/// Returns the wasm type used to represent values of a mai type, or `None`
+/// for `void`. Booleans are `i32`s, as wasm has no booleans, and strings are
+/// pointers into memory.
+fn val_type(ty: &Type, span: Span) -> Result<Option<ValType>, CompileError> {
+    match ty {
+        Type::I32 | Type::Bool | Type::Str => Ok(Some(ValType::I32)),
+        Type::I64 => Ok(Some(ValType::I64)),
+        Type::F64 => Ok(Some(ValType::F64)),
+        Type::Void => Ok(None),
+        Type::Array(_) => Err(unsupported("arrays", span)),
+        Type::Struct(_) => Err(unsupported("structs", span)),
+        Type::Function { .. } => Err(unsupported("function values", span)),
+    }
+}
+
+struct Translator {
+    // Index and signature of every function, by name.
+    functions: HashMap<String, (u32, Signature)>,
+    // Every distinct function type, indexed by position in the type section.
+    types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    // Addresses of the string literals laid out so far, by contents.
+    strings: HashMap<String, u32>,
+    // Contents of memory from `DATA_START` on.
+    data: Vec<u8>,
+    // Number of parameters of the function being translated, which come
+    // before its other locals.
+    params: u32,
+    locals: Vec<ValType>,
+    // Local index and type of the variables in scope, one map per scope like
+    // the resolver's, so variable depths index into it from the end.
+    variables: Vec<HashMap<String, (u32, Type)>>,
+    code: Vec<Instruction<'static>>,
+}
+
+impl Translator {
+    fn type_index(&mut self, (params, ret): &Signature, span: Span) -> Result<u32, CompileError> {
+        let mut wasm_params = vec![];
+        for param in params.iter() {
+            wasm_params.extend(val_type(param, span)?);
+        }
+        let ty = (wasm_params, val_type(ret, span)?.into_iter().collect());
+        if let Some(index) = self.types.iter().position(|existing| *existing == ty) {
+            return Ok(index as u32);
+        }
+        self.types.push(ty);
+        Ok(self.types.len() as u32 - 1)
+    }
+
+    /// Lays out a string literal in memory, unless it already is, and
+    /// returns its address.
+    fn string(&mut self, value: &str) -> u32 {
+        if let Some(address) = self.strings.get(value) {
+            return *address;
+        }
+        // Lengths are read as `i32`s, so keep them aligned.
+        while self.data.len() % 4 != 0 {
+            self.data.push(0);
+        }
+        let address = DATA_START + self.data.len() as u32;
+        self.data.extend((value.len() as u32).to_le_bytes());
+        self.data.extend(value.as_bytes());
+        self.strings.insert(value.to_string(), address);
+        address
+    }
+
+    /// Returns the instruction pushing the zero value of a type, used for
+    /// variables declared without an initializer and functions that fall off
+    /// the end, or `None` for `void`.
+    fn zero(&mut self, ty: &Type, span: Span) -> Result<Option<Instruction<'static>>, CompileError> {
+        let zero = match ty {
+            Type::I32 | Type::Bool => Instruction::I32Const(0),
+            Type::I64 => Instruction::I64Const(0),
+            Type::F64 => Instruction::F64Const(0.0),
+            Type::Str => Instruction::I32Const(self.string("") as i32),
+            ty => return val_type(ty, span).map(|_| None),
+        };
+        Ok(Some(zero))
+    }
+
+    fn function(&mut self, params: &[Param], ret: &Type, body: &[Box<Stmt>], span: Span) -> Result<Function, CompileError> {
+        let mut scope = HashMap::new();
+        for (i, param) in params.iter().enumerate() {
+            scope.insert(param.name.token.to_string(), (i as u32, checked(&param.ty)));
+        }
+        self.params = params.len() as u32;
+        self.locals = vec![];
+        self.variables = vec![scope];
+        self.code = vec![];
+
+        self.block(body)?;
+        if let Some(zero) = self.zero(ret, span)? {
+            self.code.push(zero);
+        }
+        self.code.push(Instruction::End);
+
+        let mut function = Function::new(self.locals.iter().map(|ty| (1, *ty)));
+        for instruction in self.code.iter() {
+            function.instruction(instruction);
+        }
+        Ok(function)
+    }
+
+    fn block(&mut self, statements: &[Box<Stmt>]) -> Result<(), CompileError> {
+        self.variables.push(HashMap::new());
+        for stmt in statements.iter() {
+            self.stmt(stmt)?;
+        }
+        self.variables.pop();
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<(), CompileError> {
+        match stmt {
+            Stmt::Block(statements) => self.block(statements)?,
+            Stmt::Expr(expr) => {
+                if self.expr(expr)? != Type::Void {
+                    self.code.push(Instruction::Drop);
+                }
+            },
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value)?;
+                }
+                self.code.push(Instruction::Return);
+            },
+            Stmt::If { cond, then_branch, else_branch } => {
+                self.expr(cond)?;
+                self.code.push(Instruction::If(BlockType::Empty));
+                self.block(std::slice::from_ref(then_branch))?;
+                if let Some(else_branch) = else_branch {
+                    self.code.push(Instruction::Else);
+                    self.block(std::slice::from_ref(else_branch))?;
+                }
+                self.code.push(Instruction::End);
+            },
+            Stmt::While { condition, body } => {
+                self.code.push(Instruction::Block(BlockType::Empty));
+                self.code.push(Instruction::Loop(BlockType::Empty));
+                self.expr(condition)?;
+                self.code.push(Instruction::I32Eqz);
+                self.code.push(Instruction::BrIf(1));
+                self.block(std::slice::from_ref(body))?;
+                self.code.push(Instruction::Br(0));
+                self.code.push(Instruction::End);
+                self.code.push(Instruction::End);
+            },
+            Stmt::Var { name, ty, initializer } => {
+                let ty = match initializer {
+                    Some(initializer) => self.expr(initializer)?,
+                    None => {
+                        let ty = checked(ty);
+                        if let Some(zero) = self.zero(&ty, name.span)? {
+                            self.code.push(zero);
+                        }
+                        ty
+                    },
+                };
+                let Some(val_type) = val_type(&ty, name.span)? else {
+                    return Err(CompileError::new("variables cannot have type `void`", name.span));
+                };
+                // Every declaration gets a local of its own, so shadowed
+                // variables keep their values.
+                let local = self.params + self.locals.len() as u32;
+                self.locals.push(val_type);
+                self.code.push(Instruction::LocalSet(local));
+                self.variables.last_mut().unwrap().insert(name.token.to_string(), (local, ty));
+            },
+            // Declarations only appear at the top level, which the type
+            // checker makes sure of.
+            Stmt::Function { .. } | Stmt::Extern { .. } | Stmt::Struct { .. } | Stmt::Import { .. } => {},
+        }
+        Ok(())
+    }
+
+    fn variable(&self, name: &str, depth: Option<usize>, span: Span) -> Result<(u32, Type), CompileError> {
+        let Some(depth) = depth else {
+            return Err(unsupported("function values", span));
+        };
+        self.variables
+            .len()
+            .checked_sub(depth + 1)
+            .and_then(|scope| self.variables[scope].get(name))
+            .cloned()
+            .ok_or_else(|| CompileError::new(format!("cannot find variable `{}` in this scope", name), span))
+    }
+
+    /// Translates an expression, leaving its value on the stack, and returns
+    /// its type.
+    fn expr(&mut self, expr: &Expr) -> Result<Type, CompileError> {
+        match expr {
+            Expr::Literal { value, ty, span } => {
+                let invalid = || CompileError::new(format!("invalid literal `{}`", value), *span);
+                let ty = checked(ty);
+                let instruction = match ty {
+                    Type::Bool => Instruction::I32Const((value == "true") as i32),
+                    Type::I32 => Instruction::I32Const(integer_literal(value, &ty).ok_or_else(invalid)? as i32),
+                    Type::I64 => Instruction::I64Const(integer_literal(value, &ty).ok_or_else(invalid)?),
+                    Type::F64 => Instruction::F64Const(float_literal(value).ok_or_else(invalid)?),
+                    _ => return Err(invalid()),
+                };
+                self.code.push(instruction);
+                Ok(ty)
+            },
+            Expr::Str { value, .. } => {
+                let address = self.string(value);
+                self.code.push(Instruction::I32Const(address as i32));
+                Ok(Type::Str)
+            },
+            Expr::Variable { name, depth } => {
+                let (local, ty) = self.variable(&name.token.to_string(), *depth, name.span)?;
+                self.code.push(Instruction::LocalGet(local));
+                Ok(ty)
+            },
+            Expr::Assign { name, value, depth } => {
+                self.expr(value)?;
+                let (local, ty) = self.variable(&name.token.to_string(), *depth, name.span)?;
+                self.code.push(Instruction::LocalTee(local));
+                Ok(ty)
+            },
+            Expr::Grouping { expr } => self.expr(expr),
+            Expr::UnaryExpr { op, right } => {
+                let ty = self.expr(right)?;
+                // Integers have no negation, but multiplying by -1 wraps the
+                // same way.
+                let instructions = match (&op.token, &ty) {
+                    (Token::Minus, Type::I32) => vec![Instruction::I32Const(-1), Instruction::I32Mul],
+                    (Token::Minus, Type::I64) => vec![Instruction::I64Const(-1), Instruction::I64Mul],
+                    (Token::Minus, Type::F64) => vec![Instruction::F64Neg],
+                    (Token::Bang, Type::Bool) => vec![Instruction::I32Eqz],
+                    _ => return Err(CompileError::new("unsupported unary operation", op.span)),
+                };
+                self.code.extend(instructions);
+                Ok(ty)
+            },
+            Expr::BinaryExpr { op, left, right, .. } => {
+                let ty = self.expr(left)?;
+                self.expr(right)?;
+                let Some(instruction) = binary(&op.token, &ty) else {
+                    return Err(CompileError::new("unsupported binary operation", op.span));
+                };
+                self.code.push(instruction);
+                match op.token {
+                    Token::Plus | Token::Minus | Token::Times | Token::Div => Ok(ty),
+                    _ => Ok(Type::Bool),
+                }
+            },
+            // The right hand side is only evaluated if the left doesn't
+            // decide the result.
+            Expr::Logical { op, left, right } => {
+                self.expr(left)?;
+                self.code.push(Instruction::If(BlockType::Result(ValType::I32)));
+                match op.token {
+                    Token::And => {
+                        self.expr(right)?;
+                        self.code.push(Instruction::Else);
+                        self.code.push(Instruction::I32Const(0));
+                    },
+                    Token::Or => {
+                        self.code.push(Instruction::I32Const(1));
+                        self.code.push(Instruction::Else);
+                        self.expr(right)?;
+                    },
+                    _ => return Err(CompileError::new("unsupported logical operation", op.span)),
+                }
+                self.code.push(Instruction::End);
+                Ok(Type::Bool)
+            },
+            Expr::Call { callee, paren, args } => {
+                // Only named functions can be called, unless a variable
+                // shadows them.
+                let Expr::Variable { name, depth: None } = callee.as_ref() else {
+                    return Err(unsupported("closures", paren.span));
+                };
+                let fn_name = name.token.to_string();
+                if let Some((index, (_, ret))) = self.functions.get(&fn_name).cloned() {
+                    for arg in args.iter() {
+                        self.expr(arg)?;
+                    }
+                    self.code.push(Instruction::Call(index));
+                    return Ok(ret);
+                }
+                match (Builtin::from_name(&fn_name), args.as_slice()) {
+                    // The length of a string is stored right before its bytes.
+                    (Some(Builtin::Len), [arg]) => {
+                        if self.expr(arg)? != Type::Str {
+                            return Err(unsupported("arrays", name.span));
+                        }
+                        self.code.push(Instruction::I32Load(MemArg { offset: 0, align: 2, memory_index: 0 }));
+                        self.code.push(Instruction::I64ExtendI32U);
+                        Ok(Type::I64)
+                    },
+                    (Some(Builtin::Len | Builtin::Push), _) => Err(unsupported("arrays", name.span)),
+                    (Some(Builtin::Concat), _) => Err(unsupported("new strings", name.span)),
+                    (None, _) => Err(CompileError::new(format!("cannot find function `{}`", fn_name), name.span)),
+                }
+            },
+            Expr::Array { span, .. } => Err(unsupported("arrays", *span)),
+            Expr::Index { bracket, .. } | Expr::IndexAssign { bracket, .. } => Err(unsupported("arrays", bracket.span)),
+            Expr::Struct { name, .. } | Expr::Get { name, .. } | Expr::Set { name, .. } => Err(unsupported("structs", name.span)),
+            Expr::Lambda { keyword, .. } => Err(unsupported("closures", keyword.span)),
+        }
+    }
+}
+
+// Returns the instruction applying a binary operator to two values of the
+// given type.
+fn binary(op: &Token, ty: &Type) -> Option<Instruction<'static>> {
+    let instruction = match (ty, op) {
+        (Type::I32, Token::Plus) => Instruction::I32Add,
+        (Type::I32, Token::Minus) => Instruction::I32Sub,
+        (Type::I32, Token::Times) => Instruction::I32Mul,
+        (Type::I32, Token::Div) => Instruction::I32DivS,
+        (Type::I32, Token::Less) => Instruction::I32LtS,
+        (Type::I32, Token::Greater) => Instruction::I32GtS,
+        (Type::I32, Token::Leq) => Instruction::I32LeS,
+        (Type::I32, Token::Geq) => Instruction::I32GeS,
+        (Type::I32 | Type::Bool, Token::Eqq) => Instruction::I32Eq,
+        (Type::I32 | Type::Bool, Token::BangEq) => Instruction::I32Ne,
+        (Type::I64, Token::Plus) => Instruction::I64Add,
+        (Type::I64, Token::Minus) => Instruction::I64Sub,
+        (Type::I64, Token::Times) => Instruction::I64Mul,
+        (Type::I64, Token::Div) => Instruction::I64DivS,
+        (Type::I64, Token::Less) => Instruction::I64LtS,
+        (Type::I64, Token::Greater) => Instruction::I64GtS,
+        (Type::I64, Token::Leq) => Instruction::I64LeS,
+        (Type::I64, Token::Geq) => Instruction::I64GeS,
+        (Type::I64, Token::Eqq) => Instruction::I64Eq,
+        (Type::I64, Token::BangEq) => Instruction::I64Ne,
+        (Type::F64, Token::Plus) => Instruction::F64Add,
+        (Type::F64, Token::Minus) => Instruction::F64Sub,
+        (Type::F64, Token::Times) => Instruction::F64Mul,
+        (Type::F64, Token::Div) => Instruction::F64Div,
+        (Type::F64, Token::Less) => Instruction::F64Lt,
+        (Type::F64, Token::Greater) => Instruction::F64Gt,
+        (Type::F64, Token::Leq) => Instruction::F64Le,
+        (Type::F64, Token::Geq) => Instruction::F64Ge,
+        (Type::F64, Token::Eqq) => Instruction::F64Eq,
+        (Type::F64, Token::BangEq) => Instruction::F64Ne,
+        _ => return None,
+    };
+    Some(instruction)
+}
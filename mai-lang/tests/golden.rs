@@ -0,0 +1,217 @@
+//! Runs every program in `tests/golden` through `mai lex`, `mai parse` and
+//! `mai run`, comparing what they print with the `.expected` files next to
+//! it:
+//!
+//! - `<name>.tokens.expected` holds the token stream,
+//! - `<name>.ast.expected` the syntax tree,
+//! - `<name>.run.expected` the output of running `main`, including any
+//!   errors.
+//!
+//! Programs are run with the interpreter, the wasm backend and, when built
+//! with the `llvm` feature, the LLVM backend at `-O0`, `-O1` and `-O2`, which
+//! must all print the same thing. A program starting with
+//! `// backends: interp llvm` is only run with the backends it lists.
+//!
+//! Every program must also be formatted already, and formatting it again
+//! must leave its syntax tree as it is.
+//!
+//! Set `BLESS=1` to write the current output to the `.expected` files instead
+//! of comparing against them, after checking the changes are intended.
+
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::process::Command;
+
+const OPT_LEVELS: &[&str] = &["0", "1", "2"];
+
+// A program breaking the standard style around every kind of comment, and
+// what `mai fmt` turns it into.
+const MISFORMATTED: &str = "\
+// Adds two numbers.
+fun add(a: i64,b: i64)->i64{
+  /* the sum,
+     unchecked */
+  return a+b;   // trailing
+}
+export fun main() -> i64 { return add(1, 2); }
+";
+const FORMATTED: &str = "\
+// Adds two numbers.
+fun add(a: i64, b: i64) -> i64 {
+    /* the sum,
+     unchecked */
+    return a + b; // trailing
+}
+
+export fun main() -> i64 {
+    return add(1, 2);
+}
+";
+
+// Runs `mai` from the crate root, so paths in diagnostics are relative to it,
+// and returns whether it succeeded along with what it printed to stdout
+// followed by stderr.
+fn run(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_mai"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .expect("failed to run mai");
+    let mut printed = String::from_utf8_lossy(&output.stdout).into_owned();
+    printed.push_str(&String::from_utf8_lossy(&output.stderr));
+    (output.status.success(), printed)
+}
+
+// Runs `mai`, returning only what it printed.
+fn mai(args: &[&str]) -> String {
+    run(args).1
+}
+
+// The backends `mai` was built with. The interpreter comes first, as the
+// one whose output is compared with the expected files.
+fn available() -> Vec<&'static str> {
+    let mut backends = vec!["interp", "wasm"];
+    if cfg!(feature = "llvm") {
+        backends.push("llvm");
+    }
+    backends
+}
+
+// The backends a program asks to be run with, all of them by default. Those
+// left out of this build are skipped.
+fn backends(program: &str) -> Vec<&str> {
+    let available = available();
+    match program.lines().next().and_then(|line| line.strip_prefix("// backends:")) {
+        Some(backends) => backends.split_whitespace().filter(|backend| available.contains(backend)).collect(),
+        None => available,
+    }
+}
+
+// Shows the lines that differ between the expected and actual output.
+fn diff(expected: &str, actual: &str) -> String {
+    let (expected, actual) = (expected.lines().collect::<Vec<&str>>(), actual.lines().collect::<Vec<&str>>());
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        let (old, new) = (expected.get(i), actual.get(i));
+        if old == new {
+            continue;
+        }
+        if let Some(old) = old {
+            out.push_str(&format!("{:>4} - {}\n", i + 1, old));
+        }
+        if let Some(new) = new {
+            out.push_str(&format!("{:>4} + {}\n", i + 1, new));
+        }
+    }
+    out
+}
+
+// Compares output with an expected file, or writes it there when blessing.
+// Returns a description of the mismatch, if any.
+fn compare(expected: &Path, actual: &str, what: &str) -> Option<String> {
+    if std::env::var_os("BLESS").is_some() {
+        if fs::read_to_string(expected).ok().as_deref() != Some(actual) {
+            fs::write(expected, actual).unwrap();
+        }
+        return None;
+    }
+    match fs::read_to_string(expected) {
+        Ok(contents) if contents == actual => None,
+        Ok(contents) => Some(format!("{} differs from {}:\n{}", what, expected.display(), diff(&contents, actual))),
+        Err(_) => Some(format!("{} is missing, run with BLESS=1 to create it\n", expected.display())),
+    }
+}
+
+#[test]
+fn golden() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+    let mut programs = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mai"))
+        .collect::<Vec<PathBuf>>();
+    programs.sort();
+    assert!(!programs.is_empty(), "no programs found in {}", dir.display());
+
+    // Programs are formatted in a copy, leaving the originals alone.
+    let scratch = std::env::temp_dir().join(format!("mai-golden-{}", std::process::id()));
+    fs::create_dir_all(&scratch).unwrap();
+    let mut failures = vec![];
+    for program in programs.iter() {
+        let name = program.file_stem().unwrap().to_str().unwrap();
+        let input = format!("tests/golden/{}.mai", name);
+        let expected = |kind: &str| dir.join(format!("{}.{}.expected", name, kind));
+
+        failures.extend(compare(&expected("tokens"), &mai(&["lex", &input]), &format!("`mai lex {}`", input)));
+        failures.extend(compare(&expected("ast"), &mai(&["parse", &input]), &format!("`mai parse {}`", input)));
+        // Every backend must agree at every optimization level before the
+        // output is compared, so blessing can't paper over a difference
+        // between them.
+        let source = fs::read_to_string(program).unwrap();
+        let runs = backends(&source)
+            .into_iter()
+            .flat_map(|backend| OPT_LEVELS.iter().map(move |level| (backend, *level)))
+            .collect::<Vec<(&str, &str)>>();
+        let outputs = runs
+            .iter()
+            .map(|(backend, level)| mai(&["run", &input, "--backend", backend, "-O", level]))
+            .collect::<Vec<String>>();
+        let (backend, level) = runs[0];
+        for ((other, other_level), output) in runs.iter().zip(outputs.iter()).skip(1) {
+            if *output != outputs[0] {
+                failures.push(format!(
+                    "`mai run {}` printed differently with `--backend {} -O {}` than with `--backend {} -O {}`:\n{}",
+                    input, other, other_level, backend, level, diff(&outputs[0], output),
+                ));
+            }
+        }
+        failures.extend(compare(&expected("run"), &outputs[0], &format!("`mai run {} --backend {}`", input, backend)));
+
+        if !run(&["fmt", "--check", &input]).0 {
+            failures.push(format!("`mai fmt --check {}` failed, run `mai fmt` on it\n", input));
+        }
+        // Formatting a second time must change nothing, and neither must
+        // change the syntax tree.
+        let copy = scratch.join(format!("{}.mai", name));
+        let copy = copy.to_str().unwrap();
+        fs::write(copy, &source).unwrap();
+        mai(&["fmt", copy]);
+        let once = fs::read_to_string(copy).unwrap();
+        mai(&["fmt", copy]);
+        let twice = fs::read_to_string(copy).unwrap();
+        if twice != once {
+            failures.push(format!("`mai fmt {}` isn't idempotent:\n{}", input, diff(&once, &twice)));
+        }
+        if let Ok(ast) = fs::read_to_string(expected("ast")) {
+            let formatted = mai(&["parse", copy]);
+            if formatted != ast {
+                failures.push(format!("`mai fmt {}` changed the syntax tree:\n{}", input, diff(&ast, &formatted)));
+            }
+        }
+    }
+    fs::remove_dir_all(&scratch).unwrap();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn fmt_check_rejects_misformatted() {
+    let dir = std::env::temp_dir().join(format!("mai-fmt-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("main.mai");
+    let input = input.to_str().unwrap();
+    fs::write(input, MISFORMATTED).unwrap();
+
+    let (checked, printed) = run(&["fmt", "--check", input]);
+    let unchanged = fs::read_to_string(input).unwrap();
+    let (formatted, _) = run(&["fmt", input]);
+    let output = fs::read_to_string(input).unwrap();
+    let (rechecked, _) = run(&["fmt", "--check", input]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!checked, "`mai fmt --check` accepted a misformatted file");
+    assert!(printed.contains("is not formatted"), "{}", printed);
+    assert_eq!(unchanged, MISFORMATTED, "`mai fmt --check` rewrote the file");
+    assert!(formatted);
+    assert_eq!(output, FORMATTED);
+    assert!(rechecked, "`mai fmt --check` rejected the output of `mai fmt`");
+}
@@ -0,0 +1,133 @@
+//! Drives `mai lsp` over stdio the way an editor would.
+
+use std::io::{BufRead,BufReader,Read,Write};
+use std::process::{Child,ChildStdin,ChildStdout,Command,Stdio};
+
+use serde_json::{json,Value};
+
+const URI: &str = "file:///tmp/lsp/main.mai";
+
+const PROGRAM: &str = "\
+fun add(a: i64, b: i64) -> i64 {
+    return a + b;
+}
+
+export fun main() -> i64 {
+    var x = add(1, 2);
+    return x + y;
+}
+";
+
+struct Client {
+    server: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: u64,
+}
+
+impl Client {
+    fn start() -> Self {
+        let mut server = Command::new(env!("CARGO_BIN_EXE_mai"))
+            .arg("lsp")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start the language server");
+        let stdin = server.stdin.take().unwrap();
+        let stdout = BufReader::new(server.stdout.take().unwrap());
+        Self { server, stdin, stdout, next_id: 0 }
+    }
+
+    fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            self.stdout.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    /// Sends a request and returns its result, skipping any notifications
+    /// the server sends in the meantime.
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        loop {
+            let message = self.receive();
+            if message["id"] == id {
+                return message["result"].clone();
+            }
+        }
+    }
+
+    fn at(&mut self, method: &str, line: u64, character: u64) -> Value {
+        self.request(method, json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+        }))
+    }
+}
+
+#[test]
+fn language_server() {
+    let mut client = Client::start();
+    let capabilities = client.request("initialize", json!({ "capabilities": {} }))["capabilities"].clone();
+    assert_eq!(capabilities["definitionProvider"], true);
+    client.notify("initialized", json!({}));
+
+    client.notify("textDocument/didOpen", json!({
+        "textDocument": { "uri": URI, "languageId": "mai", "version": 1, "text": PROGRAM },
+    }));
+    let diagnostics = client.receive();
+    assert_eq!(diagnostics["method"], "textDocument/publishDiagnostics");
+    assert_eq!(diagnostics["params"]["diagnostics"], json!([{
+        "range": { "start": { "line": 6, "character": 15 }, "end": { "line": 6, "character": 16 } },
+        "severity": 1,
+        "source": "mai",
+        "message": "cannot find variable `y` in this scope",
+    }]));
+
+    // The call to `add` and the use of `x`.
+    let definition = client.at("textDocument/definition", 5, 13);
+    assert_eq!(definition["uri"], URI);
+    assert_eq!(definition["range"]["start"], json!({ "line": 0, "character": 4 }));
+    let definition = client.at("textDocument/definition", 6, 11);
+    assert_eq!(definition["range"]["start"], json!({ "line": 5, "character": 8 }));
+
+    let hover = client.at("textDocument/hover", 5, 13);
+    assert_eq!(hover["contents"]["value"], "```mai\nfun add(a: i64, b: i64) -> i64\n```");
+    assert_eq!(client.at("textDocument/hover", 1, 4), Value::Null);
+
+    let tokens = client.request("textDocument/semanticTokens/full", json!({ "textDocument": { "uri": URI } }));
+    let data = tokens["data"].as_array().unwrap();
+    // `fun` is a keyword and `add` a function right after it.
+    assert_eq!(data[..10], [0, 0, 3, 0, 0, 0, 4, 3, 1, 0]);
+
+    let fixed = PROGRAM.replace("x + y", "x");
+    client.notify("textDocument/didSave", json!({ "textDocument": { "uri": URI }, "text": fixed }));
+    assert_eq!(client.receive()["params"]["diagnostics"], json!([]));
+
+    assert_eq!(client.request("shutdown", Value::Null), Value::Null);
+    client.notify("exit", Value::Null);
+    assert!(client.server.wait().unwrap().success());
+}
@@ -0,0 +1,115 @@
+//! Compiles programs with `mai build --backend wasm` and runs the modules it
+//! writes with wasmer.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command,Output};
+use std::sync::{Arc,Mutex};
+
+use wasmer::{imports,Function,FunctionEnv,FunctionEnvMut,Instance,Module,Store};
+
+const PROGRAM: &str = "\
+extern fun print(value: i64);
+
+fun fib(n: i64) -> i64 {
+    if (n < 2) {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+
+fun sum_to(n: i32) -> i32 {
+    var total: i32;
+    var i: i32 = 1;
+    while (i <= n) {
+        total = total + i;
+        i = i + 1;
+    }
+    return total;
+}
+
+fun area(r: f64) -> f64 {
+    return 3.0 * r * r;
+}
+
+fun between(x: i64, lo: i64, hi: i64) -> bool {
+    return x >= lo and !(x > hi);
+}
+
+export fun main() -> i64 {
+    print(fib(10));
+    if (between(len(\"four\"), 1, 9)) {
+        return -fib(7);
+    }
+    return 0;
+}
+";
+
+// Writes a program to a directory of its own and runs `mai` on it. Callers
+// remove the directory once they are done with what `mai` wrote there.
+fn mai(name: &str, program: &str, args: &[&str]) -> (PathBuf, Output) {
+    let dir = std::env::temp_dir().join(format!("mai-wasm-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("main.mai");
+    fs::write(&input, program).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_mai"))
+        .arg(args[0])
+        .arg(&input)
+        .args(["--backend", "wasm"])
+        .args(&args[1..])
+        .output()
+        .expect("failed to run mai");
+    (dir, output)
+}
+
+#[test]
+fn runs_compiled_module() {
+    let (dir, output) = mai("build", PROGRAM, &["build"]);
+    let wasm = fs::read(dir.join("main.wasm"));
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let wasm = wasm.unwrap();
+
+    let mut store = Store::default();
+    let module = Module::new(&store, &wasm).unwrap();
+    let printed = Arc::new(Mutex::new(vec![]));
+    let env = FunctionEnv::new(&mut store, printed.clone());
+    let print = Function::new_typed_with_env(&mut store, &env, |env: FunctionEnvMut<Arc<Mutex<Vec<i64>>>>, value: i64| {
+        env.data().lock().unwrap().push(value);
+    });
+    let instance = Instance::new(&mut store, &module, &imports! { "env" => { "print" => print } }).unwrap();
+    assert!(instance.exports.get_memory("memory").is_ok());
+    // Only exported functions can be called from the host.
+    assert!(instance.exports.get_function("fib").is_err());
+
+    let main = instance.exports.get_function("main").unwrap();
+    assert_eq!(main.call(&mut store, &[]).unwrap()[0].i64(), Some(-13));
+    assert_eq!(*printed.lock().unwrap(), vec![55]);
+}
+
+#[test]
+fn runs_every_function() {
+    for (entry, args, expected) in [
+        ("sum_to", &["10"][..], "55"),
+        ("area", &["2.0"], "12.0"),
+        ("between", &["12", "1", "9"], "false"),
+    ] {
+        let program = PROGRAM.replace(&format!("fun {}", entry), &format!("export fun {}", entry));
+        let mut run = vec!["run", "--entry", entry, "--args"];
+        run.extend(args);
+        let (dir, output) = mai(entry, &program, &run);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), expected);
+    }
+}
+
+#[test]
+fn reports_unsupported_programs() {
+    let program = "export fun main() -> i64 {\n    var xs = [1, 2, 3];\n    return len(xs);\n}\n";
+    let (dir, output) = mai("unsupported", program, &["build"]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("arrays are not supported by the wasm backend"), "{}", stderr);
+}